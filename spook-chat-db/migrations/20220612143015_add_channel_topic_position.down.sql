@@ -0,0 +1,4 @@
+-- Add down migration script here
+ALTER TABLE channels
+DROP COLUMN topic,
+DROP COLUMN position;
@@ -0,0 +1,4 @@
+-- Add up migration script here
+ALTER TABLE channels
+ADD COLUMN topic VARCHAR(1024),
+ADD COLUMN position INTEGER NOT NULL DEFAULT 0;
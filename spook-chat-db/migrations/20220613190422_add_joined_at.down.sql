@@ -0,0 +1,2 @@
+-- Add down migration script here
+ALTER TABLE users_servers DROP COLUMN joined_at;
@@ -0,0 +1,3 @@
+-- Add up migration script here
+ALTER TABLE users_servers
+ADD COLUMN joined_at TIMESTAMPTZ NOT NULL DEFAULT NOW();
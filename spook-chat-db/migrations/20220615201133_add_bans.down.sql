@@ -0,0 +1,9 @@
+-- Add down migration script here
+ALTER TABLE users_servers
+ADD COLUMN banned BOOLEAN NOT NULL DEFAULT FALSE;
+
+INSERT INTO users_servers (user_id, server_id, banned)
+SELECT user_id, server_id, TRUE FROM bans
+ON CONFLICT (user_id, server_id) DO UPDATE SET banned = TRUE;
+
+DROP TABLE IF EXISTS bans;
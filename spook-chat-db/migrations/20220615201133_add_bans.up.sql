@@ -0,0 +1,20 @@
+-- Add up migration script here
+CREATE TABLE IF NOT EXISTS bans (
+  server_id UUID NOT NULL,
+  user_id UUID NOT NULL,
+  reason VARCHAR(512),
+  issued_by UUID,
+  created_at TIMESTAMPTZ NOT NULL,
+  expires_at TIMESTAMPTZ,
+  PRIMARY KEY (server_id, user_id),
+  FOREIGN KEY (server_id) REFERENCES servers (server_id),
+  FOREIGN KEY (user_id) REFERENCES users (user_id),
+  FOREIGN KEY (issued_by) REFERENCES users (user_id)
+);
+
+INSERT INTO bans (server_id, user_id, created_at)
+SELECT server_id, user_id, NOW() FROM users_servers WHERE banned;
+
+DELETE FROM users_servers WHERE banned;
+
+ALTER TABLE users_servers DROP COLUMN banned;
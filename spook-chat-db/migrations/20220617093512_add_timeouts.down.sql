@@ -0,0 +1,2 @@
+-- Add down migration script here
+ALTER TABLE users_servers DROP COLUMN timed_out_until;
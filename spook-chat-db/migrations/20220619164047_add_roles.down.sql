@@ -0,0 +1,15 @@
+-- Add down migration script here
+ALTER TABLE users_servers
+ADD COLUMN manage_channels BOOLEAN NOT NULL DEFAULT FALSE,
+ADD COLUMN manage_users BOOLEAN NOT NULL DEFAULT FALSE,
+ADD COLUMN manage_invites BOOLEAN NOT NULL DEFAULT FALSE;
+
+UPDATE users_servers SET
+  manage_channels = permissions & 32 <> 0,
+  manage_users = permissions & 64 <> 0,
+  manage_invites = permissions & 128 <> 0;
+
+ALTER TABLE users_servers DROP COLUMN permissions;
+
+DROP TABLE IF EXISTS members_roles;
+DROP TABLE IF EXISTS roles;
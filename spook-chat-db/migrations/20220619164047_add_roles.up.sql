@@ -0,0 +1,52 @@
+-- Add up migration script here
+CREATE TABLE IF NOT EXISTS roles (
+  role_id UUID PRIMARY KEY,
+  server_id UUID NOT NULL,
+  name VARCHAR(30) NOT NULL,
+  colour INTEGER,
+  position INTEGER NOT NULL,
+  permissions BIGINT NOT NULL DEFAULT 0,
+  created_at TIMESTAMPTZ NOT NULL,
+  UNIQUE (role_id, server_id),
+  FOREIGN KEY (server_id) REFERENCES servers (server_id)
+);
+
+CREATE TABLE IF NOT EXISTS members_roles (
+  user_id UUID NOT NULL,
+  server_id UUID NOT NULL,
+  role_id UUID NOT NULL,
+  PRIMARY KEY (user_id, role_id),
+  FOREIGN KEY (user_id, server_id) REFERENCES users_servers (user_id, server_id) ON DELETE CASCADE,
+  FOREIGN KEY (role_id, server_id) REFERENCES roles (role_id, server_id) ON DELETE CASCADE
+);
+
+-- Every server gets an everyone role sharing its id, granting reading, sending and attaching
+INSERT INTO roles (role_id, server_id, name, position, permissions, created_at)
+SELECT server_id, server_id, 'everyone', 0, 7, NOW() FROM servers;
+
+ALTER TABLE users_servers
+ADD COLUMN permissions BIGINT NOT NULL DEFAULT 0;
+
+UPDATE users_servers SET permissions =
+  (CASE WHEN manage_channels THEN 32 ELSE 0 END)
+  | (CASE WHEN manage_users THEN 64 ELSE 0 END)
+  | (CASE WHEN manage_invites THEN 128 ELSE 0 END);
+
+-- Members holding any of the old flags keep them as direct permissions, and get a moderator
+-- role above everyone so they still outrank the members they moderate
+INSERT INTO roles (role_id, server_id, name, position, created_at)
+SELECT gen_random_uuid(), server_id, 'moderator', 1, NOW() FROM servers
+WHERE server_id IN (
+  SELECT server_id FROM users_servers
+  WHERE NOT owner AND (manage_channels OR manage_users OR manage_invites)
+);
+
+INSERT INTO members_roles (user_id, server_id, role_id)
+SELECT A.user_id, A.server_id, B.role_id
+FROM users_servers A INNER JOIN roles B ON B.server_id = A.server_id AND B.position = 1
+WHERE NOT A.owner AND (A.manage_channels OR A.manage_users OR A.manage_invites);
+
+ALTER TABLE users_servers
+DROP COLUMN manage_channels,
+DROP COLUMN manage_users,
+DROP COLUMN manage_invites;
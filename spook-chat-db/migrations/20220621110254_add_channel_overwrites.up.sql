@@ -0,0 +1,15 @@
+-- Add up migration script here
+CREATE TABLE IF NOT EXISTS channel_overwrites (
+  overwrite_id UUID PRIMARY KEY,
+  channel_id UUID NOT NULL,
+  role_id UUID,
+  user_id UUID,
+  allow BIGINT NOT NULL DEFAULT 0,
+  deny BIGINT NOT NULL DEFAULT 0,
+  UNIQUE (channel_id, role_id),
+  UNIQUE (channel_id, user_id),
+  CHECK ((role_id IS NULL) <> (user_id IS NULL)),
+  FOREIGN KEY (channel_id) REFERENCES channels (channel_id) ON DELETE CASCADE,
+  FOREIGN KEY (role_id) REFERENCES roles (role_id) ON DELETE CASCADE,
+  FOREIGN KEY (user_id) REFERENCES users (user_id) ON DELETE CASCADE
+);
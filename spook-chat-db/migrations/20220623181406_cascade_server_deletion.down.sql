@@ -0,0 +1,30 @@
+-- Add down migration script here
+ALTER TABLE roles
+DROP CONSTRAINT roles_server_id_fkey,
+ADD CONSTRAINT roles_server_id_fkey
+  FOREIGN KEY (server_id) REFERENCES servers (server_id);
+
+ALTER TABLE bans
+DROP CONSTRAINT bans_server_id_fkey,
+ADD CONSTRAINT bans_server_id_fkey
+  FOREIGN KEY (server_id) REFERENCES servers (server_id);
+
+ALTER TABLE invites
+DROP CONSTRAINT invites_server_id_fkey,
+ADD CONSTRAINT invites_server_id_fkey
+  FOREIGN KEY (server_id) REFERENCES servers (server_id);
+
+ALTER TABLE users_servers
+DROP CONSTRAINT users_servers_server_id_fkey,
+ADD CONSTRAINT users_servers_server_id_fkey
+  FOREIGN KEY (server_id) REFERENCES servers (server_id);
+
+ALTER TABLE messages
+DROP CONSTRAINT messages_channel_id_fkey,
+ADD CONSTRAINT messages_channel_id_fkey
+  FOREIGN KEY (channel_id) REFERENCES channels (channel_id);
+
+ALTER TABLE channels
+DROP CONSTRAINT channels_server_id_fkey,
+ADD CONSTRAINT channels_server_id_fkey
+  FOREIGN KEY (server_id) REFERENCES servers (server_id);
@@ -0,0 +1,6 @@
+-- Add down migration script here
+ALTER TABLE invites
+DROP COLUMN created_by,
+DROP COLUMN uses,
+DROP COLUMN max_uses,
+DROP COLUMN revoked;
@@ -0,0 +1,6 @@
+-- Add up migration script here
+ALTER TABLE invites
+ADD COLUMN created_by UUID REFERENCES users (user_id) ON DELETE SET NULL,
+ADD COLUMN uses INTEGER NOT NULL DEFAULT 0,
+ADD COLUMN max_uses INTEGER CHECK (max_uses > 0),
+ADD COLUMN revoked BOOLEAN NOT NULL DEFAULT FALSE;
@@ -0,0 +1,3 @@
+-- Add up migration script here
+ALTER TABLE servers
+ADD COLUMN icon VARCHAR(2048);
@@ -0,0 +1,4 @@
+-- Add down migration script here
+ALTER TABLE servers DROP COLUMN vanity_code;
+
+ALTER TABLE invites DROP COLUMN code;
@@ -0,0 +1,6 @@
+-- Add up migration script here
+ALTER TABLE invites
+ADD COLUMN code VARCHAR(8) UNIQUE;
+
+ALTER TABLE servers
+ADD COLUMN vanity_code VARCHAR(32) UNIQUE;
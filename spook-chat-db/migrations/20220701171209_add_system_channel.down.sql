@@ -0,0 +1,6 @@
+-- Add down migration script here
+DELETE FROM messages WHERE user_id IS NULL;
+
+ALTER TABLE messages ALTER COLUMN user_id SET NOT NULL;
+
+ALTER TABLE servers DROP COLUMN system_channel_id;
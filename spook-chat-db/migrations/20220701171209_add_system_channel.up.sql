@@ -0,0 +1,6 @@
+-- Add up migration script here
+ALTER TABLE servers
+ADD COLUMN system_channel_id UUID REFERENCES channels (channel_id) ON DELETE SET NULL;
+
+-- System messages are not written by any user
+ALTER TABLE messages ALTER COLUMN user_id DROP NOT NULL;
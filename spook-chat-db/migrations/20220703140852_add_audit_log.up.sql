@@ -0,0 +1,16 @@
+-- Add up migration script here
+CREATE TABLE IF NOT EXISTS audit_log (
+  entry_id UUID PRIMARY KEY,
+  server_id UUID NOT NULL,
+  actor_id UUID,
+  target_id UUID,
+  action VARCHAR(32) NOT NULL,
+  before JSONB,
+  after JSONB,
+  reason VARCHAR(512),
+  created_at TIMESTAMPTZ NOT NULL,
+  FOREIGN KEY (server_id) REFERENCES servers (server_id) ON DELETE CASCADE,
+  FOREIGN KEY (actor_id) REFERENCES users (user_id) ON DELETE SET NULL
+);
+
+CREATE INDEX audit_log_server_id_created_at_idx ON audit_log (server_id, created_at DESC);
@@ -0,0 +1,4 @@
+-- Add down migration script here
+ALTER TABLE users_servers
+DROP COLUMN nickname,
+DROP COLUMN avatar;
@@ -0,0 +1,4 @@
+-- Add up migration script here
+ALTER TABLE users_servers
+ADD COLUMN nickname VARCHAR(32),
+ADD COLUMN avatar VARCHAR(2048);
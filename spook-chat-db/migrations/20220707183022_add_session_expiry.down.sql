@@ -0,0 +1,7 @@
+-- Add down migration script here
+DROP INDEX IF EXISTS sessions_expires_at_idx;
+
+ALTER TABLE sessions
+DROP COLUMN expires_at,
+DROP COLUMN idle_expires_at,
+DROP COLUMN remember;
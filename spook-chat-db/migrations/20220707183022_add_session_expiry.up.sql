@@ -0,0 +1,11 @@
+-- Add up migration script here
+ALTER TABLE sessions
+ADD COLUMN expires_at TIMESTAMPTZ NOT NULL DEFAULT NOW() + INTERVAL '1 day',
+ADD COLUMN idle_expires_at TIMESTAMPTZ NOT NULL DEFAULT NOW() + INTERVAL '2 hours',
+ADD COLUMN remember BOOLEAN NOT NULL DEFAULT FALSE;
+
+ALTER TABLE sessions
+ALTER COLUMN expires_at DROP DEFAULT,
+ALTER COLUMN idle_expires_at DROP DEFAULT;
+
+CREATE INDEX sessions_expires_at_idx ON sessions (expires_at);
@@ -0,0 +1,9 @@
+-- Add up migration script here
+ALTER TABLE sessions
+ADD COLUMN user_agent VARCHAR(512),
+ADD COLUMN ip_address VARCHAR(45),
+ADD COLUMN last_seen_at TIMESTAMPTZ NOT NULL DEFAULT NOW();
+
+UPDATE sessions SET last_seen_at = created_at;
+
+ALTER TABLE sessions ALTER COLUMN last_seen_at DROP DEFAULT;
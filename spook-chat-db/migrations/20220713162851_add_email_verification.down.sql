@@ -0,0 +1,4 @@
+-- Add down migration script here
+DROP TABLE email_verifications;
+
+ALTER TABLE users DROP COLUMN email_verified_at;
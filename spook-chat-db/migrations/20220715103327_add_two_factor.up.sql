@@ -0,0 +1,18 @@
+-- Add up migration script here
+CREATE TABLE IF NOT EXISTS two_factor (
+  user_id UUID PRIMARY KEY,
+  secret VARCHAR(64) NOT NULL,
+  enabled_at TIMESTAMPTZ,
+  last_step BIGINT,
+  FOREIGN KEY (user_id) REFERENCES users (user_id) ON DELETE CASCADE
+);
+
+CREATE TABLE IF NOT EXISTS recovery_codes (
+  code_id UUID PRIMARY KEY,
+  user_id UUID NOT NULL,
+  code_hash VARCHAR(255) NOT NULL,
+  used_at TIMESTAMPTZ,
+  FOREIGN KEY (user_id) REFERENCES users (user_id) ON DELETE CASCADE
+);
+
+CREATE INDEX recovery_codes_user_id_idx ON recovery_codes (user_id);
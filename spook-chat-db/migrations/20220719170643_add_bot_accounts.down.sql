@@ -0,0 +1,4 @@
+-- Add down migration script here
+ALTER TABLE users
+DROP COLUMN bot_owner_id,
+DROP COLUMN bot;
@@ -0,0 +1,6 @@
+-- Add up migration script here
+ALTER TABLE users
+ADD COLUMN bot BOOLEAN NOT NULL DEFAULT FALSE,
+ADD COLUMN bot_owner_id UUID REFERENCES users (user_id) ON DELETE CASCADE;
+
+CREATE INDEX users_bot_owner_id_idx ON users (bot_owner_id);
@@ -0,0 +1,2 @@
+-- Add down migration script here
+DROP INDEX IF EXISTS users_email_address_lower_idx;
@@ -0,0 +1,15 @@
+-- Add up migration script here
+-- Addresses are compared case-insensitively from now on. Accounts whose addresses only
+-- differ in case can't be told apart anymore and have to be merged or changed by hand,
+-- list them with:
+--   SELECT LOWER(email_address), array_agg(user_id) FROM users GROUP BY 1 HAVING COUNT(*) > 1;
+DO $$
+BEGIN
+  IF EXISTS (SELECT 1 FROM users GROUP BY LOWER(email_address) HAVING COUNT(*) > 1) THEN
+    RAISE EXCEPTION 'Some accounts have E-Mail addresses that only differ in case'
+      USING HINT = 'Change the addresses so they differ in more than case, see the migration for a query listing them';
+  END IF;
+END
+$$;
+
+CREATE UNIQUE INDEX IF NOT EXISTS users_email_address_lower_idx ON users (LOWER(email_address));
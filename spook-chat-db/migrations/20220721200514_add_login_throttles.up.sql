@@ -0,0 +1,9 @@
+-- Add up migration script here
+CREATE TABLE IF NOT EXISTS login_throttles (
+  kind VARCHAR(16) NOT NULL,
+  subject VARCHAR(64) NOT NULL,
+  failures INTEGER NOT NULL,
+  last_failure_at TIMESTAMPTZ NOT NULL,
+  blocked_until TIMESTAMPTZ,
+  PRIMARY KEY (kind, subject)
+);
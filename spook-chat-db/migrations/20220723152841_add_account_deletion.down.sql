@@ -0,0 +1,21 @@
+-- Add down migration script here
+ALTER TABLE bans
+DROP CONSTRAINT bans_issued_by_fkey,
+ADD CONSTRAINT bans_issued_by_fkey
+  FOREIGN KEY (issued_by) REFERENCES users (user_id),
+DROP CONSTRAINT bans_user_id_fkey,
+ADD CONSTRAINT bans_user_id_fkey
+  FOREIGN KEY (user_id) REFERENCES users (user_id);
+
+ALTER TABLE users_servers
+DROP CONSTRAINT users_servers_user_id_fkey,
+ADD CONSTRAINT users_servers_user_id_fkey
+  FOREIGN KEY (user_id) REFERENCES users (user_id);
+
+ALTER TABLE sessions
+DROP CONSTRAINT sessions_user_id_fkey,
+ADD CONSTRAINT sessions_user_id_fkey
+  FOREIGN KEY (user_id) REFERENCES users (user_id);
+
+ALTER TABLE users
+DROP COLUMN deleted_at;
@@ -0,0 +1,294 @@
+use super::User;
+use crate::tokens;
+use chrono::{DateTime, Duration, Utc};
+use serde::{de, ser::SerializeSeq, Deserialize, Deserializer, Serialize, Serializer};
+use sqlx::PgPool;
+use std::fmt;
+use uuid::Uuid;
+
+/// Lets secret scanners and humans recognize leaked tokens
+const TOKEN_PREFIX: &str = "spk_";
+const TOKEN_LENGTH: usize = 40;
+
+/// What an API token may be used for, stored as a bitset like `Permissions`.
+/// Scopes only narrow down what the user may do, they never grant anything on top.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Scopes(i32);
+
+impl Scopes {
+    pub const READ_MESSAGES: Self = Self(1 << 0);
+    pub const SEND_MESSAGES: Self = Self(1 << 1);
+    /// Banning, kicking and timing out members
+    pub const MODERATE: Self = Self(1 << 2);
+
+    const NAMES: [(Self, &'static str); 3] = [
+        (Self::READ_MESSAGES, "read_messages"),
+        (Self::SEND_MESSAGES, "send_messages"),
+        (Self::MODERATE, "moderate"),
+    ];
+
+    pub const fn empty() -> Self {
+        Self(0)
+    }
+
+    pub const fn all() -> Self {
+        Self((1 << Self::NAMES.len()) - 1)
+    }
+
+    pub const fn bits(self) -> i32 {
+        self.0
+    }
+
+    pub const fn from_bits_truncate(bits: i32) -> Self {
+        Self(bits & Self::all().0)
+    }
+
+    pub const fn contains(self, other: Self) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    pub const fn is_empty(self) -> bool {
+        self.0 == 0
+    }
+
+    pub fn name(self) -> &'static str {
+        Self::NAMES
+            .iter()
+            .find(|(scope, _)| *scope == self)
+            .map(|(_, name)| *name)
+            .unwrap_or_default()
+    }
+}
+
+impl Serialize for Scopes {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut seq = serializer.serialize_seq(None)?;
+        for (scope, name) in Self::NAMES {
+            if self.contains(scope) {
+                seq.serialize_element(name)?;
+            }
+        }
+        seq.end()
+    }
+}
+
+impl<'de> Deserialize<'de> for Scopes {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct ScopesVisitor;
+
+        impl<'de> de::Visitor<'de> for ScopesVisitor {
+            type Value = Scopes;
+
+            fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+                formatter.write_str("a list of scope names")
+            }
+
+            fn visit_seq<A: de::SeqAccess<'de>>(self, mut seq: A) -> Result<Scopes, A::Error> {
+                let mut scopes = Scopes::empty();
+                while let Some(name) = seq.next_element::<String>()? {
+                    let (scope, _) = Scopes::NAMES
+                        .iter()
+                        .find(|(_, candidate)| *candidate == name)
+                        .ok_or_else(|| de::Error::custom(format!("unknown scope `{name}`")))?;
+                    scopes = Scopes(scopes.0 | scope.0);
+                }
+                Ok(scopes)
+            }
+        }
+
+        deserializer.deserialize_seq(ScopesVisitor)
+    }
+}
+
+/// A personal access token for clients that can't keep a session cookie.
+/// Only the hash of the token is stored, it is shown once when created.
+#[derive(Serialize)]
+pub struct ApiToken {
+    pub token_id: Uuid,
+    pub user_id: Uuid,
+    pub name: String,
+    #[serde(skip)]
+    pub token_hash: String,
+    pub scopes: Scopes,
+    pub created_at: DateTime<Utc>,
+    pub expires_at: Option<DateTime<Utc>>,
+    pub last_used_at: Option<DateTime<Utc>>,
+}
+
+impl ApiToken {
+    /// Creates a token for the user and returns it along with the secret to hand out
+    pub fn new(
+        user: &User,
+        name: &str,
+        scopes: Scopes,
+        expires_at: Option<DateTime<Utc>>,
+    ) -> (Self, String) {
+        let secret = format!("{TOKEN_PREFIX}{}", tokens::base62(TOKEN_LENGTH));
+        let token = Self {
+            token_id: Uuid::new_v4(),
+            user_id: user.user_id,
+            name: name.to_string(),
+            token_hash: tokens::hash(&secret),
+            scopes,
+            created_at: Utc::now(),
+            expires_at,
+            last_used_at: None,
+        };
+        (token, secret)
+    }
+
+    pub async fn save(&self, pool: &PgPool) -> sqlx::Result<()> {
+        sqlx::query!(
+            "INSERT INTO api_tokens (token_id, user_id, name, token_hash, scopes, created_at, expires_at, last_used_at)
+            VALUES ($1, $2, $3, $4, $5, $6, $7, $8)",
+            self.token_id,
+            self.user_id,
+            self.name,
+            self.token_hash,
+            self.scopes.bits(),
+            self.created_at,
+            self.expires_at,
+            self.last_used_at
+        )
+        .execute(pool)
+        .await?;
+
+        Ok(())
+    }
+
+    /// Looks up an unexpired token by its secret and records that it was used.
+    /// Tokens used within the last minute aren't updated again to spare the database.
+    pub async fn authenticate(pool: &PgPool, secret: &str) -> sqlx::Result<Option<Self>> {
+        let row = sqlx::query!(
+            "SELECT * FROM api_tokens
+            WHERE token_hash = $1 AND (expires_at IS NULL OR expires_at > NOW())",
+            tokens::hash(secret)
+        )
+        .fetch_optional(pool)
+        .await?;
+        let mut token = match row {
+            Some(row) => ApiToken {
+                token_id: row.token_id,
+                user_id: row.user_id,
+                name: row.name,
+                token_hash: row.token_hash,
+                scopes: Scopes::from_bits_truncate(row.scopes),
+                created_at: row.created_at,
+                expires_at: row.expires_at,
+                last_used_at: row.last_used_at,
+            },
+            None => return Ok(None),
+        };
+
+        let now = Utc::now();
+        let stale = match token.last_used_at {
+            Some(last_used) => now - last_used >= Duration::minutes(1),
+            None => true,
+        };
+        if stale {
+            sqlx::query!(
+                "UPDATE api_tokens SET last_used_at = $1 WHERE token_id = $2",
+                now,
+                token.token_id
+            )
+            .execute(pool)
+            .await?;
+            token.last_used_at = Some(now);
+        }
+
+        Ok(Some(token))
+    }
+
+    /// Whether the token still exists and hasn't expired
+    pub async fn is_active(pool: &PgPool, token_id: Uuid) -> sqlx::Result<bool> {
+        sqlx::query_scalar!(
+            "SELECT EXISTS (
+                SELECT 1 FROM api_tokens
+                WHERE token_id = $1 AND (expires_at IS NULL OR expires_at > NOW())
+            ) AS \"active!\"",
+            token_id
+        )
+        .fetch_one(pool)
+        .await
+    }
+
+    /// Deletes one of the user's tokens, returns `false` if they have no such token
+    pub async fn revoke(pool: &PgPool, user_id: Uuid, token_id: Uuid) -> sqlx::Result<bool> {
+        let result = sqlx::query!(
+            "DELETE FROM api_tokens WHERE token_id = $1 AND user_id = $2",
+            token_id,
+            user_id
+        )
+        .execute(pool)
+        .await?;
+
+        Ok(result.rows_affected() == 1)
+    }
+
+    /// Deletes every token of the user and returns their ids
+    pub async fn revoke_all(pool: &PgPool, user_id: Uuid) -> sqlx::Result<Vec<Uuid>> {
+        sqlx::query_scalar!(
+            "DELETE FROM api_tokens WHERE user_id = $1 RETURNING token_id",
+            user_id
+        )
+        .fetch_all(pool)
+        .await
+    }
+
+    /// Lists the user's tokens, expired ones included, newest first
+    pub async fn filter_by_user_id(pool: &PgPool, user_id: Uuid) -> sqlx::Result<Vec<Self>> {
+        let rows = sqlx::query!(
+            "SELECT * FROM api_tokens WHERE user_id = $1 ORDER BY created_at DESC",
+            user_id
+        )
+        .fetch_all(pool)
+        .await?;
+
+        Ok(rows
+            .into_iter()
+            .map(|row| ApiToken {
+                token_id: row.token_id,
+                user_id: row.user_id,
+                name: row.name,
+                token_hash: row.token_hash,
+                scopes: Scopes::from_bits_truncate(row.scopes),
+                created_at: row.created_at,
+                expires_at: row.expires_at,
+                last_used_at: row.last_used_at,
+            })
+            .collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn scopes_serialize_as_names() {
+        let scopes = Scopes(Scopes::READ_MESSAGES.0 | Scopes::MODERATE.0);
+        assert_eq!(
+            serde_json::to_string(&scopes).unwrap(),
+            r#"["read_messages","moderate"]"#
+        );
+        assert_eq!(serde_json::to_string(&Scopes::empty()).unwrap(), "[]");
+    }
+
+    #[test]
+    fn scopes_deserialize_from_names() {
+        let scopes: Scopes = serde_json::from_str(r#"["send_messages","read_messages"]"#).unwrap();
+        assert_eq!(
+            scopes,
+            Scopes(Scopes::READ_MESSAGES.0 | Scopes::SEND_MESSAGES.0)
+        );
+        let all: Scopes =
+            serde_json::from_str(r#"["read_messages","send_messages","moderate"]"#).unwrap();
+        assert_eq!(all, Scopes::all());
+    }
+
+    #[test]
+    fn unknown_scopes_are_rejected() {
+        assert!(serde_json::from_str::<Scopes>(r#"["read_messages","admin"]"#).is_err());
+        assert!(serde_json::from_str::<Scopes>(r#""read_messages""#).is_err());
+    }
+}
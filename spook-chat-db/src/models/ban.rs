@@ -0,0 +1,64 @@
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use sqlx::{FromRow, PgPool};
+use uuid::Uuid;
+
+#[derive(FromRow, Serialize)]
+pub struct Ban {
+    pub server_id: Uuid,
+    pub user_id: Uuid,
+    pub reason: Option<String>,
+    pub issued_by: Option<Uuid>,
+    pub created_at: DateTime<Utc>,
+    pub expires_at: Option<DateTime<Utc>>,
+}
+
+impl Ban {
+    pub fn new(
+        server_id: Uuid,
+        user_id: Uuid,
+        issued_by: Option<Uuid>,
+        reason: Option<&str>,
+        expires: Option<DateTime<Utc>>,
+    ) -> Self {
+        Self {
+            server_id,
+            user_id,
+            reason: reason.map(str::to_string),
+            issued_by,
+            created_at: Utc::now(),
+            expires_at: expires,
+        }
+    }
+
+    /// Check if this ban is still in effect
+    pub fn is_active(&self) -> bool {
+        if let Some(expires) = self.expires_at {
+            expires > Utc::now()
+        } else {
+            true
+        }
+    }
+
+    /// Lists the bans of a server that are still in effect, newest first
+    pub async fn filter_by_server_id(pool: &PgPool, server_id: Uuid) -> sqlx::Result<Vec<Self>> {
+        sqlx::query_as!(
+            Ban,
+            "SELECT * FROM bans WHERE server_id = $1
+                AND (expires_at IS NULL OR expires_at > NOW())
+            ORDER BY created_at DESC",
+            server_id
+        )
+        .fetch_all(pool)
+        .await
+    }
+
+    /// Removes every ban whose expiry has passed and returns how many were lifted
+    pub async fn lift_expired(pool: &PgPool) -> sqlx::Result<u64> {
+        let result = sqlx::query!("DELETE FROM bans WHERE expires_at <= NOW()")
+            .execute(pool)
+            .await?;
+
+        Ok(result.rows_affected())
+    }
+}
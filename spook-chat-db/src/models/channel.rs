@@ -1,24 +1,29 @@
-use super::server::Server;
+use super::{server::Server, Overwrite, Permissions, User};
 use chrono::{DateTime, Utc};
+use serde::Serialize;
 use sqlx::postgres::PgPool;
 use sqlx::FromRow;
 use uuid::Uuid;
 
-#[derive(FromRow)]
+#[derive(FromRow, Serialize)]
 pub struct Channel {
     pub channel_id: Uuid,
     pub name: String,
     pub server_id: Uuid,
     pub created_at: DateTime<Utc>,
+    pub topic: Option<String>,
+    pub position: i32,
 }
 
 impl Channel {
-    pub fn new(name: &str, server: Server) -> Self {
+    pub fn new(name: &str, server: &Server) -> Self {
         Self {
             channel_id: Uuid::new_v4(),
             name: name.to_string(),
             server_id: server.server_id,
             created_at: Utc::now(),
+            topic: None,
+            position: 0,
         }
     }
 
@@ -27,4 +32,89 @@ impl Channel {
             .fetch_all(pool)
             .await
     }
+
+    pub async fn filter_by_id(pool: &PgPool, id: Uuid) -> sqlx::Result<Option<Self>> {
+        sqlx::query_as!(Channel, "SELECT * FROM channels WHERE channel_id = $1", id)
+            .fetch_optional(pool)
+            .await
+    }
+
+    pub async fn filter_by_server_id(pool: &PgPool, server_id: Uuid) -> sqlx::Result<Vec<Self>> {
+        sqlx::query_as!(
+            Channel,
+            "SELECT * FROM channels WHERE server_id = $1 ORDER BY position, created_at",
+            server_id
+        )
+        .fetch_all(pool)
+        .await
+    }
+
+    /// The user's effective permissions in this channel after applying its overwrites,
+    /// `None` if they are not a member of its server
+    pub async fn permissions_for(
+        &self,
+        pool: &PgPool,
+        user: &User,
+    ) -> sqlx::Result<Option<Permissions>> {
+        let server = Server::filter_by_id(pool, self.server_id).await?;
+        let base = match server.get_permissions(pool, user).await? {
+            Some(permissions) => permissions,
+            None => return Ok(None),
+        };
+        let overwrites = Overwrite::affecting(pool, self, user.user_id).await?;
+
+        Ok(Some(Overwrite::resolve(
+            self,
+            user.user_id,
+            base,
+            &overwrites,
+        )))
+    }
+
+    pub async fn rename(&mut self, pool: &PgPool, name: &str) -> sqlx::Result<()> {
+        sqlx::query!(
+            "UPDATE channels SET name = $1 WHERE channel_id = $2",
+            name,
+            self.channel_id
+        )
+        .execute(pool)
+        .await?;
+
+        self.name = name.to_string();
+        Ok(())
+    }
+
+    pub async fn set_topic(&mut self, pool: &PgPool, topic: Option<&str>) -> sqlx::Result<()> {
+        sqlx::query!(
+            "UPDATE channels SET topic = $1 WHERE channel_id = $2",
+            topic,
+            self.channel_id
+        )
+        .execute(pool)
+        .await?;
+
+        self.topic = topic.map(str::to_string);
+        Ok(())
+    }
+
+    /// Deletes the channel together with every message that was posted in it
+    pub async fn delete(&self, pool: &PgPool) -> sqlx::Result<()> {
+        let mut transaction = pool.begin().await?;
+
+        sqlx::query!(
+            "DELETE FROM messages WHERE channel_id = $1",
+            self.channel_id
+        )
+        .execute(&mut transaction)
+        .await?;
+
+        sqlx::query!(
+            "DELETE FROM channels WHERE channel_id = $1",
+            self.channel_id
+        )
+        .execute(&mut transaction)
+        .await?;
+
+        transaction.commit().await
+    }
 }
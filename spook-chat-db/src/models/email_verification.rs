@@ -0,0 +1,95 @@
+use super::User;
+use crate::tokens;
+use chrono::{DateTime, Duration, Utc};
+use sqlx::PgPool;
+use uuid::Uuid;
+
+/// A token confirming that a user owns an E-Mail address. Redeeming it marks the
+/// address as verified, and makes it the user's address if they asked to change it.
+pub struct EmailVerification {
+    pub token_hash: String,
+    pub user_id: Uuid,
+    pub email_address: String,
+    pub created_at: DateTime<Utc>,
+    pub expires_at: DateTime<Utc>,
+}
+
+impl EmailVerification {
+    /// Creates a verification of `email_address` for the user and returns it
+    /// along with the token to send there
+    pub fn new(user: &User, email_address: &str, lifetime: Duration) -> (Self, String) {
+        let token = tokens::base62(tokens::SECRET_LENGTH);
+        let created_at = Utc::now();
+        let verification = Self {
+            token_hash: tokens::hash(&token),
+            user_id: user.user_id,
+            email_address: email_address.to_string(),
+            created_at,
+            expires_at: created_at + lifetime,
+        };
+        (verification, token)
+    }
+
+    pub async fn save(&self, pool: &PgPool) -> sqlx::Result<()> {
+        sqlx::query!(
+            "INSERT INTO email_verifications (token_hash, user_id, email_address, created_at, expires_at)
+            VALUES ($1, $2, $3, $4, $5)",
+            self.token_hash,
+            self.user_id,
+            self.email_address,
+            self.created_at,
+            self.expires_at
+        )
+        .execute(pool)
+        .await?;
+
+        Ok(())
+    }
+
+    /// Uses up the token and verifies its address. Pending verifications of the
+    /// user are dropped, so an older request can't switch the address back.
+    /// Returns the user's id, or `None` if the token is unknown or expired.
+    pub async fn redeem(pool: &PgPool, token: &str) -> sqlx::Result<Option<Uuid>> {
+        let mut transaction = pool.begin().await?;
+
+        let verification = sqlx::query!(
+            "DELETE FROM email_verifications
+            WHERE token_hash = $1 AND expires_at > NOW()
+            RETURNING user_id, email_address",
+            tokens::hash(token)
+        )
+        .fetch_optional(&mut transaction)
+        .await?;
+        let verification = match verification {
+            Some(verification) => verification,
+            None => return Ok(None),
+        };
+
+        sqlx::query!(
+            "UPDATE users SET email_address = $1, email_verified_at = NOW() WHERE user_id = $2",
+            verification.email_address,
+            verification.user_id
+        )
+        .execute(&mut transaction)
+        .await?;
+
+        sqlx::query!(
+            "DELETE FROM email_verifications WHERE user_id = $1",
+            verification.user_id
+        )
+        .execute(&mut transaction)
+        .await?;
+
+        transaction.commit().await?;
+        Ok(Some(verification.user_id))
+    }
+
+    /// Removes verifications that can no longer be used and returns how many were deleted
+    pub async fn purge_expired(pool: &PgPool) -> sqlx::Result<u64> {
+        let result = sqlx::query!("DELETE FROM email_verifications WHERE expires_at <= NOW()")
+            .execute(pool)
+            .await?;
+
+        Ok(result.rows_affected())
+    }
+}
@@ -1,40 +1,116 @@
 use super::Server;
+use crate::tokens;
 use chrono::{DateTime, Utc};
+use serde::Serialize;
 use sqlx::{postgres::PgQueryResult, PgPool};
 use uuid::Uuid;
 
+pub const INVITE_CODE_LENGTH: usize = 8;
+const MAX_CODE_ATTEMPTS: usize = 5;
+/// Invite and vanity codes live in two tables but share one namespace, so whoever takes
+/// a code holds this advisory lock while checking the other table
+pub(crate) const CODE_LOCK: i64 = 0x7370_6f6f_6b63_6f64;
+
+/// A random base62 code of `INVITE_CODE_LENGTH` characters
+fn generate_code() -> String {
+    tokens::base62(INVITE_CODE_LENGTH)
+}
+
+/// How redeeming an invite went
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Redemption {
+    Joined,
+    /// The user joined through another request in the meantime, the invite wasn't used up
+    AlreadyMember,
+    /// The invite was revoked, used up or expired in the meantime
+    Unusable,
+}
+
+#[derive(Serialize)]
 pub struct Invite {
     pub invite_id: Uuid,
+    /// Short code to share instead of the id, missing on invites created before codes existed
+    pub code: Option<String>,
     pub server_id: Uuid,
     pub created_at: DateTime<Utc>,
     pub expires_at: Option<DateTime<Utc>>,
+    pub created_by: Option<Uuid>,
+    pub uses: i32,
+    pub max_uses: Option<i32>,
+    pub revoked: bool,
 }
 
 impl Invite {
-    pub fn new(server: &Server, expires: Option<DateTime<Utc>>) -> Self {
+    pub fn new(
+        server: &Server,
+        created_by: Uuid,
+        expires: Option<DateTime<Utc>>,
+        max_uses: Option<i32>,
+    ) -> Self {
         Self {
             invite_id: Uuid::new_v4(),
+            code: Some(generate_code()),
             server_id: server.server_id,
             created_at: Utc::now(),
             expires_at: expires,
+            created_by: Some(created_by),
+            uses: 0,
+            max_uses,
+            revoked: false,
         }
     }
 
-    pub async fn save(&self, pool: &PgPool) -> sqlx::Result<PgQueryResult> {
-        sqlx::query!(
-            "INSERT INTO invites (invite_id, server_id, created_at, expires_at)
-            VALUES ($1, $2, $3, $4)",
-            self.invite_id,
-            self.server_id,
-            self.created_at,
-            self.expires_at
-        )
-        .execute(pool)
-        .await
+    /// Saves the invite, drawing a new code if the current one is already taken
+    /// by another invite or a server's vanity code.
+    /// Returns `false` without saving if no free code turned up.
+    pub async fn save(&mut self, pool: &PgPool) -> sqlx::Result<bool> {
+        for _ in 0..MAX_CODE_ATTEMPTS {
+            let mut transaction = pool.begin().await?;
+            sqlx::query!("SELECT pg_advisory_xact_lock($1)", CODE_LOCK)
+                .execute(&mut transaction)
+                .await?;
+            let result = sqlx::query!(
+                "INSERT INTO invites (invite_id, code, server_id, created_at, expires_at, created_by, uses, max_uses, revoked)
+                SELECT $1, $2::VARCHAR, $3, $4, $5, $6, $7, $8, $9
+                WHERE NOT EXISTS (SELECT 1 FROM servers WHERE vanity_code = $2::VARCHAR)",
+                self.invite_id,
+                self.code,
+                self.server_id,
+                self.created_at,
+                self.expires_at,
+                self.created_by,
+                self.uses,
+                self.max_uses,
+                self.revoked
+            )
+            .execute(&mut transaction)
+            .await;
+
+            match result {
+                Ok(result) if result.rows_affected() == 1 => {
+                    transaction.commit().await?;
+                    return Ok(true);
+                }
+                Ok(_) => {}
+                Err(sqlx::Error::Database(e)) if e.code().as_deref() == Some("23505") => {}
+                Err(e) => return Err(e),
+            }
+            self.code = Some(generate_code());
+        }
+
+        Ok(false)
     }
 
-    /// Check if this invite has expired yet
+    /// Check if this invite can still be used: it has not been revoked, used up or expired yet
     pub fn is_valid(&self) -> bool {
+        if self.revoked {
+            return false;
+        }
+        if let Some(max_uses) = self.max_uses {
+            if self.uses >= max_uses {
+                return false;
+            }
+        }
         if let Some(expires) = self.expires_at {
             expires > Utc::now()
         } else {
@@ -42,9 +118,81 @@ impl Invite {
         }
     }
 
+    /// Counts a use of the invite and adds the user to its server.
+    /// Checking validity in the same statement keeps concurrent joins from exceeding
+    /// `max_uses`. Members joining again don't use it up.
+    pub async fn redeem(&self, pool: &PgPool, user_id: Uuid) -> sqlx::Result<Redemption> {
+        let mut transaction = pool.begin().await?;
+
+        let used = sqlx::query!(
+            "UPDATE invites SET uses = uses + 1
+            WHERE invite_id = $1 AND NOT revoked
+                AND (max_uses IS NULL OR uses < max_uses)
+                AND (expires_at IS NULL OR expires_at > NOW())",
+            self.invite_id
+        )
+        .execute(&mut transaction)
+        .await?;
+        if used.rows_affected() == 0 {
+            return Ok(Redemption::Unusable);
+        }
+
+        let joined = sqlx::query!(
+            "INSERT INTO users_servers (user_id, server_id)
+            VALUES ($1, $2)
+            ON CONFLICT (user_id, server_id) DO NOTHING",
+            user_id,
+            self.server_id
+        )
+        .execute(&mut transaction)
+        .await?;
+
+        if joined.rows_affected() == 0 {
+            return Ok(Redemption::AlreadyMember);
+        }
+        transaction.commit().await?;
+        Ok(Redemption::Joined)
+    }
+
+    pub async fn revoke(&mut self, pool: &PgPool) -> sqlx::Result<PgQueryResult> {
+        let result = sqlx::query!(
+            "UPDATE invites SET revoked = TRUE WHERE invite_id = $1",
+            self.invite_id
+        )
+        .execute(pool)
+        .await?;
+
+        self.revoked = true;
+        Ok(result)
+    }
+
     pub async fn filter_by_id(pool: &PgPool, id: Uuid) -> sqlx::Result<Option<Invite>> {
         sqlx::query_as!(Invite, "SELECT * FROM invites WHERE invite_id = $1", id)
             .fetch_optional(pool)
             .await
     }
+
+    pub async fn filter_by_code(pool: &PgPool, code: &str) -> sqlx::Result<Option<Invite>> {
+        sqlx::query_as!(Invite, "SELECT * FROM invites WHERE code = $1", code)
+            .fetch_optional(pool)
+            .await
+    }
+
+    /// Invites of the server that can still be used, newest first
+    pub async fn filter_active_by_server_id(
+        pool: &PgPool,
+        server_id: Uuid,
+    ) -> sqlx::Result<Vec<Self>> {
+        sqlx::query_as!(
+            Invite,
+            "SELECT * FROM invites
+            WHERE server_id = $1 AND NOT revoked
+                AND (max_uses IS NULL OR uses < max_uses)
+                AND (expires_at IS NULL OR expires_at > NOW())
+            ORDER BY created_at DESC",
+            server_id
+        )
+        .fetch_all(pool)
+        .await
+    }
 }
@@ -0,0 +1,143 @@
+use super::User;
+use crate::tokens;
+use chrono::{DateTime, Utc};
+use sqlx::PgPool;
+
+/// What failed login attempts, or password reset requests, are counted against
+#[derive(Clone, Copy)]
+pub enum ThrottleKind {
+    Account,
+    IpAddress,
+    ResetAddress,
+    ResetIpAddress,
+}
+
+impl ThrottleKind {
+    pub(crate) fn as_str(self) -> &'static str {
+        match self {
+            Self::Account => "account",
+            Self::IpAddress => "ip_address",
+            Self::ResetAddress => "reset_address",
+            Self::ResetIpAddress => "reset_ip",
+        }
+    }
+}
+
+/// Whether an attempt may go ahead
+pub enum LoginAttempt {
+    /// It was counted as the given failure in a row, until it turns out to be right
+    Allowed(i32),
+    /// Attempts are refused until then, this one wasn't counted
+    Blocked(DateTime<Utc>),
+}
+
+/// Failed login attempts against one account or from one IP address.
+/// `subject` is the hash of the E-Mail address the login was for, whether or not
+/// an account has it, or the IP address. Password reset requests are counted the same way.
+pub struct LoginThrottle;
+
+impl LoginThrottle {
+    /// The subject attempts for an E-Mail address are counted under, whether or not
+    /// an account has the address. It's hashed so the table doesn't collect addresses.
+    pub fn address_subject(email: &str) -> String {
+        tokens::hash(&User::normalize_email(email))
+    }
+
+    /// Counts an attempt as failed before it is checked, unless attempts are refused
+    /// right now. The row is locked meanwhile, so parallel attempts each see the count
+    /// and block left by the others instead of all slipping past. `block_after` turns
+    /// the new count into until when further attempts are refused, if they are.
+    /// The count starts over if the last failure happened before `since`.
+    pub async fn attempt(
+        pool: &PgPool,
+        kind: ThrottleKind,
+        subject: &str,
+        since: DateTime<Utc>,
+        block_after: impl FnOnce(i32) -> Option<DateTime<Utc>>,
+    ) -> sqlx::Result<LoginAttempt> {
+        let mut transaction = pool.begin().await?;
+
+        sqlx::query!(
+            "INSERT INTO login_throttles (kind, subject, failures, last_failure_at)
+            VALUES ($1, $2, 0, NOW())
+            ON CONFLICT (kind, subject) DO NOTHING",
+            kind.as_str(),
+            subject
+        )
+        .execute(&mut transaction)
+        .await?;
+        let row = sqlx::query!(
+            "SELECT failures, last_failure_at, blocked_until FROM login_throttles
+            WHERE kind = $1 AND subject = $2
+            FOR UPDATE",
+            kind.as_str(),
+            subject
+        )
+        .fetch_one(&mut transaction)
+        .await?;
+
+        if let Some(until) = row.blocked_until.filter(|until| *until > Utc::now()) {
+            return Ok(LoginAttempt::Blocked(until));
+        }
+        let failures = if row.last_failure_at < since {
+            1
+        } else {
+            row.failures + 1
+        };
+        sqlx::query!(
+            "UPDATE login_throttles SET failures = $1, last_failure_at = NOW(), blocked_until = $2
+            WHERE kind = $3 AND subject = $4",
+            failures,
+            block_after(failures),
+            kind.as_str(),
+            subject
+        )
+        .execute(&mut transaction)
+        .await?;
+
+        transaction.commit().await?;
+        Ok(LoginAttempt::Allowed(failures))
+    }
+
+    /// Takes back the failure `attempt` counted for an attempt that went through,
+    /// but leaves any block in place
+    pub async fn uncount(pool: &PgPool, kind: ThrottleKind, subject: &str) -> sqlx::Result<()> {
+        sqlx::query!(
+            "UPDATE login_throttles SET failures = GREATEST(failures - 1, 0)
+            WHERE kind = $1 AND subject = $2",
+            kind.as_str(),
+            subject
+        )
+        .execute(pool)
+        .await?;
+
+        Ok(())
+    }
+
+    /// Forgets the failures, after a successful login
+    pub async fn clear(pool: &PgPool, kind: ThrottleKind, subject: &str) -> sqlx::Result<()> {
+        sqlx::query!(
+            "DELETE FROM login_throttles WHERE kind = $1 AND subject = $2",
+            kind.as_str(),
+            subject
+        )
+        .execute(pool)
+        .await?;
+
+        Ok(())
+    }
+
+    /// Removes throttles that no longer block anything and whose last failure
+    /// happened before `since`, returns how many were deleted
+    pub async fn purge_stale(pool: &PgPool, since: DateTime<Utc>) -> sqlx::Result<u64> {
+        let result = sqlx::query!(
+            "DELETE FROM login_throttles
+            WHERE last_failure_at < $1 AND (blocked_until IS NULL OR blocked_until <= NOW())",
+            since
+        )
+        .execute(pool)
+        .await?;
+
+        Ok(result.rows_affected())
+    }
+}
@@ -1,5 +1,6 @@
 use super::{channel::Channel, user::User};
 use chrono::{DateTime, Utc};
+use serde::Serialize;
 use sqlx::postgres::PgPool;
 use sqlx::FromRow;
 use uuid::Uuid;
@@ -11,6 +12,16 @@ pub struct Message {
     pub created_at: DateTime<Utc>,
 }
 
+/// A message as listed to its author, along with where it was posted
+#[derive(FromRow, Serialize)]
+pub struct AuthoredMessage {
+    pub message_id: Uuid,
+    pub content: String,
+    pub created_at: DateTime<Utc>,
+    pub channel_id: Uuid,
+    pub server_id: Uuid,
+}
+
 impl Message {
     pub fn new(content: &str) -> Self {
         Self {
@@ -35,4 +46,20 @@ impl Message {
 
         Ok(())
     }
+
+    /// Saves the message without an author, as a notice from the server itself
+    pub async fn save_system(&self, pool: &PgPool, channel_id: Uuid) -> sqlx::Result<()> {
+        sqlx::query!(
+            "INSERT INTO messages (message_id, content, created_at, channel_id)
+            VALUES ($1, $2, $3, $4)",
+            self.message_id,
+            self.content,
+            self.created_at,
+            channel_id
+        )
+        .execute(pool)
+        .await?;
+
+        Ok(())
+    }
 }
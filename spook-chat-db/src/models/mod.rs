@@ -1,11 +1,28 @@
+pub mod api_token;
+pub mod audit;
+pub mod ban;
 pub mod channel;
+pub mod email_verification;
 pub mod invite;
+pub mod login_throttle;
 pub mod message;
+pub mod overwrite;
+pub mod password_reset;
+pub mod permissions;
+pub mod role;
 pub mod server;
 pub mod session;
+pub mod two_factor;
 pub mod user;
 
 pub use self::{
-    channel::Channel, invite::Invite, message::Message, server::ChangePermissions,
-    server::Permissions, server::Server, session::Session, user::User,
+    api_token::ApiToken, api_token::Scopes, audit::AuditAction, audit::AuditEntry,
+    audit::AuditFilter, ban::Ban, channel::Channel, email_verification::EmailVerification,
+    invite::Invite, invite::Redemption, login_throttle::LoginAttempt,
+    login_throttle::LoginThrottle, login_throttle::ThrottleKind, message::AuthoredMessage,
+    message::Message, overwrite::Overwrite, password_reset::PasswordReset,
+    permissions::Permissions, role::Role, server::ChangePermissions, server::Member,
+    server::Profile, server::Server, session::Session, session::SessionLifetime,
+    two_factor::TwoFactor, user::DeletedAccount, user::Membership, user::MessagePolicy,
+    user::RevokedCredentials, user::User,
 };
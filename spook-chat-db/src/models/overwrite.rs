@@ -0,0 +1,331 @@
+use super::{Channel, Permissions};
+use serde::Serialize;
+use sqlx::{postgres::PgQueryResult, PgPool};
+use uuid::Uuid;
+
+/// Permissions allowed or denied in a single channel for either a role or a member
+#[derive(Serialize)]
+pub struct Overwrite {
+    pub overwrite_id: Uuid,
+    pub channel_id: Uuid,
+    pub role_id: Option<Uuid>,
+    pub user_id: Option<Uuid>,
+    pub allow: Permissions,
+    pub deny: Permissions,
+}
+
+impl Overwrite {
+    pub fn for_role(
+        channel_id: Uuid,
+        role_id: Uuid,
+        allow: Permissions,
+        deny: Permissions,
+    ) -> Self {
+        Self {
+            overwrite_id: Uuid::new_v4(),
+            channel_id,
+            role_id: Some(role_id),
+            user_id: None,
+            allow,
+            deny: deny.difference(allow),
+        }
+    }
+
+    pub fn for_member(
+        channel_id: Uuid,
+        user_id: Uuid,
+        allow: Permissions,
+        deny: Permissions,
+    ) -> Self {
+        Self {
+            overwrite_id: Uuid::new_v4(),
+            channel_id,
+            role_id: None,
+            user_id: Some(user_id),
+            allow,
+            deny: deny.difference(allow),
+        }
+    }
+
+    /// Applies the overwrites to the server-wide permissions of a member.
+    /// The everyone role's overwrite goes first, then those of the member's other roles
+    /// combined and finally the member's own. Administrators are not affected and
+    /// without `READ_MESSAGES` the channel is hidden, so nothing else is allowed either.
+    /// Role overwrites aren't checked against the member's roles, `overwrites` has to
+    /// hold only those affecting the member, the way `affecting` returns them.
+    pub fn resolve(
+        channel: &Channel,
+        user_id: Uuid,
+        base: Permissions,
+        overwrites: &[Overwrite],
+    ) -> Permissions {
+        if base.contains(Permissions::ADMINISTRATOR) {
+            return base;
+        }
+
+        let mut permissions = base;
+        if let Some(everyone) = overwrites
+            .iter()
+            .find(|overwrite| overwrite.role_id == Some(channel.server_id))
+        {
+            permissions = permissions.apply(everyone.allow, everyone.deny);
+        }
+
+        let (allow, deny) = overwrites
+            .iter()
+            .filter(|overwrite| {
+                overwrite.role_id.is_some() && overwrite.role_id != Some(channel.server_id)
+            })
+            .fold(
+                (Permissions::empty(), Permissions::empty()),
+                |(allow, deny), overwrite| (allow | overwrite.allow, deny | overwrite.deny),
+            );
+        permissions = permissions.apply(allow, deny);
+
+        if let Some(member) = overwrites
+            .iter()
+            .find(|overwrite| overwrite.user_id == Some(user_id))
+        {
+            permissions = permissions.apply(member.allow, member.deny);
+        }
+
+        if permissions.contains(Permissions::READ_MESSAGES) {
+            permissions
+        } else {
+            Permissions::empty()
+        }
+    }
+
+    /// Inserts the overwrite or replaces the one already set for its role or member
+    pub async fn save(&self, pool: &PgPool) -> sqlx::Result<PgQueryResult> {
+        if self.role_id.is_some() {
+            sqlx::query!(
+                "INSERT INTO channel_overwrites (overwrite_id, channel_id, role_id, allow, deny)
+                VALUES ($1, $2, $3, $4, $5)
+                ON CONFLICT (channel_id, role_id) DO UPDATE SET allow = $4, deny = $5",
+                self.overwrite_id,
+                self.channel_id,
+                self.role_id,
+                self.allow.bits(),
+                self.deny.bits()
+            )
+            .execute(pool)
+            .await
+        } else {
+            sqlx::query!(
+                "INSERT INTO channel_overwrites (overwrite_id, channel_id, user_id, allow, deny)
+                VALUES ($1, $2, $3, $4, $5)
+                ON CONFLICT (channel_id, user_id) DO UPDATE SET allow = $4, deny = $5",
+                self.overwrite_id,
+                self.channel_id,
+                self.user_id,
+                self.allow.bits(),
+                self.deny.bits()
+            )
+            .execute(pool)
+            .await
+        }
+    }
+
+    /// Removes the overwrite of a role or member, whichever `target_id` belongs to
+    pub async fn delete(
+        pool: &PgPool,
+        channel_id: Uuid,
+        target_id: Uuid,
+    ) -> sqlx::Result<PgQueryResult> {
+        sqlx::query!(
+            "DELETE FROM channel_overwrites
+            WHERE channel_id = $1 AND (role_id = $2 OR user_id = $2)",
+            channel_id,
+            target_id
+        )
+        .execute(pool)
+        .await
+    }
+
+    pub async fn filter_by_channel_id(pool: &PgPool, channel_id: Uuid) -> sqlx::Result<Vec<Self>> {
+        let rows = sqlx::query!(
+            "SELECT * FROM channel_overwrites WHERE channel_id = $1",
+            channel_id
+        )
+        .fetch_all(pool)
+        .await?;
+
+        Ok(rows
+            .into_iter()
+            .map(|row| Overwrite {
+                overwrite_id: row.overwrite_id,
+                channel_id: row.channel_id,
+                role_id: row.role_id,
+                user_id: row.user_id,
+                allow: Permissions::from_bits_truncate(row.allow),
+                deny: Permissions::from_bits_truncate(row.deny),
+            })
+            .collect())
+    }
+
+    /// The overwrites of the channel that apply to the member: their own,
+    /// the everyone role's and those of the roles they hold
+    pub async fn affecting(
+        pool: &PgPool,
+        channel: &Channel,
+        user_id: Uuid,
+    ) -> sqlx::Result<Vec<Self>> {
+        let rows = sqlx::query!(
+            "SELECT * FROM channel_overwrites A
+            WHERE A.channel_id = $1 AND (
+                A.user_id = $2 OR A.role_id = $3 OR A.role_id IN (
+                    SELECT B.role_id FROM members_roles B
+                    WHERE B.user_id = $2 AND B.server_id = $3
+                )
+            )",
+            channel.channel_id,
+            user_id,
+            channel.server_id
+        )
+        .fetch_all(pool)
+        .await?;
+
+        Ok(rows
+            .into_iter()
+            .map(|row| Overwrite {
+                overwrite_id: row.overwrite_id,
+                channel_id: row.channel_id,
+                role_id: row.role_id,
+                user_id: row.user_id,
+                allow: Permissions::from_bits_truncate(row.allow),
+                deny: Permissions::from_bits_truncate(row.deny),
+            })
+            .collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::Utc;
+
+    fn channel() -> Channel {
+        Channel {
+            channel_id: Uuid::new_v4(),
+            name: "general".to_string(),
+            server_id: Uuid::new_v4(),
+            created_at: Utc::now(),
+            topic: None,
+            position: 0,
+        }
+    }
+
+    const READ: Permissions = Permissions::READ_MESSAGES;
+    const SEND: Permissions = Permissions::SEND_MESSAGES;
+    const ATTACH: Permissions = Permissions::ATTACH_FILES;
+
+    #[test]
+    fn levels_apply_in_order() {
+        let channel = channel();
+        let user_id = Uuid::new_v4();
+        let role_id = Uuid::new_v4();
+        let base = READ | SEND;
+
+        let everyone = Overwrite::for_role(
+            channel.channel_id,
+            channel.server_id,
+            Permissions::empty(),
+            SEND,
+        );
+        let role = Overwrite::for_role(
+            channel.channel_id,
+            role_id,
+            SEND | ATTACH,
+            Permissions::empty(),
+        );
+        let member =
+            Overwrite::for_member(channel.channel_id, user_id, Permissions::empty(), ATTACH);
+
+        let only_everyone = [Overwrite::for_role(
+            channel.channel_id,
+            channel.server_id,
+            Permissions::empty(),
+            SEND,
+        )];
+        assert_eq!(
+            Overwrite::resolve(&channel, user_id, base, &only_everyone),
+            READ
+        );
+
+        // The role's allow wins over the everyone role's deny, the member's deny over both
+        let overwrites = [member, role, everyone];
+        assert_eq!(
+            Overwrite::resolve(&channel, user_id, base, &overwrites),
+            READ | SEND
+        );
+    }
+
+    #[test]
+    fn deny_loses_to_allow_on_the_same_level() {
+        let channel = channel();
+        let user_id = Uuid::new_v4();
+        let overwrites = [
+            Overwrite::for_role(
+                channel.channel_id,
+                Uuid::new_v4(),
+                Permissions::empty(),
+                SEND,
+            ),
+            Overwrite::for_role(
+                channel.channel_id,
+                Uuid::new_v4(),
+                SEND,
+                Permissions::empty(),
+            ),
+        ];
+        assert_eq!(
+            Overwrite::resolve(&channel, user_id, READ, &overwrites),
+            READ | SEND
+        );
+
+        let member = [Overwrite::for_member(
+            channel.channel_id,
+            user_id,
+            SEND,
+            SEND,
+        )];
+        assert_eq!(
+            Overwrite::resolve(&channel, user_id, READ, &member),
+            READ | SEND
+        );
+    }
+
+    #[test]
+    fn administrators_are_not_affected() {
+        let channel = channel();
+        let user_id = Uuid::new_v4();
+        let base = Permissions::all();
+        let overwrites = [Overwrite::for_member(
+            channel.channel_id,
+            user_id,
+            Permissions::empty(),
+            Permissions::all(),
+        )];
+        assert_eq!(
+            Overwrite::resolve(&channel, user_id, base, &overwrites),
+            base
+        );
+    }
+
+    #[test]
+    fn hidden_channels_allow_nothing() {
+        let channel = channel();
+        let user_id = Uuid::new_v4();
+        let overwrites = [Overwrite::for_role(
+            channel.channel_id,
+            channel.server_id,
+            Permissions::empty(),
+            READ,
+        )];
+        assert_eq!(
+            Overwrite::resolve(&channel, user_id, READ | SEND | ATTACH, &overwrites),
+            Permissions::empty()
+        );
+    }
+}
@@ -0,0 +1,119 @@
+use super::{RevokedCredentials, User};
+use crate::tokens;
+use chrono::{DateTime, Duration, Utc};
+use sqlx::PgPool;
+use uuid::Uuid;
+
+/// A single-use token that lets a user pick a new password without knowing the old one.
+/// Only the hash of the token is stored, the token itself goes out by email.
+pub struct PasswordReset {
+    pub token_hash: String,
+    pub user_id: Uuid,
+    pub created_at: DateTime<Utc>,
+    pub expires_at: DateTime<Utc>,
+    pub used_at: Option<DateTime<Utc>>,
+}
+
+impl PasswordReset {
+    /// Creates a reset for the user and returns it along with the token to send them
+    pub fn new(user: &User, lifetime: Duration) -> (Self, String) {
+        let token = tokens::base62(tokens::SECRET_LENGTH);
+        let created_at = Utc::now();
+        let reset = Self {
+            token_hash: tokens::hash(&token),
+            user_id: user.user_id,
+            created_at,
+            expires_at: created_at + lifetime,
+            used_at: None,
+        };
+        (reset, token)
+    }
+
+    pub async fn save(&self, pool: &PgPool) -> sqlx::Result<()> {
+        sqlx::query!(
+            "INSERT INTO password_resets (token_hash, user_id, created_at, expires_at, used_at)
+            VALUES ($1, $2, $3, $4, $5)",
+            self.token_hash,
+            self.user_id,
+            self.created_at,
+            self.expires_at,
+            self.used_at
+        )
+        .execute(pool)
+        .await?;
+
+        Ok(())
+    }
+
+    /// Uses up the token and sets the new password, which logs the user out everywhere
+    /// and revokes their API tokens. Returns the user's id and what was ended, or `None`
+    /// if the token is unknown, expired or was used already.
+    pub async fn redeem(
+        pool: &PgPool,
+        token: &str,
+        password: &str,
+    ) -> sqlx::Result<Option<(Uuid, RevokedCredentials)>> {
+        let mut transaction = pool.begin().await?;
+
+        let user_id = sqlx::query_scalar!(
+            "UPDATE password_resets SET used_at = NOW()
+            WHERE token_hash = $1 AND used_at IS NULL AND expires_at > NOW()
+            RETURNING user_id",
+            tokens::hash(token)
+        )
+        .fetch_optional(&mut transaction)
+        .await?;
+        let user_id = match user_id {
+            Some(user_id) => user_id,
+            None => return Ok(None),
+        };
+
+        sqlx::query!(
+            "UPDATE users SET password = $1 WHERE user_id = $2",
+            User::hash_password(password),
+            user_id
+        )
+        .execute(&mut transaction)
+        .await?;
+
+        // Other tokens requested before would still allow resetting the password again
+        sqlx::query!(
+            "DELETE FROM password_resets WHERE user_id = $1 AND used_at IS NULL",
+            user_id
+        )
+        .execute(&mut transaction)
+        .await?;
+
+        let sessions = sqlx::query_scalar!(
+            "DELETE FROM sessions WHERE user_id = $1 RETURNING session_id",
+            user_id
+        )
+        .fetch_all(&mut transaction)
+        .await?;
+
+        let api_tokens = sqlx::query_scalar!(
+            "DELETE FROM api_tokens WHERE user_id = $1 RETURNING token_id",
+            user_id
+        )
+        .fetch_all(&mut transaction)
+        .await?;
+
+        transaction.commit().await?;
+        Ok(Some((
+            user_id,
+            RevokedCredentials {
+                sessions,
+                api_tokens,
+            },
+        )))
+    }
+
+    /// Removes resets that can no longer be used and returns how many were deleted
+    pub async fn purge_expired(pool: &PgPool) -> sqlx::Result<u64> {
+        let result = sqlx::query!("DELETE FROM password_resets WHERE expires_at <= NOW()")
+            .execute(pool)
+            .await?;
+
+        Ok(result.rows_affected())
+    }
+}
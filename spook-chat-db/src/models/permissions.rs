@@ -0,0 +1,223 @@
+use serde::{de, ser::SerializeSeq, Deserialize, Deserializer, Serialize, Serializer};
+use std::fmt;
+use std::ops::{BitOr, BitOrAssign};
+
+/// A set of permissions, stored as a bitset in the database.
+/// Serialized as the list of permission names it contains.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Permissions(i64);
+
+impl Permissions {
+    pub const READ_MESSAGES: Self = Self(1 << 0);
+    pub const SEND_MESSAGES: Self = Self(1 << 1);
+    pub const ATTACH_FILES: Self = Self(1 << 2);
+    pub const MENTION_EVERYONE: Self = Self(1 << 3);
+    pub const MANAGE_MESSAGES: Self = Self(1 << 4);
+    pub const MANAGE_CHANNELS: Self = Self(1 << 5);
+    pub const MANAGE_USERS: Self = Self(1 << 6);
+    pub const MANAGE_INVITES: Self = Self(1 << 7);
+    pub const MANAGE_ROLES: Self = Self(1 << 8);
+    /// Grants every other permission
+    pub const ADMINISTRATOR: Self = Self(1 << 9);
+    pub const VIEW_AUDIT_LOG: Self = Self(1 << 10);
+
+    /// What the everyone role of a new server allows
+    pub const DEFAULT: Self =
+        Self(Self::READ_MESSAGES.0 | Self::SEND_MESSAGES.0 | Self::ATTACH_FILES.0);
+
+    const NAMES: [(Self, &'static str); 11] = [
+        (Self::READ_MESSAGES, "read_messages"),
+        (Self::SEND_MESSAGES, "send_messages"),
+        (Self::ATTACH_FILES, "attach_files"),
+        (Self::MENTION_EVERYONE, "mention_everyone"),
+        (Self::MANAGE_MESSAGES, "manage_messages"),
+        (Self::MANAGE_CHANNELS, "manage_channels"),
+        (Self::MANAGE_USERS, "manage_users"),
+        (Self::MANAGE_INVITES, "manage_invites"),
+        (Self::MANAGE_ROLES, "manage_roles"),
+        (Self::ADMINISTRATOR, "administrator"),
+        (Self::VIEW_AUDIT_LOG, "view_audit_log"),
+    ];
+
+    pub const fn empty() -> Self {
+        Self(0)
+    }
+
+    pub const fn all() -> Self {
+        Self((1 << Self::NAMES.len()) - 1)
+    }
+
+    pub const fn bits(self) -> i64 {
+        self.0
+    }
+
+    /// Builds a set from database bits, ignoring bits that don't belong to any permission
+    pub const fn from_bits_truncate(bits: i64) -> Self {
+        Self(bits & Self::all().0)
+    }
+
+    /// The effective permissions of a member holding `granted`.
+    /// Owners and administrators may do everything.
+    pub const fn resolve(owner: bool, granted: Self) -> Self {
+        if owner || granted.contains(Self::ADMINISTRATOR) {
+            Self::all()
+        } else {
+            granted
+        }
+    }
+
+    pub const fn contains(self, other: Self) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    pub const fn is_empty(self) -> bool {
+        self.0 == 0
+    }
+
+    pub const fn union(self, other: Self) -> Self {
+        Self(self.0 | other.0)
+    }
+
+    pub const fn intersection(self, other: Self) -> Self {
+        Self(self.0 & other.0)
+    }
+
+    pub const fn difference(self, other: Self) -> Self {
+        Self(self.0 & !other.0)
+    }
+
+    /// Takes away everything in `deny`, then adds everything in `allow`
+    pub const fn apply(self, allow: Self, deny: Self) -> Self {
+        self.difference(deny).union(allow)
+    }
+
+    fn from_name(name: &str) -> Option<Self> {
+        Self::NAMES
+            .iter()
+            .find(|(_, candidate)| *candidate == name)
+            .map(|(permission, _)| *permission)
+    }
+
+    fn names(self) -> impl Iterator<Item = &'static str> {
+        Self::NAMES
+            .into_iter()
+            .filter(move |(permission, _)| self.contains(*permission))
+            .map(|(_, name)| name)
+    }
+}
+
+impl BitOr for Permissions {
+    type Output = Self;
+
+    fn bitor(self, rhs: Self) -> Self {
+        self.union(rhs)
+    }
+}
+
+impl BitOrAssign for Permissions {
+    fn bitor_assign(&mut self, rhs: Self) {
+        *self = self.union(rhs);
+    }
+}
+
+impl Serialize for Permissions {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut seq = serializer.serialize_seq(None)?;
+        for name in self.names() {
+            seq.serialize_element(name)?;
+        }
+        seq.end()
+    }
+}
+
+impl<'de> Deserialize<'de> for Permissions {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct PermissionsVisitor;
+
+        impl<'de> de::Visitor<'de> for PermissionsVisitor {
+            type Value = Permissions;
+
+            fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+                formatter.write_str("a list of permission names")
+            }
+
+            fn visit_seq<A: de::SeqAccess<'de>>(self, mut seq: A) -> Result<Permissions, A::Error> {
+                let mut permissions = Permissions::empty();
+                while let Some(name) = seq.next_element::<String>()? {
+                    permissions |= Permissions::from_name(&name)
+                        .ok_or_else(|| de::Error::custom(format!("unknown permission `{name}`")))?;
+                }
+                Ok(permissions)
+            }
+        }
+
+        deserializer.deserialize_seq(PermissionsVisitor)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn owners_and_administrators_get_everything() {
+        assert_eq!(
+            Permissions::resolve(true, Permissions::empty()),
+            Permissions::all()
+        );
+        assert_eq!(
+            Permissions::resolve(false, Permissions::ADMINISTRATOR),
+            Permissions::all()
+        );
+        assert_eq!(
+            Permissions::resolve(false, Permissions::DEFAULT),
+            Permissions::DEFAULT
+        );
+    }
+
+    #[test]
+    fn apply_denies_before_allowing() {
+        let base = Permissions::READ_MESSAGES | Permissions::SEND_MESSAGES;
+        let allow = Permissions::SEND_MESSAGES | Permissions::ATTACH_FILES;
+        let deny = Permissions::READ_MESSAGES | Permissions::SEND_MESSAGES;
+        assert_eq!(base.apply(allow, deny), allow);
+    }
+
+    #[test]
+    fn from_bits_truncate_drops_unknown_bits() {
+        assert_eq!(Permissions::from_bits_truncate(-1), Permissions::all());
+        assert_eq!(
+            Permissions::from_bits_truncate(1 << 62),
+            Permissions::empty()
+        );
+    }
+
+    #[test]
+    fn serde_round_trips_through_names() {
+        let permissions = Permissions::READ_MESSAGES | Permissions::MANAGE_ROLES;
+        let json = serde_json::to_string(&permissions).unwrap();
+        assert_eq!(json, r#"["read_messages","manage_roles"]"#);
+        assert_eq!(
+            serde_json::from_str::<Permissions>(&json).unwrap(),
+            permissions
+        );
+
+        let all = serde_json::to_string(&Permissions::all()).unwrap();
+        assert_eq!(
+            serde_json::from_str::<Permissions>(&all).unwrap(),
+            Permissions::all()
+        );
+    }
+
+    #[test]
+    fn unknown_names_are_rejected() {
+        assert!(serde_json::from_str::<Permissions>(r#"["read_messages","fly"]"#).is_err());
+    }
+
+    #[test]
+    fn default_matches_the_migrated_everyone_role() {
+        let migration = include_str!("../../migrations/20220619164047_add_roles.up.sql");
+        let everyone = format!("'everyone', 0, {}, NOW()", Permissions::DEFAULT.bits());
+        assert!(migration.contains(&everyone));
+    }
+}
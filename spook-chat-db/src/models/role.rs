@@ -0,0 +1,213 @@
+use super::Permissions;
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use sqlx::{postgres::PgQueryResult, PgPool};
+use uuid::Uuid;
+
+/// The name of the role every member of a server implicitly holds
+pub const EVERYONE_ROLE_NAME: &str = "everyone";
+
+#[derive(Serialize)]
+pub struct Role {
+    pub role_id: Uuid,
+    pub server_id: Uuid,
+    pub name: String,
+    pub colour: Option<i32>,
+    /// Higher positions rank above lower ones, the everyone role is always at 0
+    pub position: i32,
+    pub permissions: Permissions,
+    pub created_at: DateTime<Utc>,
+}
+
+impl Role {
+    pub fn new(server_id: Uuid, name: &str, colour: Option<i32>, permissions: Permissions) -> Self {
+        Self {
+            role_id: Uuid::new_v4(),
+            server_id,
+            name: name.to_string(),
+            colour,
+            position: 1,
+            permissions,
+            created_at: Utc::now(),
+        }
+    }
+
+    /// The role every member of the server holds, it shares its id with the server
+    pub fn everyone(server_id: Uuid) -> Self {
+        Self {
+            role_id: server_id,
+            server_id,
+            name: EVERYONE_ROLE_NAME.to_string(),
+            colour: None,
+            position: 0,
+            permissions: Permissions::DEFAULT,
+            created_at: Utc::now(),
+        }
+    }
+
+    pub fn is_everyone(&self) -> bool {
+        self.role_id == self.server_id
+    }
+
+    /// Inserts the role at its position, moving every role at or above it one up
+    pub async fn save(&self, pool: &PgPool) -> sqlx::Result<()> {
+        let mut transaction = pool.begin().await?;
+
+        sqlx::query!(
+            "UPDATE roles SET position = position + 1 WHERE server_id = $1 AND position >= $2",
+            self.server_id,
+            self.position
+        )
+        .execute(&mut transaction)
+        .await?;
+
+        sqlx::query!(
+            "INSERT INTO roles (role_id, server_id, name, colour, position, permissions, created_at)
+            VALUES ($1, $2, $3, $4, $5, $6, $7)",
+            self.role_id,
+            self.server_id,
+            self.name,
+            self.colour,
+            self.position,
+            self.permissions.bits(),
+            self.created_at
+        )
+        .execute(&mut transaction)
+        .await?;
+
+        transaction.commit().await
+    }
+
+    /// Writes name, colour and permissions back to the database
+    pub async fn update(&self, pool: &PgPool) -> sqlx::Result<PgQueryResult> {
+        sqlx::query!(
+            "UPDATE roles SET (name, colour, permissions) = ($1, $2, $3) WHERE role_id = $4",
+            self.name,
+            self.colour,
+            self.permissions.bits(),
+            self.role_id
+        )
+        .execute(pool)
+        .await
+    }
+
+    /// Moves the role to a new position, shifting the roles in between to keep positions unique
+    pub async fn move_to(&mut self, pool: &PgPool, position: i32) -> sqlx::Result<()> {
+        let mut transaction = pool.begin().await?;
+
+        if position < self.position {
+            sqlx::query!(
+                "UPDATE roles SET position = position + 1
+                WHERE server_id = $1 AND position >= $2 AND position < $3",
+                self.server_id,
+                position,
+                self.position
+            )
+            .execute(&mut transaction)
+            .await?;
+        } else {
+            sqlx::query!(
+                "UPDATE roles SET position = position - 1
+                WHERE server_id = $1 AND position > $2 AND position <= $3",
+                self.server_id,
+                self.position,
+                position
+            )
+            .execute(&mut transaction)
+            .await?;
+        }
+
+        sqlx::query!(
+            "UPDATE roles SET position = $1 WHERE role_id = $2",
+            position,
+            self.role_id
+        )
+        .execute(&mut transaction)
+        .await?;
+
+        transaction.commit().await?;
+        self.position = position;
+        Ok(())
+    }
+
+    /// Deletes the role, taking it away from every member and closing the gap it leaves
+    pub async fn delete(&self, pool: &PgPool) -> sqlx::Result<()> {
+        let mut transaction = pool.begin().await?;
+
+        sqlx::query!("DELETE FROM roles WHERE role_id = $1", self.role_id)
+            .execute(&mut transaction)
+            .await?;
+
+        sqlx::query!(
+            "UPDATE roles SET position = position - 1 WHERE server_id = $1 AND position > $2",
+            self.server_id,
+            self.position
+        )
+        .execute(&mut transaction)
+        .await?;
+
+        transaction.commit().await
+    }
+
+    pub async fn filter_by_id(pool: &PgPool, role_id: Uuid) -> sqlx::Result<Option<Self>> {
+        let row = sqlx::query!("SELECT * FROM roles WHERE role_id = $1", role_id)
+            .fetch_optional(pool)
+            .await?;
+
+        Ok(row.map(|row| Role {
+            role_id: row.role_id,
+            server_id: row.server_id,
+            name: row.name,
+            colour: row.colour,
+            position: row.position,
+            permissions: Permissions::from_bits_truncate(row.permissions),
+            created_at: row.created_at,
+        }))
+    }
+
+    /// Lists the roles of a server from the highest to the lowest position
+    pub async fn filter_by_server_id(pool: &PgPool, server_id: Uuid) -> sqlx::Result<Vec<Self>> {
+        let rows = sqlx::query!(
+            "SELECT * FROM roles WHERE server_id = $1 ORDER BY position DESC",
+            server_id
+        )
+        .fetch_all(pool)
+        .await?;
+
+        Ok(rows
+            .into_iter()
+            .map(|row| Role {
+                role_id: row.role_id,
+                server_id: row.server_id,
+                name: row.name,
+                colour: row.colour,
+                position: row.position,
+                permissions: Permissions::from_bits_truncate(row.permissions),
+                created_at: row.created_at,
+            })
+            .collect())
+    }
+
+    pub async fn assign(&self, pool: &PgPool, user_id: Uuid) -> sqlx::Result<PgQueryResult> {
+        sqlx::query!(
+            "INSERT INTO members_roles (user_id, server_id, role_id)
+            VALUES ($1, $2, $3)
+            ON CONFLICT DO NOTHING",
+            user_id,
+            self.server_id,
+            self.role_id
+        )
+        .execute(pool)
+        .await
+    }
+
+    pub async fn unassign(&self, pool: &PgPool, user_id: Uuid) -> sqlx::Result<PgQueryResult> {
+        sqlx::query!(
+            "DELETE FROM members_roles WHERE user_id = $1 AND role_id = $2",
+            user_id,
+            self.role_id
+        )
+        .execute(pool)
+        .await
+    }
+}
@@ -1,30 +1,51 @@
 use super::channel::Channel;
-use super::{Invite, User};
+use super::{invite, Ban, Invite, Permissions, Role, User};
 use chrono::{DateTime, Utc};
+use serde::Serialize;
 use sqlx::postgres::{PgPool, PgQueryResult};
 use sqlx::FromRow;
 use uuid::Uuid;
 
-#[derive(FromRow)]
+#[derive(FromRow, Serialize)]
 pub struct Server {
     pub server_id: Uuid,
     pub name: String,
     pub created_at: DateTime<Utc>,
+    pub icon: Option<String>,
+    pub vanity_code: Option<String>,
+    /// Channel that receives notices such as members joining
+    pub system_channel_id: Option<Uuid>,
 }
 
-#[derive(FromRow)]
-pub struct Permissions {
+/// The rank of a server's owner, above every role
+pub const OWNER_RANK: i32 = i32::MAX;
+
+#[derive(Serialize)]
+pub struct Member {
+    pub user_id: Uuid,
+    pub username: String,
+    pub bot: bool,
+    pub nickname: Option<String>,
+    /// The nickname if the member set one, their username otherwise
+    pub display_name: String,
+    pub avatar: Option<String>,
+    pub joined_at: DateTime<Utc>,
+    pub timed_out_until: Option<DateTime<Utc>>,
     pub owner: bool,
-    pub manage_channels: bool,
-    pub manage_users: bool,
-    pub manage_invites: bool,
-    pub banned: bool,
+    pub roles: Vec<Uuid>,
+    pub permissions: Permissions,
+}
+
+/// How a member appears on a server
+#[derive(Serialize)]
+pub struct Profile {
+    pub display_name: String,
+    pub avatar: Option<String>,
 }
 
 pub struct ChangePermissions {
-    pub manage_channels: Option<bool>,
-    pub manage_users: Option<bool>,
-    pub manage_invites: Option<bool>,
+    pub grant: Permissions,
+    pub revoke: Permissions,
 }
 
 impl Server {
@@ -33,10 +54,17 @@ impl Server {
             server_id: Uuid::new_v4(),
             name: name.to_string(),
             created_at: Utc::now(),
+            icon: None,
+            vanity_code: None,
+            system_channel_id: None,
         }
     }
 
+    /// Saves the server together with its everyone role
     pub async fn save(&self, pool: &PgPool) -> sqlx::Result<()> {
+        let everyone = Role::everyone(self.server_id);
+        let mut transaction = pool.begin().await?;
+
         sqlx::query!(
             "INSERT INTO servers (server_id, name, created_at)
             VALUES ($1, $2, $3)",
@@ -44,9 +72,87 @@ impl Server {
             self.name,
             self.created_at
         )
+        .execute(&mut transaction)
+        .await?;
+
+        sqlx::query!(
+            "INSERT INTO roles (role_id, server_id, name, colour, position, permissions, created_at)
+            VALUES ($1, $2, $3, $4, $5, $6, $7)",
+            everyone.role_id,
+            everyone.server_id,
+            everyone.name,
+            everyone.colour,
+            everyone.position,
+            everyone.permissions.bits(),
+            everyone.created_at
+        )
+        .execute(&mut transaction)
+        .await?;
+
+        transaction.commit().await
+    }
+
+    /// Sets the URL of the server's icon, `None` removes it
+    pub async fn set_icon(&mut self, pool: &PgPool, icon: Option<&str>) -> sqlx::Result<()> {
+        sqlx::query!(
+            "UPDATE servers SET icon = $1 WHERE server_id = $2",
+            icon,
+            self.server_id
+        )
+        .execute(pool)
+        .await?;
+
+        self.icon = icon.map(str::to_string);
+        Ok(())
+    }
+
+    /// Sets the server's custom invite code, `None` removes it.
+    /// Returns `false` without changing anything if the code is already in use.
+    pub async fn set_vanity_code(
+        &mut self,
+        pool: &PgPool,
+        code: Option<&str>,
+    ) -> sqlx::Result<bool> {
+        let mut transaction = pool.begin().await?;
+        sqlx::query!("SELECT pg_advisory_xact_lock($1)", invite::CODE_LOCK)
+            .execute(&mut transaction)
+            .await?;
+        let result = sqlx::query!(
+            "UPDATE servers SET vanity_code = $1::VARCHAR
+            WHERE server_id = $2 AND NOT EXISTS (SELECT 1 FROM invites WHERE code = $1::VARCHAR)",
+            code,
+            self.server_id
+        )
+        .execute(&mut transaction)
+        .await;
+
+        match result {
+            Ok(result) if result.rows_affected() == 1 => {
+                transaction.commit().await?;
+                self.vanity_code = code.map(str::to_string);
+                Ok(true)
+            }
+            Ok(_) => Ok(false),
+            Err(sqlx::Error::Database(e)) if e.code().as_deref() == Some("23505") => Ok(false),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Sets the channel notices are posted in, `None` turns them off
+    pub async fn set_system_channel(
+        &mut self,
+        pool: &PgPool,
+        channel_id: Option<Uuid>,
+    ) -> sqlx::Result<()> {
+        sqlx::query!(
+            "UPDATE servers SET system_channel_id = $1 WHERE server_id = $2",
+            channel_id,
+            self.server_id
+        )
         .execute(pool)
         .await?;
 
+        self.system_channel_id = channel_id;
         Ok(())
     }
 
@@ -61,86 +167,213 @@ impl Server {
         server.is_ok()
     }
 
-    pub async fn add_channel(&self, pool: &PgPool, channel: &Channel) -> sqlx::Result<()> {
+    /// Inserts the channel at the bottom of the server's channel list
+    pub async fn add_channel(&self, pool: &PgPool, channel: &mut Channel) -> sqlx::Result<()> {
         if !self.is_in_database(pool).await {
             return Err(sqlx::Error::RowNotFound);
         }
 
-        sqlx::query!(
-            "INSERT INTO channels (server_id, channel_id, name, created_at)
-            VALUES ($1, $2, $3, $4)",
+        channel.position = sqlx::query_scalar!(
+            "INSERT INTO channels (server_id, channel_id, name, created_at, topic, position)
+            VALUES ($1, $2, $3, $4, $5, (
+                SELECT COALESCE(MAX(position) + 1, 0) FROM channels WHERE server_id = $1
+            ))
+            RETURNING position",
             self.server_id,
             channel.channel_id,
             channel.name,
-            channel.created_at
+            channel.created_at,
+            channel.topic
         )
-        .execute(pool)
+        .fetch_one(pool)
         .await?;
 
         Ok(())
     }
 
+    /// Sets the position of every channel to its index in `channel_ids`.
+    /// Fails without changing anything unless `channel_ids` contains exactly
+    /// the channels of this server.
+    pub async fn reorder_channels(&self, pool: &PgPool, channel_ids: &[Uuid]) -> sqlx::Result<()> {
+        let mut transaction = pool.begin().await?;
+
+        let mut current = sqlx::query_scalar!(
+            "SELECT channel_id FROM channels WHERE server_id = $1 FOR UPDATE",
+            self.server_id
+        )
+        .fetch_all(&mut transaction)
+        .await?;
+        let mut requested = channel_ids.to_vec();
+        current.sort();
+        requested.sort();
+        if current != requested {
+            return Err(sqlx::Error::RowNotFound);
+        }
+
+        for (position, channel_id) in channel_ids.iter().enumerate() {
+            sqlx::query!(
+                "UPDATE channels SET position = $1 WHERE channel_id = $2",
+                position as i32,
+                channel_id
+            )
+            .execute(&mut transaction)
+            .await?;
+        }
+
+        transaction.commit().await
+    }
+
+    /// Creates and saves an invite, `None` if no free code could be drawn for it
     pub async fn create_invite(
         &self,
         pool: &PgPool,
+        created_by: Uuid,
         expires: Option<DateTime<Utc>>,
-    ) -> sqlx::Result<Invite> {
-        let invite = Invite::new(self, expires);
-        invite.save(pool).await?;
+        max_uses: Option<i32>,
+    ) -> sqlx::Result<Option<Invite>> {
+        let mut invite = Invite::new(self, created_by, expires, max_uses);
+        Ok(invite.save(pool).await?.then_some(invite))
+    }
+
+    /// Whether the user is the owner, what they are allowed to do and their rank,
+    /// or `None` if they are not a member
+    async fn access(
+        &self,
+        pool: &PgPool,
+        user_id: Uuid,
+    ) -> sqlx::Result<Option<(bool, Permissions, i32)>> {
+        let row = sqlx::query!(
+            r#"SELECT B.owner, B.permissions,
+                COALESCE(BIT_OR(C.permissions), 0) AS "role_permissions!",
+                COALESCE(MAX(C.position), 0) AS "rank!"
+            FROM users_servers B LEFT JOIN roles C ON C.server_id = B.server_id AND (
+                C.role_id = B.server_id OR C.role_id IN (
+                    SELECT D.role_id FROM members_roles D
+                    WHERE D.user_id = B.user_id AND D.server_id = B.server_id
+                )
+            )
+            WHERE B.server_id = $1 AND B.user_id = $2
+            GROUP BY B.user_id, B.server_id"#,
+            self.server_id,
+            user_id
+        )
+        .fetch_optional(pool)
+        .await?;
 
-        Ok(invite)
+        Ok(row.map(|row| {
+            let granted = Permissions::from_bits_truncate(row.permissions | row.role_permissions);
+            let rank = if row.owner { OWNER_RANK } else { row.rank };
+            (row.owner, Permissions::resolve(row.owner, granted), rank)
+        }))
     }
 
+    /// The user's effective permissions on this server, combining the everyone role,
+    /// their other roles and what was granted to them directly.
+    /// `None` if they are not a member.
     pub async fn get_permissions(
         &self,
         pool: &PgPool,
         user: &User,
     ) -> sqlx::Result<Option<Permissions>> {
-        sqlx::query_as!(
-            Permissions, 
-            "SELECT owner, manage_channels, manage_users, manage_invites, banned 
-            FROM users_servers WHERE server_id = $1 AND user_id = $2", 
-            self.server_id, 
-            user.user_id
-        )
-        .fetch_optional(pool)
-        .await
+        Ok(self
+            .access(pool, user.user_id)
+            .await?
+            .map(|(_, permissions, _)| permissions))
+    }
+
+    /// The position of the user's highest role, `OWNER_RANK` for the owner
+    /// and `None` if they are not a member
+    pub async fn rank(&self, pool: &PgPool, user_id: Uuid) -> sqlx::Result<Option<i32>> {
+        Ok(self.access(pool, user_id).await?.map(|(_, _, rank)| rank))
+    }
+
+    pub async fn is_owner(&self, pool: &PgPool, user_id: Uuid) -> sqlx::Result<bool> {
+        Ok(self
+            .access(pool, user_id)
+            .await?
+            .is_some_and(|(owner, _, _)| owner))
     }
 
+    /// Whether `actor` ranks strictly above `target`, which is required to moderate them.
+    /// Users that are not part of the server rank below every member.
+    pub async fn outranks(&self, pool: &PgPool, actor: &User, target: Uuid) -> sqlx::Result<bool> {
+        let actor_rank = match self.rank(pool, actor.user_id).await? {
+            Some(rank) => rank,
+            None => return Ok(false),
+        };
+        let target_rank = self.rank(pool, target).await?.unwrap_or(-1);
+
+        Ok(actor_rank > target_rank)
+    }
+
+    /// Grants and revokes permissions held by the member directly, independent of their roles.
+    /// Returns the member's direct permissions after the change.
     pub async fn change_permissions(
         &self,
         pool: &PgPool,
         user: &User,
         changes: ChangePermissions,
-    ) -> sqlx::Result<PgQueryResult> {
-        let current_permissions = self
-            .get_permissions(pool, user)
-            .await?
-            .ok_or(sqlx::Error::RowNotFound)?;
+    ) -> sqlx::Result<Permissions> {
+        let mut transaction = pool.begin().await?;
+
+        let current_permissions = sqlx::query_scalar!(
+            "SELECT permissions FROM users_servers WHERE server_id = $1 AND user_id = $2
+            FOR UPDATE",
+            self.server_id,
+            user.user_id
+        )
+        .fetch_optional(&mut transaction)
+        .await?
+        .ok_or(sqlx::Error::RowNotFound)?;
+        let updated = Permissions::from_bits_truncate(current_permissions)
+            .union(changes.grant)
+            .difference(changes.revoke);
         sqlx::query!(
-            "UPDATE users_servers SET (manage_channels, manage_users, manage_invites) = ($1, $2, $3)", 
-            changes.manage_channels.unwrap_or(current_permissions.manage_channels), 
-            changes.manage_users.unwrap_or(current_permissions.manage_users), 
-            changes.manage_invites.unwrap_or(current_permissions.manage_invites)
+            "UPDATE users_servers SET permissions = $1 WHERE server_id = $2 AND user_id = $3",
+            updated.bits(),
+            self.server_id,
+            user.user_id
         )
-        .execute(pool)
-        .await
+        .execute(&mut transaction)
+        .await?;
+
+        transaction.commit().await?;
+        Ok(updated)
     }
 
-    pub async fn ban_user(&self, pool: &PgPool, user: &User) -> sqlx::Result<()> {
+    /// Removes the user from the server, if they are a member, and keeps them from rejoining
+    /// until the ban is lifted or expires
+    pub async fn ban_user(&self, pool: &PgPool, ban: &Ban) -> sqlx::Result<()> {
         if !self.is_in_database(pool).await {
             return Err(sqlx::Error::RowNotFound);
         }
 
+        let mut transaction = pool.begin().await?;
+
         sqlx::query!(
-            "UPDATE users_servers SET banned = true WHERE server_id = $1 AND user_id = $2",
+            "DELETE FROM users_servers WHERE server_id = $1 AND user_id = $2",
             self.server_id,
-            user.user_id
+            ban.user_id
         )
-        .execute(pool)
+        .execute(&mut transaction)
         .await?;
 
-        Ok(())
+        sqlx::query!(
+            "INSERT INTO bans (server_id, user_id, reason, issued_by, created_at, expires_at)
+            VALUES ($1, $2, $3, $4, $5, $6)
+            ON CONFLICT (server_id, user_id) DO UPDATE
+            SET reason = $3, issued_by = $4, created_at = $5, expires_at = $6",
+            self.server_id,
+            ban.user_id,
+            ban.reason,
+            ban.issued_by,
+            ban.created_at,
+            ban.expires_at
+        )
+        .execute(&mut transaction)
+        .await?;
+
+        transaction.commit().await
     }
 
     pub async fn unban_user(&self, pool: &PgPool, user: &User) -> sqlx::Result<()> {
@@ -149,7 +382,7 @@ impl Server {
         }
 
         sqlx::query!(
-            "UPDATE users_servers SET banned = false WHERE server_id = $1 AND user_id = $2",
+            "DELETE FROM bans WHERE server_id = $1 AND user_id = $2",
             self.server_id,
             user.user_id
         )
@@ -159,6 +392,197 @@ impl Server {
         Ok(())
     }
 
+    /// Removes the user's membership without keeping them from rejoining
+    pub async fn remove_user(&self, pool: &PgPool, user_id: Uuid) -> sqlx::Result<PgQueryResult> {
+        sqlx::query!(
+            "DELETE FROM users_servers WHERE server_id = $1 AND user_id = $2",
+            self.server_id,
+            user_id
+        )
+        .execute(pool)
+        .await
+    }
+
+    /// Hands the server from its current owner to another member
+    pub async fn transfer_ownership(
+        &self,
+        pool: &PgPool,
+        owner_id: Uuid,
+        new_owner_id: Uuid,
+    ) -> sqlx::Result<()> {
+        let mut transaction = pool.begin().await?;
+
+        let previous = sqlx::query!(
+            "UPDATE users_servers SET owner = FALSE
+            WHERE server_id = $1 AND user_id = $2 AND owner",
+            self.server_id,
+            owner_id
+        )
+        .execute(&mut transaction)
+        .await?;
+        let next = sqlx::query!(
+            "UPDATE users_servers SET owner = TRUE WHERE server_id = $1 AND user_id = $2",
+            self.server_id,
+            new_owner_id
+        )
+        .execute(&mut transaction)
+        .await?;
+
+        if previous.rows_affected() != 1 || next.rows_affected() != 1 {
+            return Err(sqlx::Error::RowNotFound);
+        }
+
+        transaction.commit().await
+    }
+
+    /// Deletes the server with its channels, messages, invites, roles, bans and memberships.
+    /// Returns the ids of the deleted channels.
+    pub async fn delete(&self, pool: &PgPool) -> sqlx::Result<Vec<Uuid>> {
+        let mut transaction = pool.begin().await?;
+
+        let channel_ids = sqlx::query_scalar!(
+            "DELETE FROM channels WHERE server_id = $1 RETURNING channel_id",
+            self.server_id
+        )
+        .fetch_all(&mut transaction)
+        .await?;
+        sqlx::query!("DELETE FROM servers WHERE server_id = $1", self.server_id)
+            .execute(&mut transaction)
+            .await?;
+
+        transaction.commit().await?;
+        Ok(channel_ids)
+    }
+
+    /// The member's nickname and avatar on this server, `None` if they are not a member
+    pub async fn profile(&self, pool: &PgPool, user: &User) -> sqlx::Result<Option<Profile>> {
+        let row = sqlx::query!(
+            "SELECT nickname, avatar FROM users_servers WHERE server_id = $1 AND user_id = $2",
+            self.server_id,
+            user.user_id
+        )
+        .fetch_optional(pool)
+        .await?;
+
+        Ok(row.map(|row| Profile {
+            display_name: row.nickname.unwrap_or_else(|| user.username.clone()),
+            avatar: row.avatar,
+        }))
+    }
+
+    /// Sets the member's nickname on this server, `None` falls back to their username
+    pub async fn set_nickname(
+        &self,
+        pool: &PgPool,
+        user_id: Uuid,
+        nickname: Option<&str>,
+    ) -> sqlx::Result<PgQueryResult> {
+        sqlx::query!(
+            "UPDATE users_servers SET nickname = $1 WHERE server_id = $2 AND user_id = $3",
+            nickname,
+            self.server_id,
+            user_id
+        )
+        .execute(pool)
+        .await
+    }
+
+    pub async fn set_avatar(
+        &self,
+        pool: &PgPool,
+        user_id: Uuid,
+        avatar: Option<&str>,
+    ) -> sqlx::Result<PgQueryResult> {
+        sqlx::query!(
+            "UPDATE users_servers SET avatar = $1 WHERE server_id = $2 AND user_id = $3",
+            avatar,
+            self.server_id,
+            user_id
+        )
+        .execute(pool)
+        .await
+    }
+
+    /// Makes a member read-only until the given time, `None` lifts an active timeout
+    pub async fn timeout_user(
+        &self,
+        pool: &PgPool,
+        user_id: Uuid,
+        until: Option<DateTime<Utc>>,
+    ) -> sqlx::Result<PgQueryResult> {
+        sqlx::query!(
+            "UPDATE users_servers SET timed_out_until = $1 WHERE server_id = $2 AND user_id = $3",
+            until,
+            self.server_id,
+            user_id
+        )
+        .execute(pool)
+        .await
+    }
+
+    /// The ids of every member of this server, in no particular order
+    pub async fn member_ids(&self, pool: &PgPool) -> sqlx::Result<Vec<Uuid>> {
+        sqlx::query_scalar!(
+            "SELECT user_id FROM users_servers WHERE server_id = $1",
+            self.server_id
+        )
+        .fetch_all(pool)
+        .await
+    }
+
+    /// Lists the members of this server in the order they joined
+    pub async fn members(
+        &self,
+        pool: &PgPool,
+        limit: i64,
+        offset: i64,
+    ) -> sqlx::Result<Vec<Member>> {
+        let rows = sqlx::query!(
+            r#"SELECT A.user_id, A.username, A.bot, B.nickname, B.avatar, B.joined_at, B.timed_out_until,
+                B.owner, B.permissions,
+                ARRAY(
+                    SELECT D.role_id FROM members_roles D
+                    WHERE D.user_id = B.user_id AND D.server_id = B.server_id
+                ) AS "roles!",
+                (
+                    SELECT COALESCE(BIT_OR(C.permissions), 0) FROM roles C
+                    WHERE C.server_id = B.server_id AND (C.role_id = B.server_id OR C.role_id IN (
+                        SELECT D.role_id FROM members_roles D
+                        WHERE D.user_id = B.user_id AND D.server_id = B.server_id
+                    ))
+                ) AS "role_permissions!"
+            FROM users A INNER JOIN users_servers B ON A.user_id = B.user_id
+            WHERE B.server_id = $1
+            ORDER BY B.joined_at, A.user_id
+            LIMIT $2 OFFSET $3"#,
+            self.server_id,
+            limit,
+            offset
+        )
+        .fetch_all(pool)
+        .await?;
+
+        Ok(rows
+            .into_iter()
+            .map(|row| Member {
+                user_id: row.user_id,
+                display_name: row.nickname.clone().unwrap_or_else(|| row.username.clone()),
+                username: row.username,
+                bot: row.bot,
+                nickname: row.nickname,
+                avatar: row.avatar,
+                joined_at: row.joined_at,
+                timed_out_until: row.timed_out_until,
+                owner: row.owner,
+                roles: row.roles,
+                permissions: Permissions::resolve(
+                    row.owner,
+                    Permissions::from_bits_truncate(row.permissions | row.role_permissions),
+                ),
+            })
+            .collect())
+    }
+
     pub async fn filter_by_id(pool: &PgPool, id: Uuid) -> sqlx::Result<Server> {
         let server = sqlx::query_as!(Server, "SELECT * FROM servers WHERE server_id = $1", id)
             .fetch_one(pool)
@@ -167,6 +591,16 @@ impl Server {
         server
     }
 
+    pub async fn filter_by_vanity_code(pool: &PgPool, code: &str) -> sqlx::Result<Option<Self>> {
+        sqlx::query_as!(
+            Server,
+            "SELECT * FROM servers WHERE vanity_code = $1",
+            code.to_lowercase()
+        )
+        .fetch_optional(pool)
+        .await
+    }
+
     pub async fn filter_by_name(pool: &PgPool, name: &str) -> sqlx::Result<Vec<Self>> {
         let server = sqlx::query_as!(Server, "SELECT * FROM servers WHERE name = $1", name)
             .fetch_all(pool)
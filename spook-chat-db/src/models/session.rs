@@ -1,35 +1,127 @@
 use super::User;
-use chrono::{DateTime, Utc};
+use chrono::{DateTime, Duration, Utc};
+use serde::Serialize;
 use sqlx::postgres::PgPool;
 use sqlx::FromRow;
 use uuid::Uuid;
 
-#[derive(FromRow)]
+/// How long a session may last in total and without being used
+#[derive(Clone, Copy)]
+pub struct SessionLifetime {
+    pub absolute: Duration,
+    pub idle: Duration,
+}
+
+/// The longest user agent that is stored, anything after it is cut off
+const MAX_USER_AGENT_LENGTH: usize = 512;
+
+#[derive(FromRow, Serialize)]
 pub struct Session {
     pub session_id: Uuid,
     pub user_id: Uuid,
     pub created_at: DateTime<Utc>,
+    /// The session ends at this point no matter how active it is
+    pub expires_at: DateTime<Utc>,
+    /// The session ends at this point unless it gets used and renewed before
+    pub idle_expires_at: DateTime<Utc>,
+    /// Whether the session was created with "remember me"
+    pub remember: bool,
+    pub user_agent: Option<String>,
+    pub ip_address: Option<String>,
+    pub last_seen_at: DateTime<Utc>,
 }
 
 impl Session {
-    pub fn new(user: &User) -> Self {
+    pub fn new(
+        user: &User,
+        lifetime: SessionLifetime,
+        remember: bool,
+        user_agent: Option<&str>,
+        ip_address: Option<String>,
+    ) -> Self {
+        let created_at = Utc::now();
+        let expires_at = created_at + lifetime.absolute;
         Self {
             session_id: Uuid::new_v4(),
             user_id: user.user_id,
-            created_at: Utc::now(),
+            created_at,
+            expires_at,
+            idle_expires_at: (created_at + lifetime.idle).min(expires_at),
+            remember,
+            user_agent: user_agent
+                .map(|user_agent| user_agent.chars().take(MAX_USER_AGENT_LENGTH).collect()),
+            ip_address,
+            last_seen_at: created_at,
         }
     }
 
+    pub async fn save(&self, pool: &PgPool) -> sqlx::Result<()> {
+        sqlx::query!(
+            "INSERT INTO sessions (session_id, user_id, created_at, expires_at, idle_expires_at, remember, user_agent, ip_address, last_seen_at)
+            VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9)",
+            self.session_id,
+            self.user_id,
+            self.created_at,
+            self.expires_at,
+            self.idle_expires_at,
+            self.remember,
+            self.user_agent,
+            self.ip_address,
+            self.last_seen_at
+        )
+        .execute(pool)
+        .await?;
+        Ok(())
+    }
+
+    /// Looks up a session that has not expired yet
     pub async fn filter_by_id(pool: &PgPool, session_id: Uuid) -> sqlx::Result<Option<Self>> {
         sqlx::query_as!(
             Session,
-            "SELECT * FROM sessions WHERE session_id = $1",
+            "SELECT * FROM sessions
+            WHERE session_id = $1 AND expires_at > NOW() AND idle_expires_at > NOW()",
             session_id
         )
         .fetch_optional(pool)
         .await
     }
 
+    /// Lists the sessions of the user that have not expired, most recently used first
+    pub async fn filter_by_user_id(pool: &PgPool, user_id: Uuid) -> sqlx::Result<Vec<Self>> {
+        sqlx::query_as!(
+            Session,
+            "SELECT * FROM sessions
+            WHERE user_id = $1 AND expires_at > NOW() AND idle_expires_at > NOW()
+            ORDER BY last_seen_at DESC",
+            user_id
+        )
+        .fetch_all(pool)
+        .await
+    }
+
+    /// Marks the session as seen and pushes back its idle expiry, but never past `expires_at`.
+    /// Sessions renewed within the last minute are left alone to spare the database.
+    pub async fn renew(&mut self, pool: &PgPool, idle: Duration) -> sqlx::Result<()> {
+        let now = Utc::now();
+        let idle_expires_at = (now + idle).min(self.expires_at);
+        if idle_expires_at - self.idle_expires_at < Duration::minutes(1) {
+            return Ok(());
+        }
+
+        sqlx::query!(
+            "UPDATE sessions SET idle_expires_at = $1, last_seen_at = $2 WHERE session_id = $3",
+            idle_expires_at,
+            now,
+            self.session_id
+        )
+        .execute(pool)
+        .await?;
+
+        self.idle_expires_at = idle_expires_at;
+        self.last_seen_at = now;
+        Ok(())
+    }
+
     pub async fn delete(&self, pool: &PgPool) -> sqlx::Result<()> {
         sqlx::query!(
             "DELETE FROM sessions WHERE session_id = $1",
@@ -39,4 +131,39 @@ impl Session {
         .await?;
         Ok(())
     }
+
+    /// Deletes one of the user's sessions, returns `false` if they have no such session
+    pub async fn revoke(pool: &PgPool, user_id: Uuid, session_id: Uuid) -> sqlx::Result<bool> {
+        let result = sqlx::query!(
+            "DELETE FROM sessions WHERE session_id = $1 AND user_id = $2",
+            session_id,
+            user_id
+        )
+        .execute(pool)
+        .await?;
+
+        Ok(result.rows_affected() == 1)
+    }
+
+    /// Deletes every session of the user except this one and returns their ids
+    pub async fn revoke_others(&self, pool: &PgPool) -> sqlx::Result<Vec<Uuid>> {
+        sqlx::query_scalar!(
+            "DELETE FROM sessions WHERE user_id = $1 AND session_id <> $2 RETURNING session_id",
+            self.user_id,
+            self.session_id
+        )
+        .fetch_all(pool)
+        .await
+    }
+
+    /// Removes every session that has expired and returns how many were deleted
+    pub async fn purge_expired(pool: &PgPool) -> sqlx::Result<u64> {
+        let result = sqlx::query!(
+            "DELETE FROM sessions WHERE expires_at <= NOW() OR idle_expires_at <= NOW()"
+        )
+        .execute(pool)
+        .await?;
+
+        Ok(result.rows_affected())
+    }
 }
@@ -0,0 +1,253 @@
+use super::User;
+use crate::tokens;
+use base32::Alphabet;
+use chrono::{DateTime, Utc};
+use hmac::{Hmac, Mac};
+use sha1::Sha1;
+use sqlx::PgPool;
+use uuid::Uuid;
+
+const SECRET_BYTES: usize = 20;
+const STEP_SECONDS: i64 = 30;
+const DIGITS: u32 = 6;
+/// Codes from one step before or after are accepted too, to allow for clock drift
+const ALLOWED_SKEW: i64 = 1;
+pub const RECOVERY_CODE_COUNT: usize = 10;
+const RECOVERY_CODE_ALPHABET: &[u8] = b"0123456789abcdefghijklmnopqrstuvwxyz";
+const RECOVERY_CODE_LENGTH: usize = 10;
+
+const SECRET_ALPHABET: Alphabet = Alphabet::RFC4648 { padding: false };
+
+/// The TOTP (RFC 6238) secret of a user. It is only in effect once `enabled_at` is set,
+/// which happens after the user proved their authenticator app works by entering a code.
+pub struct TwoFactor {
+    pub user_id: Uuid,
+    /// Base32 encoded, the way authenticator apps expect it
+    pub secret: String,
+    pub enabled_at: Option<DateTime<Utc>>,
+    /// The last time step a code was accepted for, so a code can't be used twice
+    pub last_step: Option<i64>,
+}
+
+/// The code for a single time step, HOTP (RFC 4226) with the step as counter
+fn code_at(secret: &[u8], step: i64) -> u32 {
+    let mut mac = Hmac::<Sha1>::new_from_slice(secret).expect("HMAC takes keys of any size");
+    mac.update(&step.to_be_bytes());
+    let hash = mac.finalize().into_bytes();
+
+    let offset = (hash[hash.len() - 1] & 0x0F) as usize;
+    let binary = u32::from_be_bytes([
+        hash[offset] & 0x7F,
+        hash[offset + 1],
+        hash[offset + 2],
+        hash[offset + 3],
+    ]);
+    binary % 10u32.pow(DIGITS)
+}
+
+/// Strips the separators users tend to type along with codes
+fn normalize(code: &str) -> String {
+    code.chars()
+        .filter(|c| !c.is_whitespace() && *c != '-')
+        .collect::<String>()
+        .to_lowercase()
+}
+
+impl TwoFactor {
+    /// A fresh, not yet enabled secret for the user
+    pub fn new(user: &User) -> Self {
+        Self {
+            user_id: user.user_id,
+            secret: base32::encode(SECRET_ALPHABET, &tokens::bytes(SECRET_BYTES)),
+            enabled_at: None,
+            last_step: None,
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.enabled_at.is_some()
+    }
+
+    /// Stores the secret, replacing an earlier one that was never enabled
+    pub async fn save(&self, pool: &PgPool) -> sqlx::Result<()> {
+        sqlx::query!(
+            "INSERT INTO two_factor (user_id, secret, enabled_at, last_step)
+            VALUES ($1, $2, $3, $4)
+            ON CONFLICT (user_id) DO UPDATE SET secret = $2, enabled_at = $3, last_step = $4
+            WHERE two_factor.enabled_at IS NULL",
+            self.user_id,
+            self.secret,
+            self.enabled_at,
+            self.last_step
+        )
+        .execute(pool)
+        .await?;
+
+        Ok(())
+    }
+
+    /// The time step a TOTP code is valid for, if it is valid right now
+    fn matching_step(&self, code: &str) -> Option<i64> {
+        let secret = base32::decode(SECRET_ALPHABET, &self.secret)?;
+        let code: u32 = normalize(code).parse().ok()?;
+        let now = Utc::now().timestamp() / STEP_SECONDS;
+
+        (now - ALLOWED_SKEW..=now + ALLOWED_SKEW).find(|step| code_at(&secret, *step) == code)
+    }
+
+    /// Checks a TOTP code and marks its time step as used
+    pub async fn verify(&mut self, pool: &PgPool, code: &str) -> sqlx::Result<bool> {
+        let step = match self.matching_step(code) {
+            Some(step) => step,
+            None => return Ok(false),
+        };
+
+        let result = sqlx::query!(
+            "UPDATE two_factor SET last_step = $1
+            WHERE user_id = $2 AND (last_step IS NULL OR last_step < $1)",
+            step,
+            self.user_id
+        )
+        .execute(pool)
+        .await?;
+        if result.rows_affected() == 0 {
+            return Ok(false);
+        }
+
+        self.last_step = Some(step);
+        Ok(true)
+    }
+
+    /// Checks a TOTP code or, failing that, uses up a recovery code
+    pub async fn verify_any(&mut self, pool: &PgPool, code: &str) -> sqlx::Result<bool> {
+        if self.verify(pool, code).await? {
+            return Ok(true);
+        }
+        self.use_recovery_code(pool, code).await
+    }
+
+    /// Turns on two-factor authentication and replaces the recovery codes.
+    /// Returns the new recovery codes, this is the only time they are readable.
+    pub async fn enable(&mut self, pool: &PgPool) -> sqlx::Result<Vec<String>> {
+        let enabled_at = Utc::now();
+        let mut transaction = pool.begin().await?;
+
+        sqlx::query!(
+            "UPDATE two_factor SET enabled_at = $1 WHERE user_id = $2",
+            enabled_at,
+            self.user_id
+        )
+        .execute(&mut transaction)
+        .await?;
+
+        sqlx::query!(
+            "DELETE FROM recovery_codes WHERE user_id = $1",
+            self.user_id
+        )
+        .execute(&mut transaction)
+        .await?;
+
+        let mut codes = Vec::with_capacity(RECOVERY_CODE_COUNT);
+        for _ in 0..RECOVERY_CODE_COUNT {
+            // The codes are random enough that a plain hash, unlike for passwords,
+            // can't be brute forced, and lets them be looked up directly
+            let code = tokens::random(RECOVERY_CODE_ALPHABET, RECOVERY_CODE_LENGTH);
+            sqlx::query!(
+                "INSERT INTO recovery_codes (code_id, user_id, code_hash) VALUES ($1, $2, $3)",
+                Uuid::new_v4(),
+                self.user_id,
+                tokens::hash(&code)
+            )
+            .execute(&mut transaction)
+            .await?;
+
+            let (first, second) = code.split_at(RECOVERY_CODE_LENGTH / 2);
+            codes.push(format!("{first}-{second}"));
+        }
+
+        transaction.commit().await?;
+        self.enabled_at = Some(enabled_at);
+        Ok(codes)
+    }
+
+    /// Uses up one of the user's recovery codes if `code` is among them
+    async fn use_recovery_code(&self, pool: &PgPool, code: &str) -> sqlx::Result<bool> {
+        let result = sqlx::query!(
+            "UPDATE recovery_codes SET used_at = NOW()
+            WHERE user_id = $1 AND code_hash = $2 AND used_at IS NULL",
+            self.user_id,
+            tokens::hash(&normalize(code))
+        )
+        .execute(pool)
+        .await?;
+
+        Ok(result.rows_affected() == 1)
+    }
+
+    /// How many recovery codes the user has left
+    pub async fn remaining_recovery_codes(&self, pool: &PgPool) -> sqlx::Result<i64> {
+        let count = sqlx::query_scalar!(
+            r#"SELECT COUNT(*) AS "count!" FROM recovery_codes WHERE user_id = $1 AND used_at IS NULL"#,
+            self.user_id
+        )
+        .fetch_one(pool)
+        .await?;
+
+        Ok(count)
+    }
+
+    /// Turns off two-factor authentication and drops the recovery codes
+    pub async fn delete(&self, pool: &PgPool) -> sqlx::Result<()> {
+        let mut transaction = pool.begin().await?;
+
+        sqlx::query!("DELETE FROM two_factor WHERE user_id = $1", self.user_id)
+            .execute(&mut transaction)
+            .await?;
+        sqlx::query!(
+            "DELETE FROM recovery_codes WHERE user_id = $1",
+            self.user_id
+        )
+        .execute(&mut transaction)
+        .await?;
+
+        transaction.commit().await
+    }
+
+    pub async fn filter_by_user_id(pool: &PgPool, user_id: Uuid) -> sqlx::Result<Option<Self>> {
+        sqlx::query_as!(
+            TwoFactor,
+            "SELECT * FROM two_factor WHERE user_id = $1",
+            user_id
+        )
+        .fetch_optional(pool)
+        .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The SHA-1 test vectors from RFC 6238, cut down to six digits
+    #[test]
+    fn code_at_matches_rfc_6238() {
+        let secret = b"12345678901234567890";
+        let vectors = [
+            (59, 287082),
+            (1111111109, 81804),
+            (1111111111, 50471),
+            (1234567890, 5924),
+            (2000000000, 279037),
+            (20000000000, 353130),
+        ];
+        for (time, code) in vectors {
+            assert_eq!(code_at(secret, time / STEP_SECONDS), code, "at {time}");
+        }
+    }
+
+    #[test]
+    fn normalize_strips_separators() {
+        assert_eq!(normalize(" 12345-ABCDE "), "12345abcde");
+        assert_eq!(normalize("123 456"), "123456");
+    }
+}
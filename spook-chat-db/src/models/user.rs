@@ -1,10 +1,14 @@
-use super::{channel::Channel, server::Server, Session};
-use crate::ARGON2;
+use super::{
+    channel::Channel, message::AuthoredMessage, server::Server, Ban, LoginThrottle, Permissions,
+    Session, SessionLifetime, ThrottleKind,
+};
+use crate::{tokens, ARGON2};
 use argon2::{
     password_hash::{rand_core::OsRng, SaltString},
     PasswordHash, PasswordHasher, PasswordVerifier,
 };
 use chrono::{DateTime, Utc};
+use serde::Serialize;
 use sqlx::{postgres::PgQueryResult, FromRow, PgPool};
 use uuid::Uuid;
 
@@ -15,34 +19,106 @@ pub struct User {
     pub username: String,
     pub password: String,
     pub created_at: DateTime<Utc>,
+    /// When the current E-Mail address was confirmed, `None` until then
+    pub email_verified_at: Option<DateTime<Utc>>,
+    /// Bots authenticate with API tokens only and are marked as such in messages
+    pub bot: bool,
+    /// The user that created the bot and manages its tokens
+    pub bot_owner_id: Option<Uuid>,
+    /// Set on the placeholder that stays behind when a deleted account's messages are kept
+    pub deleted_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Serialize)]
+pub struct Membership {
+    pub server: Server,
+    pub nickname: Option<String>,
+    pub avatar: Option<String>,
+    pub joined_at: DateTime<Utc>,
+    pub owner: bool,
+    pub permissions: Permissions,
+}
+
+/// What happens to the messages of a deleted account
+#[derive(Clone, Copy)]
+pub enum MessagePolicy {
+    /// The messages stay, attributed to a placeholder without any personal data
+    Anonymize,
+    Delete,
+}
+
+/// What was taken away by deleting an account, so the streams relying on it can be closed
+pub struct DeletedAccount {
+    pub sessions: Vec<Uuid>,
+    pub api_tokens: Vec<Uuid>,
+    /// The user and server of every membership that ended
+    pub memberships: Vec<(Uuid, Uuid)>,
+}
+
+/// The sessions and API tokens that were ended by changing the password
+pub struct RevokedCredentials {
+    pub sessions: Vec<Uuid>,
+    pub api_tokens: Vec<Uuid>,
 }
 
 impl User {
-    pub fn new(email_address: &str, username: &str, password: &str) -> Self {
-        let salt = SaltString::generate(&mut OsRng);
-        let password_hash = ARGON2
-            .hash_password(password.as_bytes(), &salt)
-            .unwrap()
-            .to_string();
+    /// Addresses are compared case-insensitively, so they are stored and looked up lowercased
+    pub fn normalize_email(address: &str) -> String {
+        address.trim().to_lowercase()
+    }
 
+    pub fn new(email_address: &str, username: &str, password: &str) -> Self {
         Self {
             user_id: Uuid::new_v4(),
             email_address: email_address.to_string(),
             username: username.to_string(),
-            password: password_hash,
+            password: Self::hash_password(password),
             created_at: Utc::now(),
+            email_verified_at: None,
+            bot: false,
+            bot_owner_id: None,
+            deleted_at: None,
         }
     }
 
+    /// A bot account owned by `owner`. Its address and password are placeholders
+    /// nobody knows, bots can't log in with a password.
+    pub fn new_bot(username: &str, owner: &User) -> Self {
+        let user_id = Uuid::new_v4();
+        let created_at = Utc::now();
+        Self {
+            user_id,
+            email_address: format!("{user_id}@bots.invalid"),
+            username: username.to_string(),
+            password: Self::hash_password(&tokens::base62(tokens::SECRET_LENGTH)),
+            created_at,
+            email_verified_at: Some(created_at),
+            bot: true,
+            bot_owner_id: Some(owner.user_id),
+            deleted_at: None,
+        }
+    }
+
+    pub fn hash_password(password: &str) -> String {
+        let salt = SaltString::generate(&mut OsRng);
+        ARGON2
+            .hash_password(password.as_bytes(), &salt)
+            .unwrap()
+            .to_string()
+    }
+
     pub async fn save(&self, pool: &PgPool) -> sqlx::Result<()> {
         sqlx::query!(
-            "INSERT INTO users (user_id, email_address, username, password, created_at)
-            VALUES ($1, $2, $3, $4, $5)",
+            "INSERT INTO users (user_id, email_address, username, password, created_at, email_verified_at, bot, bot_owner_id)
+            VALUES ($1, $2, $3, $4, $5, $6, $7, $8)",
             self.user_id,
             self.email_address,
             self.username,
             self.password,
-            self.created_at
+            self.created_at,
+            self.email_verified_at,
+            self.bot,
+            self.bot_owner_id
         )
         .execute(pool)
         .await?;
@@ -62,8 +138,52 @@ impl User {
         .await
     }
 
+    /// The servers this user is a member of together with their permissions there
+    pub async fn memberships(&self, pool: &PgPool) -> sqlx::Result<Vec<Membership>> {
+        let rows = sqlx::query!(
+            r#"SELECT A.server_id, A.name, A.created_at, A.icon, A.vanity_code, A.system_channel_id,
+                B.nickname, B.avatar, B.joined_at, B.owner, B.permissions,
+                (
+                    SELECT COALESCE(BIT_OR(C.permissions), 0) FROM roles C
+                    WHERE C.server_id = B.server_id AND (C.role_id = B.server_id OR C.role_id IN (
+                        SELECT D.role_id FROM members_roles D
+                        WHERE D.user_id = B.user_id AND D.server_id = B.server_id
+                    ))
+                ) AS "role_permissions!"
+            FROM servers A INNER JOIN users_servers B ON A.server_id = B.server_id
+            WHERE B.user_id = $1
+            ORDER BY B.joined_at"#,
+            self.user_id
+        )
+        .fetch_all(pool)
+        .await?;
+
+        Ok(rows
+            .into_iter()
+            .map(|row| Membership {
+                server: Server {
+                    server_id: row.server_id,
+                    name: row.name,
+                    created_at: row.created_at,
+                    icon: row.icon,
+                    vanity_code: row.vanity_code,
+                    system_channel_id: row.system_channel_id,
+                },
+                nickname: row.nickname,
+                avatar: row.avatar,
+                joined_at: row.joined_at,
+                owner: row.owner,
+                permissions: Permissions::resolve(
+                    row.owner,
+                    Permissions::from_bits_truncate(row.permissions | row.role_permissions),
+                ),
+            })
+            .collect())
+    }
+
+    /// Every channel on the user's servers they are allowed to read
     pub async fn channels(&self, pool: &PgPool) -> sqlx::Result<Vec<Channel>> {
-        sqlx::query_as!(
+        let channels = sqlx::query_as!(
             Channel,
             "SELECT A.* FROM channels A WHERE A.server_id IN (
                 SELECT B.server_id FROM users_servers B WHERE B.user_id = $1
@@ -71,29 +191,235 @@ impl User {
             self.user_id
         )
         .fetch_all(pool)
+        .await?;
+
+        let mut readable = Vec::with_capacity(channels.len());
+        for channel in channels {
+            if channel
+                .permissions_for(pool, self)
+                .await?
+                .is_some_and(|permissions| permissions.contains(Permissions::READ_MESSAGES))
+            {
+                readable.push(channel);
+            }
+        }
+
+        Ok(readable)
+    }
+
+    /// The messages the user wrote, oldest first
+    pub async fn messages(&self, pool: &PgPool) -> sqlx::Result<Vec<AuthoredMessage>> {
+        sqlx::query_as!(
+            AuthoredMessage,
+            "SELECT A.message_id, A.content, A.created_at, A.channel_id, B.server_id
+            FROM messages A INNER JOIN channels B ON A.channel_id = B.channel_id
+            WHERE A.user_id = $1
+            ORDER BY A.created_at",
+            self.user_id
+        )
+        .fetch_all(pool)
         .await
     }
 
+    /// Deletes the account along with the bots it owns. Sessions, tokens, memberships
+    /// and bans go away either way, `policy` decides what happens to the messages.
+    /// Servers the user or their bots own have to be handed over or deleted first,
+    /// otherwise nothing is deleted and `None` is returned.
+    pub async fn delete_account(
+        self,
+        pool: &PgPool,
+        policy: MessagePolicy,
+    ) -> sqlx::Result<Option<DeletedAccount>> {
+        let mut transaction = pool.begin().await?;
+
+        let users = sqlx::query!(
+            "SELECT user_id, email_address FROM users WHERE user_id = $1 OR bot_owner_id = $1",
+            self.user_id
+        )
+        .fetch_all(&mut transaction)
+        .await?;
+        let user_ids: Vec<Uuid> = users.iter().map(|user| user.user_id).collect();
+        let addresses: Vec<String> = users
+            .iter()
+            .map(|user| LoginThrottle::address_subject(&user.email_address))
+            .collect();
+
+        // With the memberships locked, an ownership transfer to one of them either
+        // finished before and is seen here, or fails on the deleted membership
+        let owners = sqlx::query_scalar!(
+            "SELECT owner FROM users_servers WHERE user_id = ANY($1) FOR UPDATE",
+            &user_ids
+        )
+        .fetch_all(&mut transaction)
+        .await?;
+        if owners.into_iter().any(|owner| owner) {
+            return Ok(None);
+        }
+
+        let sessions = sqlx::query_scalar!(
+            "DELETE FROM sessions WHERE user_id = ANY($1) RETURNING session_id",
+            &user_ids
+        )
+        .fetch_all(&mut transaction)
+        .await?;
+        let api_tokens = sqlx::query_scalar!(
+            "DELETE FROM api_tokens WHERE user_id = ANY($1) RETURNING token_id",
+            &user_ids
+        )
+        .fetch_all(&mut transaction)
+        .await?;
+        let memberships = sqlx::query!(
+            "DELETE FROM users_servers WHERE user_id = ANY($1) RETURNING user_id, server_id",
+            &user_ids
+        )
+        .fetch_all(&mut transaction)
+        .await?
+        .into_iter()
+        .map(|row| (row.user_id, row.server_id))
+        .collect();
+        sqlx::query!("DELETE FROM bans WHERE user_id = ANY($1)", &user_ids)
+            .execute(&mut transaction)
+            .await?;
+        // Throttles are keyed by the address, they would outlive the account otherwise
+        sqlx::query!(
+            "DELETE FROM login_throttles WHERE kind = ANY($1) AND subject = ANY($2)",
+            &[
+                ThrottleKind::Account.as_str(),
+                ThrottleKind::ResetAddress.as_str()
+            ] as &[&str],
+            &addresses
+        )
+        .execute(&mut transaction)
+        .await?;
+
+        match policy {
+            MessagePolicy::Delete => {
+                sqlx::query!("DELETE FROM messages WHERE user_id = ANY($1)", &user_ids)
+                    .execute(&mut transaction)
+                    .await?;
+                // Everything else referencing the users cascades or is set to NULL
+                sqlx::query!("DELETE FROM users WHERE user_id = ANY($1)", &user_ids)
+                    .execute(&mut transaction)
+                    .await?;
+            }
+            MessagePolicy::Anonymize => {
+                sqlx::query!("DELETE FROM two_factor WHERE user_id = ANY($1)", &user_ids)
+                    .execute(&mut transaction)
+                    .await?;
+                sqlx::query!(
+                    "DELETE FROM recovery_codes WHERE user_id = ANY($1)",
+                    &user_ids
+                )
+                .execute(&mut transaction)
+                .await?;
+                sqlx::query!(
+                    "DELETE FROM password_resets WHERE user_id = ANY($1)",
+                    &user_ids
+                )
+                .execute(&mut transaction)
+                .await?;
+                sqlx::query!(
+                    "DELETE FROM email_verifications WHERE user_id = ANY($1)",
+                    &user_ids
+                )
+                .execute(&mut transaction)
+                .await?;
+                sqlx::query!(
+                    "DELETE FROM channel_overwrites WHERE user_id = ANY($1)",
+                    &user_ids
+                )
+                .execute(&mut transaction)
+                .await?;
+                // Nobody knows the password and the address can't receive mail,
+                // so the placeholder can never be logged into
+                sqlx::query!(
+                    "UPDATE users SET username = 'Deleted User',
+                        email_address = user_id || '@deleted.invalid',
+                        password = $2, email_verified_at = NULL, bot = FALSE,
+                        bot_owner_id = NULL, deleted_at = NOW()
+                    WHERE user_id = ANY($1)",
+                    &user_ids,
+                    Self::hash_password(&tokens::base62(tokens::SECRET_LENGTH))
+                )
+                .execute(&mut transaction)
+                .await?;
+            }
+        }
+
+        transaction.commit().await?;
+        Ok(Some(DeletedAccount {
+            sessions,
+            api_tokens,
+            memberships,
+        }))
+    }
+
+    pub fn is_verified(&self) -> bool {
+        self.email_verified_at.is_some()
+    }
+
     pub fn verify_password(&self, password: &str) -> bool {
         let hash = PasswordHash::new(self.password.as_str()).unwrap();
         ARGON2.verify_password(password.as_bytes(), &hash).is_ok()
     }
 
-    pub async fn new_session(&self, pool: &PgPool) -> sqlx::Result<String> {
-        let session = Session::new(self);
+    /// Replaces the password, ends every session except `current` and revokes
+    /// every API token. Returns what was ended.
+    pub async fn set_password(
+        &mut self,
+        pool: &PgPool,
+        password: &str,
+        current: Uuid,
+    ) -> sqlx::Result<RevokedCredentials> {
+        let password = Self::hash_password(password);
+        let mut transaction = pool.begin().await?;
+
         sqlx::query!(
-            "INSERT INTO sessions (session_id, user_id, created_at)
-            VALUES ($1, $2, $3)",
-            session.session_id,
-            session.user_id,
-            session.created_at
+            "UPDATE users SET password = $1 WHERE user_id = $2",
+            password,
+            self.user_id
         )
-        .execute(pool)
+        .execute(&mut transaction)
+        .await?;
+
+        let sessions = sqlx::query_scalar!(
+            "DELETE FROM sessions WHERE user_id = $1 AND session_id <> $2 RETURNING session_id",
+            self.user_id,
+            current
+        )
+        .fetch_all(&mut transaction)
         .await?;
 
-        Ok(session.session_id.to_string())
+        let api_tokens = sqlx::query_scalar!(
+            "DELETE FROM api_tokens WHERE user_id = $1 RETURNING token_id",
+            self.user_id
+        )
+        .fetch_all(&mut transaction)
+        .await?;
+
+        transaction.commit().await?;
+        self.password = password;
+        Ok(RevokedCredentials {
+            sessions,
+            api_tokens,
+        })
+    }
+
+    pub async fn new_session(
+        &self,
+        pool: &PgPool,
+        lifetime: SessionLifetime,
+        remember: bool,
+        user_agent: Option<&str>,
+        ip_address: Option<String>,
+    ) -> sqlx::Result<Session> {
+        let session = Session::new(self, lifetime, remember, user_agent, ip_address);
+        session.save(pool).await?;
+
+        Ok(session)
     }
 
+    /// Makes the user a member of the server, doing nothing if they already are one
     pub async fn add_to_server(
         &self,
         pool: &PgPool,
@@ -101,7 +427,8 @@ impl User {
     ) -> sqlx::Result<PgQueryResult> {
         sqlx::query!(
             "INSERT INTO users_servers (user_id, server_id)
-            VALUES ($1, $2)",
+            VALUES ($1, $2)
+            ON CONFLICT (user_id, server_id) DO NOTHING",
             self.user_id,
             server_id
         )
@@ -109,23 +436,21 @@ impl User {
         .await
     }
 
+    /// Whether the user is allowed to read the channel
     pub async fn has_access_to_channel(
         &self,
         pool: &PgPool,
         channel_id: Uuid,
     ) -> sqlx::Result<bool> {
-        let channel = sqlx::query_as!(
-            Channel,
-            "SELECT A.* FROM channels A WHERE A.server_id IN (
-                SELECT B.server_id FROM users_servers B WHERE B.user_id = $1
-            ) AND A.channel_id = $2",
-            self.user_id,
-            channel_id
-        )
-        .fetch_optional(pool)
-        .await?;
+        let channel = match Channel::filter_by_id(pool, channel_id).await? {
+            Some(channel) => channel,
+            None => return Ok(false),
+        };
 
-        Ok(channel.is_some())
+        Ok(channel
+            .permissions_for(pool, self)
+            .await?
+            .is_some_and(|permissions| permissions.contains(Permissions::READ_MESSAGES)))
     }
 
     pub async fn has_access_to_server(&self, pool: &PgPool, server_id: Uuid) -> sqlx::Result<bool> {
@@ -143,6 +468,39 @@ impl User {
         Ok(server.is_some())
     }
 
+    /// Returns when the user's timeout on the server of the channel ends,
+    /// if they are currently timed out there
+    pub async fn timed_out_in_channel(
+        &self,
+        pool: &PgPool,
+        channel_id: Uuid,
+    ) -> sqlx::Result<Option<DateTime<Utc>>> {
+        let until = sqlx::query_scalar!(
+            "SELECT B.timed_out_until FROM channels A
+            INNER JOIN users_servers B ON A.server_id = B.server_id
+            WHERE A.channel_id = $1 AND B.user_id = $2 AND B.timed_out_until > NOW()",
+            channel_id,
+            self.user_id
+        )
+        .fetch_optional(pool)
+        .await?;
+
+        Ok(until.flatten())
+    }
+
+    pub async fn is_banned_from(&self, pool: &PgPool, server_id: Uuid) -> sqlx::Result<bool> {
+        let ban = sqlx::query_as!(
+            Ban,
+            "SELECT * FROM bans WHERE user_id = $1 AND server_id = $2",
+            self.user_id,
+            server_id
+        )
+        .fetch_optional(pool)
+        .await?;
+
+        Ok(ban.is_some_and(|ban| ban.is_active()))
+    }
+
     pub async fn filter_by_id(pool: &PgPool, id: Uuid) -> sqlx::Result<Option<User>> {
         let user = sqlx::query_as!(User, "SELECT * FROM users WHERE user_id = $1", id)
             .fetch_optional(pool)
@@ -151,10 +509,11 @@ impl User {
         user
     }
 
+    /// Looks up the account with the address, regardless of case
     pub async fn filter_by_email(pool: &PgPool, email_address: &str) -> sqlx::Result<Option<Self>> {
         let user = sqlx::query_as!(
             User,
-            "SELECT * FROM users WHERE email_address = $1",
+            "SELECT * FROM users WHERE LOWER(email_address) = LOWER($1)",
             email_address
         )
         .fetch_optional(pool)
@@ -171,6 +530,7 @@ impl User {
             User,
             "SELECT * FROM users WHERE user_id IN (
                 SELECT user_id FROM sessions WHERE session_id = $1
+                    AND expires_at > NOW() AND idle_expires_at > NOW()
             )",
             session_id
         )
@@ -180,6 +540,17 @@ impl User {
         users
     }
 
+    /// The bots this user created
+    pub async fn bots(&self, pool: &PgPool) -> sqlx::Result<Vec<Self>> {
+        sqlx::query_as!(
+            User,
+            "SELECT * FROM users WHERE bot_owner_id = $1 ORDER BY created_at",
+            self.user_id
+        )
+        .fetch_all(pool)
+        .await
+    }
+
     pub async fn filter_by_username(pool: &PgPool, username: &str) -> sqlx::Result<Vec<Self>> {
         let user = sqlx::query_as!(User, "SELECT * FROM users WHERE username = $1", username)
             .fetch_all(pool)
@@ -188,3 +559,16 @@ impl User {
         user
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn normalize_email_trims_and_lowercases() {
+        assert_eq!(
+            User::normalize_email("  Someone@Example.COM "),
+            "someone@example.com"
+        );
+    }
+}
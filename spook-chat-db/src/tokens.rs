@@ -0,0 +1,80 @@
+use argon2::password_hash::rand_core::{OsRng, RngCore};
+use sha2::{Digest, Sha256};
+
+const BASE62: &[u8] = b"0123456789ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz";
+
+/// Length of the secrets handed out for password resets and the like
+pub const SECRET_LENGTH: usize = 32;
+
+/// A random string of `length` characters picked from `alphabet`
+pub fn random(alphabet: &[u8], length: usize) -> String {
+    // Rejecting the top bytes keeps every character equally likely
+    let limit = 256 - 256 % alphabet.len();
+    let mut code = String::with_capacity(length);
+    while code.len() < length {
+        let byte = (OsRng.next_u32() & 0xFF) as usize;
+        if byte < limit {
+            code.push(alphabet[byte % alphabet.len()] as char);
+        }
+    }
+    code
+}
+
+/// A random base62 string of `length` characters
+pub fn base62(length: usize) -> String {
+    random(BASE62, length)
+}
+
+/// `length` random bytes
+pub fn bytes(length: usize) -> Vec<u8> {
+    let mut bytes = vec![0; length];
+    OsRng.fill_bytes(&mut bytes);
+    bytes
+}
+
+/// Hex encoded SHA-256 of a secret. Secrets are only stored hashed, so a leaked
+/// table can't be used to take over accounts. They are random and long enough
+/// that a fast hash does the job, unlike passwords.
+pub fn hash(secret: &str) -> String {
+    Sha256::digest(secret.as_bytes())
+        .iter()
+        .map(|byte| format!("{byte:02x}"))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn random_has_the_length_and_alphabet() {
+        let alphabet = b"abc123";
+        for length in [0, 1, 10, 64] {
+            let code = random(alphabet, length);
+            assert_eq!(code.len(), length);
+            assert!(code.bytes().all(|c| alphabet.contains(&c)));
+        }
+    }
+
+    #[test]
+    fn random_uses_the_whole_alphabet() {
+        let code = random(b"01", 256);
+        assert!(code.contains('0') && code.contains('1'));
+    }
+
+    #[test]
+    fn base62_is_alphanumeric() {
+        let secret = base62(SECRET_LENGTH);
+        assert_eq!(secret.len(), SECRET_LENGTH);
+        assert!(secret.chars().all(|c| c.is_ascii_alphanumeric()));
+        assert_ne!(secret, base62(SECRET_LENGTH));
+    }
+
+    #[test]
+    fn hash_is_hex_sha256() {
+        assert_eq!(
+            hash("abc"),
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
+    }
+}
@@ -0,0 +1,161 @@
+use crate::{
+    events::{self, Revocation},
+    guards::LoginGuard,
+    quick_response, MyState,
+};
+use rocket::{
+    http::{Cookie, CookieJar, Header, Status},
+    response::Responder,
+    serde::{json::Json, uuid::Uuid, Deserialize, Serialize},
+    State,
+};
+use spook_chat_db::models::{AuthoredMessage, Membership, Session, TwoFactor};
+use sqlx::types::chrono::{DateTime, Utc};
+
+#[derive(Deserialize)]
+#[serde(crate = "rocket::serde")]
+struct DeleteAccountData<'a> {
+    password: &'a str,
+    /// Required when two-factor authentication is enabled
+    code: Option<&'a str>,
+}
+
+#[derive(Serialize)]
+#[serde(crate = "rocket::serde")]
+struct ExportedProfile {
+    user_id: Uuid,
+    username: String,
+    email_address: String,
+    created_at: DateTime<Utc>,
+    email_verified_at: Option<DateTime<Utc>>,
+}
+
+/// Everything stored about the user, as handed out by `/account/export`
+#[derive(Serialize)]
+#[serde(crate = "rocket::serde")]
+struct AccountExport {
+    exported_at: DateTime<Utc>,
+    profile: ExportedProfile,
+    memberships: Vec<Membership>,
+    sessions: Vec<Session>,
+    messages: Vec<AuthoredMessage>,
+}
+
+/// The export as a file download
+#[derive(Responder)]
+struct ExportFile {
+    data: Json<AccountExport>,
+    disposition: Header<'static>,
+}
+
+enum AccountError {
+    SqlxError(sqlx::Error),
+    WrongPassword,
+    CodeRequired,
+    InvalidCode,
+    OwnsServers,
+}
+
+impl<'r, 'o: 'r> Responder<'r, 'o> for AccountError {
+    fn respond_to(self, _request: &'r rocket::Request<'_>) -> rocket::response::Result<'o> {
+        match self {
+            Self::SqlxError(e) => Ok(quick_response(Status::InternalServerError, e.to_string())),
+            Self::WrongPassword => Ok(quick_response(Status::Forbidden, "Wrong password")),
+            Self::CodeRequired => Ok(quick_response(
+                Status::BadRequest,
+                "Enter a code from your authenticator app or a recovery code",
+            )),
+            Self::InvalidCode => Ok(quick_response(Status::BadRequest, "Invalid code")),
+            Self::OwnsServers => Ok(quick_response(
+                Status::Conflict,
+                "Transfer the ownership of the servers you and your bots own or delete them first",
+            )),
+        }
+    }
+}
+
+impl From<sqlx::Error> for AccountError {
+    fn from(e: sqlx::Error) -> Self {
+        Self::SqlxError(e)
+    }
+}
+
+/// Downloads the user's profile, memberships, sessions and messages as JSON
+#[get("/export")]
+async fn export(state: &State<MyState>, login: LoginGuard) -> Result<ExportFile, AccountError> {
+    let user = &login.user;
+    let exported_at = Utc::now();
+    let export = AccountExport {
+        exported_at,
+        memberships: user.memberships(&state.conn).await?,
+        sessions: Session::filter_by_user_id(&state.conn, user.user_id).await?,
+        messages: user.messages(&state.conn).await?,
+        profile: ExportedProfile {
+            user_id: user.user_id,
+            username: user.username.clone(),
+            email_address: user.email_address.clone(),
+            created_at: user.created_at,
+            email_verified_at: user.email_verified_at,
+        },
+    };
+
+    Ok(ExportFile {
+        data: Json(export),
+        disposition: Header::new(
+            "Content-Disposition",
+            format!(
+                "attachment; filename=\"spook-chat-{}.json\"",
+                exported_at.format("%Y-%m-%d")
+            ),
+        ),
+    })
+}
+
+/// Deletes the account and the bots it owns, and logs out everywhere.
+/// Whether its messages stay behind anonymized is up to the instance.
+#[post("/delete", data = "<data>")]
+async fn delete_account(
+    state: &State<MyState>,
+    cookies: &CookieJar<'_>,
+    login: LoginGuard,
+    data: Json<DeleteAccountData<'_>>,
+) -> Result<String, AccountError> {
+    if !login.user.verify_password(data.password) {
+        return Err(AccountError::WrongPassword);
+    }
+    let two_factor = TwoFactor::filter_by_user_id(&state.conn, login.user.user_id)
+        .await?
+        .filter(TwoFactor::is_enabled);
+    if let Some(mut two_factor) = two_factor {
+        let code = data.code.ok_or(AccountError::CodeRequired)?;
+        if !two_factor.verify_any(&state.conn, code).await? {
+            return Err(AccountError::InvalidCode);
+        }
+    }
+    let user_id = login.user.user_id;
+    let deleted = login
+        .user
+        .delete_account(&state.conn, state.deletion.messages)
+        .await?
+        .ok_or(AccountError::OwnsServers)?;
+    events::revoke(
+        state,
+        Revocation::UserSessions {
+            user_id,
+            except: None,
+        },
+    );
+    for token_id in deleted.api_tokens {
+        events::revoke(state, Revocation::ApiToken { token_id });
+    }
+    for (user_id, server_id) in deleted.memberships {
+        events::revoke(state, Revocation::Membership { user_id, server_id });
+    }
+    cookies.remove_private(Cookie::named("session"));
+
+    Ok("Your account has been deleted".to_string())
+}
+
+pub fn routes() -> Vec<rocket::Route> {
+    routes![export, delete_account]
+}
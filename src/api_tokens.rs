@@ -0,0 +1,127 @@
+use crate::{
+    events::{self, Revocation},
+    guards::LoginGuard,
+    quick_response, MyState,
+};
+use rocket::{
+    http::Status,
+    response::Responder,
+    serde::{json::Json, uuid::Uuid, Deserialize, Serialize},
+    State,
+};
+use spook_chat_db::models::{ApiToken, Scopes};
+use sqlx::types::chrono::{DateTime, Utc};
+
+const MAX_NAME_LENGTH: usize = 64;
+
+#[derive(Deserialize)]
+#[serde(crate = "rocket::serde")]
+struct CreateTokenData<'a> {
+    name: &'a str,
+    scopes: Scopes,
+    /// The token never expires without one
+    expires_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Deserialize)]
+#[serde(crate = "rocket::serde")]
+struct RevokeTokenData {
+    token_id: Uuid,
+}
+
+/// A freshly created token, the only response that contains its secret
+#[derive(Serialize)]
+#[serde(crate = "rocket::serde")]
+struct CreatedToken {
+    #[serde(flatten)]
+    token: ApiToken,
+    secret: String,
+}
+
+enum TokenError {
+    SqlxError(sqlx::Error),
+    InvalidRequest(&'static str),
+    InvalidName,
+    NoToken,
+}
+
+impl<'r, 'o: 'r> Responder<'r, 'o> for TokenError {
+    fn respond_to(self, _request: &'r rocket::Request<'_>) -> rocket::response::Result<'o> {
+        match self {
+            Self::SqlxError(e) => Ok(quick_response(Status::InternalServerError, e.to_string())),
+            Self::InvalidRequest(reason) => Ok(quick_response(Status::BadRequest, reason)),
+            Self::InvalidName => Ok(quick_response(
+                Status::BadRequest,
+                format!("Token names have to be between 1 and {MAX_NAME_LENGTH} characters long"),
+            )),
+            Self::NoToken => Ok(quick_response(Status::NotFound, "No such token")),
+        }
+    }
+}
+
+impl From<sqlx::Error> for TokenError {
+    fn from(e: sqlx::Error) -> Self {
+        Self::SqlxError(e)
+    }
+}
+
+#[get("/")]
+async fn list_tokens(
+    state: &State<MyState>,
+    login: LoginGuard,
+) -> Result<Json<Vec<ApiToken>>, TokenError> {
+    Ok(Json(
+        ApiToken::filter_by_user_id(&state.conn, login.user.user_id).await?,
+    ))
+}
+
+/// Creates a personal access token. Only browser sessions can manage tokens,
+/// so a leaked token can't be used to mint more.
+#[post("/", data = "<data>")]
+async fn create_token(
+    state: &State<MyState>,
+    login: LoginGuard,
+    data: Json<CreateTokenData<'_>>,
+) -> Result<Json<CreatedToken>, TokenError> {
+    let name = data.name.trim();
+    if name.is_empty() || name.chars().count() > MAX_NAME_LENGTH {
+        return Err(TokenError::InvalidName);
+    }
+    if data.scopes.is_empty() {
+        return Err(TokenError::InvalidRequest(
+            "A token needs at least one scope",
+        ));
+    }
+    if data.expires_at.is_some_and(|expires| expires <= Utc::now()) {
+        return Err(TokenError::InvalidRequest(
+            "The expiry has to be in the future",
+        ));
+    }
+
+    let (token, secret) = ApiToken::new(&login.user, name, data.scopes, data.expires_at);
+    token.save(&state.conn).await?;
+    Ok(Json(CreatedToken { token, secret }))
+}
+
+/// Deletes a token and closes the event streams opened with it
+#[post("/revoke", data = "<data>")]
+async fn revoke_token(
+    state: &State<MyState>,
+    login: LoginGuard,
+    data: Json<RevokeTokenData>,
+) -> Result<String, TokenError> {
+    if !ApiToken::revoke(&state.conn, login.user.user_id, data.token_id).await? {
+        return Err(TokenError::NoToken);
+    }
+    events::revoke(
+        state,
+        Revocation::ApiToken {
+            token_id: data.token_id,
+        },
+    );
+    Ok("Token revoked".to_string())
+}
+
+pub fn routes() -> Vec<rocket::Route> {
+    routes![list_tokens, create_token, revoke_token]
+}
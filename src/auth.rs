@@ -1,12 +1,27 @@
-use crate::{guards::LoginGuard, quick_response, MyState};
+use crate::{
+    events::{self, Revocation},
+    guards::{ClientInfo, LoginGuard},
+    mail::{Email, Mailer},
+    quick_response, throttle,
+    validation::{self, FieldErrors},
+    MyState,
+};
+use chrono::{DateTime, Utc};
 use rocket::{
     http::{Cookie, CookieJar, Status},
     response::Responder,
-    serde::{json::Json, uuid::Uuid, Deserialize},
+    serde::{json::Json, uuid::Uuid, Deserialize, Serialize},
+    time::Duration,
     FromForm, State,
 };
-use spook_chat_db::models::User;
-use std::str::FromStr;
+use spook_chat_db::models::{
+    EmailVerification, LoginAttempt, PasswordReset, Session, TwoFactor, User,
+};
+use sqlx::PgPool;
+use std::{str::FromStr, sync::Arc};
+
+/// How long the second login step may take once the password was accepted
+const PENDING_LOGIN_SECONDS: i64 = 5 * 60;
 
 #[derive(FromForm, Deserialize)]
 #[serde(crate = "rocket::serde")]
@@ -21,6 +36,96 @@ struct RegisterData<'a> {
 struct LoginData<'a> {
     email: &'a str,
     password: &'a str,
+    /// Issues a longer lived session
+    #[serde(default)]
+    remember: bool,
+}
+
+#[derive(Deserialize)]
+#[serde(crate = "rocket::serde")]
+struct SecondFactorData<'a> {
+    /// A code from the authenticator app or one of the recovery codes
+    code: &'a str,
+}
+
+/// A login that got the password right but still needs a two-factor code.
+/// It lives in a private cookie, so clients can neither read nor forge it.
+struct PendingLogin {
+    user_id: Uuid,
+    remember: bool,
+    expires_at: i64,
+}
+
+impl PendingLogin {
+    const COOKIE: &'static str = "pending_login";
+
+    fn from_cookies(cookies: &CookieJar<'_>) -> Option<Self> {
+        let cookie = cookies.get_private(Self::COOKIE)?;
+        let mut parts = cookie.value().split(' ');
+        let pending = Self {
+            user_id: Uuid::from_str(parts.next()?).ok()?,
+            remember: parts.next()?.parse().ok()?,
+            expires_at: parts.next()?.parse().ok()?,
+        };
+        (pending.expires_at > Utc::now().timestamp()).then_some(pending)
+    }
+
+    fn to_cookie(&self) -> Cookie<'static> {
+        Cookie::build(
+            Self::COOKIE,
+            format!("{} {} {}", self.user_id, self.remember, self.expires_at),
+        )
+        .max_age(Duration::seconds(PENDING_LOGIN_SECONDS))
+        .finish()
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(crate = "rocket::serde")]
+struct ChangePasswordData<'a> {
+    old_password: &'a str,
+    new_password: &'a str,
+}
+
+#[derive(Deserialize)]
+#[serde(crate = "rocket::serde")]
+struct ForgotPasswordData<'a> {
+    email: &'a str,
+}
+
+#[derive(Deserialize)]
+#[serde(crate = "rocket::serde")]
+struct ResetPasswordData<'a> {
+    token: &'a str,
+    password: &'a str,
+}
+
+#[derive(Deserialize)]
+#[serde(crate = "rocket::serde")]
+struct VerifyEmailData<'a> {
+    token: &'a str,
+}
+
+#[derive(Deserialize)]
+#[serde(crate = "rocket::serde")]
+struct ChangeEmailData<'a> {
+    email: &'a str,
+    password: &'a str,
+}
+
+#[derive(Deserialize)]
+#[serde(crate = "rocket::serde")]
+struct RevokeSessionData {
+    session_id: Uuid,
+}
+
+/// A session as listed to its owner, `current` marks the one making the request
+#[derive(Serialize)]
+#[serde(crate = "rocket::serde")]
+struct SessionInfo {
+    #[serde(flatten)]
+    session: Session,
+    current: bool,
 }
 
 enum AuthErrors {
@@ -28,17 +133,63 @@ enum AuthErrors {
     SqlxError(sqlx::Error),
     WrongEmail,
     WrongPassword,
+    NoSession,
+    InvalidToken,
+    EmailTaken,
+    AlreadyVerified,
+    NoPendingLogin,
+    InvalidCode,
+    InvalidFields(FieldErrors),
+    TooManyAttempts(DateTime<Utc>),
+    TooManyResetRequests(DateTime<Utc>),
 }
 
 impl<'r, 'o: 'r> Responder<'r, 'o> for AuthErrors {
-    fn respond_to(self, _request: &'r rocket::Request<'_>) -> rocket::response::Result<'o> {
+    fn respond_to(self, request: &'r rocket::Request<'_>) -> rocket::response::Result<'o> {
         match self {
             Self::SqlxError(e) => Ok(quick_response(Status::InternalServerError, e.to_string())),
+            Self::InvalidFields(errors) => (Status::BadRequest, Json(errors)).respond_to(request),
             Self::UuidError(e) => Ok(quick_response(Status::BadRequest, e.to_string())),
             Self::WrongEmail | Self::WrongPassword => Ok(quick_response(
                 Status::BadRequest,
                 "Wrong E-Mail or Password",
             )),
+            Self::NoSession => Ok(quick_response(Status::NotFound, "No such session")),
+            Self::InvalidToken => Ok(quick_response(
+                Status::BadRequest,
+                "The token is invalid or has expired",
+            )),
+            Self::EmailTaken => Ok(quick_response(
+                Status::Conflict,
+                "This E-Mail address is already in use",
+            )),
+            Self::AlreadyVerified => Ok(quick_response(
+                Status::BadRequest,
+                "Your E-Mail address is already verified",
+            )),
+            Self::NoPendingLogin => Ok(quick_response(
+                Status::BadRequest,
+                "Log in with your password first",
+            )),
+            Self::InvalidCode => Ok(quick_response(Status::BadRequest, "Invalid code")),
+            Self::TooManyAttempts(until) => {
+                let seconds = (until - Utc::now()).num_seconds().max(1);
+                let mut response = quick_response(
+                    Status::TooManyRequests,
+                    format!("Too many failed attempts, try again in {seconds} seconds"),
+                );
+                response.set_raw_header("Retry-After", seconds.to_string());
+                Ok(response)
+            }
+            Self::TooManyResetRequests(until) => {
+                let seconds = (until - Utc::now()).num_seconds().max(1);
+                let mut response = quick_response(
+                    Status::TooManyRequests,
+                    format!("Too many password reset requests, try again in {seconds} seconds"),
+                );
+                response.set_raw_header("Retry-After", seconds.to_string());
+                Ok(response)
+            }
         }
     }
 }
@@ -72,6 +223,12 @@ async fn authenticated(login: LoginGuard) -> (Status, String) {
 #[get("/logout")]
 async fn logout(state: &State<MyState>, user: LoginGuard) -> Result<(Status, String), AuthErrors> {
     user.session.delete(&state.conn).await?;
+    events::revoke(
+        state,
+        Revocation::Session {
+            session_id: user.session.session_id,
+        },
+    );
     return Ok((
         Status::Ok,
         "You've been successfully logged out".to_string(),
@@ -83,15 +240,105 @@ async fn register(
     state: &State<MyState>,
     register: Json<RegisterData<'_>>,
 ) -> Result<(Status, String), AuthErrors> {
-    let user = User::new(register.email, register.username, register.password);
-    user.save(&state.conn).await?;
+    let email = User::normalize_email(register.email);
+    let mut errors = FieldErrors::default();
+    errors.check("email", validation::email(&email));
+    errors.check("username", validation::username(register.username));
+    errors.check(
+        "password",
+        validation::password(register.password, &[register.username, &email]),
+    );
+    errors.into_result().map_err(AuthErrors::InvalidFields)?;
+
+    if User::filter_by_email(&state.conn, &email).await?.is_some() {
+        return Err(AuthErrors::EmailTaken);
+    }
+    let user = User::new(&email, register.username, register.password);
+    match user.save(&state.conn).await {
+        // Someone registered the address since it was checked
+        Err(sqlx::Error::Database(e)) if e.code().as_deref() == Some("23505") => {
+            return Err(AuthErrors::EmailTaken)
+        }
+        result => result?,
+    }
+    send_verification(state, &user, &user.email_address).await?;
     Ok((Status::Ok, format!("Created User: {}", user.username)))
 }
 
+/// Emails a token to `email_address` that verifies it for the user once redeemed
+async fn send_verification(
+    state: &MyState,
+    user: &User,
+    email_address: &str,
+) -> Result<(), AuthErrors> {
+    let (verification, token) =
+        EmailVerification::new(user, email_address, state.tokens.email_verification);
+    verification.save(&state.conn).await?;
+
+    let link = state.mailer.link("verify-email", &token);
+    let email = Email::verification(email_address, &user.username, &link);
+    if let Err(e) = state.mailer.send(email).await {
+        eprintln!("Failed to send verification email: {e}");
+    }
+    Ok(())
+}
+
+#[post("/email/verify", data = "<verify>")]
+async fn verify_email(
+    state: &State<MyState>,
+    verify: Json<VerifyEmailData<'_>>,
+) -> Result<(Status, String), AuthErrors> {
+    match EmailVerification::redeem(&state.conn, verify.token).await {
+        Ok(Some(_)) => Ok((Status::Ok, "E-Mail address verified".to_string())),
+        Ok(None) => Err(AuthErrors::InvalidToken),
+        // Someone else registered the address since the change was requested
+        Err(sqlx::Error::Database(e)) if e.code().as_deref() == Some("23505") => {
+            Err(AuthErrors::EmailTaken)
+        }
+        Err(e) => Err(e.into()),
+    }
+}
+
+#[post("/email/resend")]
+async fn resend_verification(
+    state: &State<MyState>,
+    login: LoginGuard,
+) -> Result<(Status, String), AuthErrors> {
+    if login.user.is_verified() {
+        return Err(AuthErrors::AlreadyVerified);
+    }
+    send_verification(state, &login.user, &login.user.email_address).await?;
+    Ok((Status::Ok, "Verification E-Mail sent".to_string()))
+}
+
+/// Sends a verification to the new address, which replaces the current one
+/// once it's verified
+#[post("/email/change", data = "<change>")]
+async fn change_email(
+    state: &State<MyState>,
+    login: LoginGuard,
+    change: Json<ChangeEmailData<'_>>,
+) -> Result<(Status, String), AuthErrors> {
+    if !login.user.verify_password(change.password) {
+        return Err(AuthErrors::WrongPassword);
+    }
+    let email = User::normalize_email(change.email);
+    let mut errors = FieldErrors::default();
+    errors.check("email", validation::email(&email));
+    errors.into_result().map_err(AuthErrors::InvalidFields)?;
+    if User::filter_by_email(&state.conn, &email).await?.is_some() {
+        return Err(AuthErrors::EmailTaken);
+    }
+
+    send_verification(state, &login.user, &email).await?;
+    Ok((Status::Ok, format!("Verification E-Mail sent to {email}")))
+}
+
 #[post("/login", data = "<login>")]
 async fn login(
     state: &State<MyState>,
     cookies: &CookieJar<'_>,
+    client: ClientInfo,
     login: Json<LoginData<'_>>,
 ) -> Result<(Status, String), AuthErrors> {
     if let Some(cookie) = cookies.get_private("session") {
@@ -104,19 +351,283 @@ async fn login(
         }
     }
 
-    if let Some(user) = User::filter_by_email(&state.conn, login.email).await? {
+    let ip_address = client.ip_address.as_deref();
+    let failures = match throttle::begin_attempt(state, login.email, ip_address).await? {
+        LoginAttempt::Allowed(failures) => failures,
+        LoginAttempt::Blocked(until) => return Err(AuthErrors::TooManyAttempts(until)),
+    };
+    // Bots only authenticate with their API tokens
+    let user = User::filter_by_email(&state.conn, &User::normalize_email(login.email))
+        .await?
+        .filter(|user| !user.bot);
+    if let Some(user) = user {
         if user.verify_password(login.password) {
-            let session_id = user.new_session(&state.conn).await.unwrap();
-            cookies.add_private(Cookie::build("session", session_id).finish());
+            let two_factor = TwoFactor::filter_by_user_id(&state.conn, user.user_id).await?;
+            if two_factor.is_some_and(|two_factor| two_factor.is_enabled()) {
+                let pending = PendingLogin {
+                    user_id: user.user_id,
+                    remember: login.remember,
+                    expires_at: Utc::now().timestamp() + PENDING_LOGIN_SECONDS,
+                };
+                throttle::record_password_accepted(state, &user, ip_address).await?;
+                cookies.add_private(pending.to_cookie());
+                return Ok((
+                    Status::Accepted,
+                    "Enter the code from your authenticator app".to_string(),
+                ));
+            }
+
+            throttle::record_success(state, &user, ip_address).await?;
+            start_session(state, cookies, client, &user, login.remember).await?;
             Ok((Status::Ok, format!("Logged in as {}", user.username)))
         } else {
+            throttle::record_failure(state, Some(&user), failures, ip_address).await;
             Err(AuthErrors::WrongPassword)
         }
     } else {
+        throttle::record_failure(state, None, failures, ip_address).await;
         Err(AuthErrors::WrongEmail)
     }
 }
 
+/// The second login step for accounts with two-factor authentication
+#[post("/login/2fa", data = "<second_factor>")]
+async fn login_second_factor(
+    state: &State<MyState>,
+    cookies: &CookieJar<'_>,
+    client: ClientInfo,
+    second_factor: Json<SecondFactorData<'_>>,
+) -> Result<(Status, String), AuthErrors> {
+    let pending = PendingLogin::from_cookies(cookies).ok_or(AuthErrors::NoPendingLogin)?;
+    let user = User::filter_by_id(&state.conn, pending.user_id)
+        .await?
+        .ok_or(AuthErrors::NoPendingLogin)?;
+    let mut two_factor = TwoFactor::filter_by_user_id(&state.conn, user.user_id)
+        .await?
+        .ok_or(AuthErrors::NoPendingLogin)?;
+
+    let ip_address = client.ip_address.as_deref();
+    let failures = match throttle::begin_attempt(state, &user.email_address, ip_address).await? {
+        LoginAttempt::Allowed(failures) => failures,
+        LoginAttempt::Blocked(until) => return Err(AuthErrors::TooManyAttempts(until)),
+    };
+    if !two_factor
+        .verify_any(&state.conn, second_factor.code)
+        .await?
+    {
+        throttle::record_failure(state, Some(&user), failures, ip_address).await;
+        return Err(AuthErrors::InvalidCode);
+    }
+
+    throttle::record_success(state, &user, ip_address).await?;
+    cookies.remove_private(Cookie::named(PendingLogin::COOKIE));
+    start_session(state, cookies, client, &user, pending.remember).await?;
+    Ok((Status::Ok, format!("Logged in as {}", user.username)))
+}
+
+/// Creates a session for the user and hands it out as a cookie
+async fn start_session(
+    state: &MyState,
+    cookies: &CookieJar<'_>,
+    client: ClientInfo,
+    user: &User,
+    remember: bool,
+) -> Result<(), AuthErrors> {
+    let lifetime = state.sessions.lifetime(remember);
+    let session = user
+        .new_session(
+            &state.conn,
+            lifetime,
+            remember,
+            client.user_agent.as_deref(),
+            client.ip_address,
+        )
+        .await?;
+    cookies.add_private(
+        Cookie::build("session", session.session_id.to_string())
+            .max_age(Duration::seconds(lifetime.absolute.num_seconds()))
+            .finish(),
+    );
+    Ok(())
+}
+
+/// Ends the streams of every session of the user but `except`, after they were logged out
+fn revoke_sessions(state: &MyState, user_id: Uuid, except: Option<Uuid>) {
+    events::revoke(state, Revocation::UserSessions { user_id, except });
+}
+
+/// Ends the streams of the API tokens a password change revoked
+fn revoke_api_tokens(state: &MyState, api_tokens: Vec<Uuid>) {
+    for token_id in api_tokens {
+        events::revoke(state, Revocation::ApiToken { token_id });
+    }
+}
+
+/// Sets a new password and logs out every other session
+#[post("/password/change", data = "<change>")]
+async fn change_password(
+    state: &State<MyState>,
+    mut login: LoginGuard,
+    change: Json<ChangePasswordData<'_>>,
+) -> Result<(Status, String), AuthErrors> {
+    if !login.user.verify_password(change.old_password) {
+        return Err(AuthErrors::WrongPassword);
+    }
+    let mut errors = FieldErrors::default();
+    errors.check(
+        "new_password",
+        validation::password(
+            change.new_password,
+            &[&login.user.username, &login.user.email_address],
+        ),
+    );
+    errors.into_result().map_err(AuthErrors::InvalidFields)?;
+
+    let revoked = login
+        .user
+        .set_password(&state.conn, change.new_password, login.session.session_id)
+        .await?;
+    revoke_sessions(state, login.user.user_id, Some(login.session.session_id));
+    revoke_api_tokens(state, revoked.api_tokens);
+    Ok((Status::Ok, "Password changed".to_string()))
+}
+
+/// Emails a password reset token. Responds the same, and just as fast, whether
+/// or not the address belongs to an account, so it can't be used to find out.
+#[post("/password/forgot", data = "<forgot>")]
+async fn forgot_password(
+    state: &State<MyState>,
+    client: ClientInfo,
+    forgot: Json<ForgotPasswordData<'_>>,
+) -> Result<(Status, String), AuthErrors> {
+    let ip_address = client.ip_address.as_deref();
+    if let Some(until) = throttle::record_reset_request(state, forgot.email, ip_address).await? {
+        return Err(AuthErrors::TooManyResetRequests(until));
+    }
+    rocket::tokio::spawn(send_password_reset(
+        state.conn.clone(),
+        state.mailer.clone(),
+        User::normalize_email(forgot.email),
+        state.tokens.password_reset,
+    ));
+
+    Ok((
+        Status::Ok,
+        "If an account with that E-Mail exists, a reset link is on its way".to_string(),
+    ))
+}
+
+/// Creates a reset token for the account with the address, if there is one, and mails it
+async fn send_password_reset(
+    conn: PgPool,
+    mailer: Arc<Mailer>,
+    address: String,
+    valid_for: chrono::Duration,
+) {
+    let user = match User::filter_by_email(&conn, &address).await {
+        Ok(user) => user.filter(|user| !user.bot),
+        Err(e) => {
+            eprintln!("Failed to look up the account for a password reset: {e}");
+            return;
+        }
+    };
+    if let Some(user) = user {
+        let (reset, token) = PasswordReset::new(&user, valid_for);
+        if let Err(e) = reset.save(&conn).await {
+            eprintln!("Failed to save password reset: {e}");
+            return;
+        }
+
+        let link = mailer.link("reset-password", &token);
+        let email = Email::password_reset(&user.email_address, &user.username, &link);
+        if let Err(e) = mailer.send(email).await {
+            eprintln!("Failed to send password reset email: {e}");
+        }
+    }
+}
+
+/// Sets a new password with a token from `forgot_password` and logs out every session
+#[post("/password/reset", data = "<reset>")]
+async fn reset_password(
+    state: &State<MyState>,
+    reset: Json<ResetPasswordData<'_>>,
+) -> Result<(Status, String), AuthErrors> {
+    let mut errors = FieldErrors::default();
+    errors.check("password", validation::password(reset.password, &[]));
+    errors.into_result().map_err(AuthErrors::InvalidFields)?;
+
+    let (user_id, revoked) = PasswordReset::redeem(&state.conn, reset.token, reset.password)
+        .await?
+        .ok_or(AuthErrors::InvalidToken)?;
+    revoke_sessions(state, user_id, None);
+    revoke_api_tokens(state, revoked.api_tokens);
+    Ok((Status::Ok, "Password reset, you can log in now".to_string()))
+}
+
+/// Lists the devices the user is logged in on
+#[get("/sessions")]
+async fn sessions(
+    state: &State<MyState>,
+    login: LoginGuard,
+) -> Result<Json<Vec<SessionInfo>>, AuthErrors> {
+    let sessions = Session::filter_by_user_id(&state.conn, login.user.user_id).await?;
+    Ok(Json(
+        sessions
+            .into_iter()
+            .map(|session| SessionInfo {
+                current: session.session_id == login.session.session_id,
+                session,
+            })
+            .collect(),
+    ))
+}
+
+/// Logs out one of the user's sessions and closes its event streams
+#[post("/sessions/revoke", data = "<revoke>")]
+async fn revoke_session(
+    state: &State<MyState>,
+    login: LoginGuard,
+    revoke: Json<RevokeSessionData>,
+) -> Result<(Status, String), AuthErrors> {
+    if !Session::revoke(&state.conn, login.user.user_id, revoke.session_id).await? {
+        return Err(AuthErrors::NoSession);
+    }
+    events::revoke(
+        state,
+        Revocation::Session {
+            session_id: revoke.session_id,
+        },
+    );
+    Ok((Status::Ok, "Session revoked".to_string()))
+}
+
+/// Logs out every session of the user except the one making the request
+#[post("/sessions/revoke-others")]
+async fn revoke_other_sessions(
+    state: &State<MyState>,
+    login: LoginGuard,
+) -> Result<(Status, String), AuthErrors> {
+    let revoked = login.session.revoke_others(&state.conn).await?;
+    revoke_sessions(state, login.user.user_id, Some(login.session.session_id));
+    Ok((Status::Ok, format!("Revoked {} sessions", revoked.len())))
+}
+
 pub fn routes() -> Vec<rocket::Route> {
-    routes![register, login, authenticated, logout, test]
+    routes![
+        register,
+        login,
+        login_second_factor,
+        authenticated,
+        logout,
+        test,
+        sessions,
+        revoke_session,
+        revoke_other_sessions,
+        change_password,
+        forgot_password,
+        reset_password,
+        verify_email,
+        resend_verification,
+        change_email
+    ]
 }
@@ -0,0 +1,160 @@
+use crate::{
+    events::{self, Revocation},
+    guards::LoginGuard,
+    quick_response, MyState,
+};
+use rocket::{
+    http::Status,
+    response::Responder,
+    serde::{json::Json, uuid::Uuid, Deserialize, Serialize},
+    State,
+};
+use spook_chat_db::models::{ApiToken, Scopes, User};
+use sqlx::types::chrono::{DateTime, Utc};
+
+/// Usernames are stored as VARCHAR(30)
+const MAX_NAME_LENGTH: usize = 30;
+
+#[derive(Deserialize)]
+#[serde(crate = "rocket::serde")]
+struct CreateBotData<'a> {
+    name: &'a str,
+}
+
+#[derive(Deserialize)]
+#[serde(crate = "rocket::serde")]
+struct BotIdData {
+    bot_id: Uuid,
+}
+
+#[derive(Serialize)]
+#[serde(crate = "rocket::serde")]
+struct Bot {
+    user_id: Uuid,
+    username: String,
+    created_at: DateTime<Utc>,
+}
+
+impl From<User> for Bot {
+    fn from(user: User) -> Self {
+        Self {
+            user_id: user.user_id,
+            username: user.username,
+            created_at: user.created_at,
+        }
+    }
+}
+
+/// A bot along with the token it authenticates with, shown only once
+#[derive(Serialize)]
+#[serde(crate = "rocket::serde")]
+struct BotCredentials {
+    bot: Bot,
+    token: String,
+}
+
+enum BotError {
+    SqlxError(sqlx::Error),
+    InvalidName,
+    NotServerOwner,
+    NoBot,
+}
+
+impl<'r, 'o: 'r> Responder<'r, 'o> for BotError {
+    fn respond_to(self, _request: &'r rocket::Request<'_>) -> rocket::response::Result<'o> {
+        match self {
+            Self::SqlxError(e) => Ok(quick_response(Status::InternalServerError, e.to_string())),
+            Self::InvalidName => Ok(quick_response(
+                Status::BadRequest,
+                format!("Bot names must be between 1 and {MAX_NAME_LENGTH} characters long"),
+            )),
+            Self::NotServerOwner => Ok(quick_response(
+                Status::Forbidden,
+                "Only server owners can create bots",
+            )),
+            Self::NoBot => Ok(quick_response(
+                Status::NotFound,
+                "You have no bot with this id",
+            )),
+        }
+    }
+}
+
+impl From<sqlx::Error> for BotError {
+    fn from(e: sqlx::Error) -> Self {
+        Self::SqlxError(e)
+    }
+}
+
+fn validate_name(name: &str) -> Result<&str, BotError> {
+    let name = name.trim();
+    if name.is_empty() || name.chars().count() > MAX_NAME_LENGTH {
+        return Err(BotError::InvalidName);
+    }
+    Ok(name)
+}
+
+/// Hands out a token for the bot that may do everything the bot is allowed to
+async fn issue_token(state: &MyState, bot: &User) -> Result<String, BotError> {
+    let (token, secret) = ApiToken::new(bot, "Bot token", Scopes::all(), None);
+    token.save(&state.conn).await?;
+    Ok(secret)
+}
+
+async fn owned_bot(state: &MyState, owner: &User, bot_id: Uuid) -> Result<User, BotError> {
+    User::filter_by_id(&state.conn, bot_id)
+        .await?
+        .filter(|bot| bot.bot && bot.bot_owner_id == Some(owner.user_id))
+        .ok_or(BotError::NoBot)
+}
+
+#[get("/list")]
+async fn list_bots(state: &State<MyState>, login: LoginGuard) -> Result<Json<Vec<Bot>>, BotError> {
+    let bots = login.user.bots(&state.conn).await?;
+    Ok(Json(bots.into_iter().map(Bot::from).collect()))
+}
+
+/// Creates a bot account owned by the user, who has to own a server.
+/// It joins servers through `/server/bot/add`.
+#[post("/new", data = "<data>")]
+async fn create_bot(
+    state: &State<MyState>,
+    login: LoginGuard,
+    data: Json<CreateBotData<'_>>,
+) -> Result<Json<BotCredentials>, BotError> {
+    let memberships = login.user.memberships(&state.conn).await?;
+    if !memberships.iter().any(|membership| membership.owner) {
+        return Err(BotError::NotServerOwner);
+    }
+    let bot = User::new_bot(validate_name(data.name)?, &login.user);
+    bot.save(&state.conn).await?;
+
+    let token = issue_token(state, &bot).await?;
+    Ok(Json(BotCredentials {
+        bot: bot.into(),
+        token,
+    }))
+}
+
+/// Replaces the bot's tokens with a new one, for when the old one leaked
+#[post("/token/reset", data = "<data>")]
+async fn reset_token(
+    state: &State<MyState>,
+    login: LoginGuard,
+    data: Json<BotIdData>,
+) -> Result<Json<BotCredentials>, BotError> {
+    let bot = owned_bot(state, &login.user, data.bot_id).await?;
+
+    for token_id in ApiToken::revoke_all(&state.conn, bot.user_id).await? {
+        events::revoke(state, Revocation::ApiToken { token_id });
+    }
+    let token = issue_token(state, &bot).await?;
+    Ok(Json(BotCredentials {
+        bot: bot.into(),
+        token,
+    }))
+}
+
+pub fn routes() -> Vec<rocket::Route> {
+    routes![list_bots, create_bot, reset_token]
+}
@@ -0,0 +1,553 @@
+use crate::{
+    events::{self, Revocation, ServerEvent},
+    guards::LoginGuard,
+    quick_response, MyState,
+};
+use rocket::{
+    http::Status,
+    serde::{json::Json, uuid::Uuid, Deserialize},
+    tokio::sync::broadcast,
+    State,
+};
+use spook_chat_db::models::{
+    AuditAction, AuditEntry, Channel, Overwrite, Permissions, Role, Server, User,
+};
+
+const MAX_NAME_LENGTH: usize = 30;
+const MAX_TOPIC_LENGTH: usize = 1024;
+
+#[derive(Deserialize)]
+#[serde(crate = "rocket::serde")]
+struct NewChannelData<'a> {
+    server_id: Uuid,
+    name: &'a str,
+    topic: Option<&'a str>,
+}
+
+#[derive(Deserialize)]
+#[serde(crate = "rocket::serde")]
+struct RenameChannelData<'a> {
+    channel_id: Uuid,
+    name: &'a str,
+}
+
+#[derive(Deserialize)]
+#[serde(crate = "rocket::serde")]
+struct ChannelTopicData<'a> {
+    channel_id: Uuid,
+    topic: Option<&'a str>,
+}
+
+#[derive(Deserialize)]
+#[serde(crate = "rocket::serde")]
+struct ReorderChannelsData {
+    server_id: Uuid,
+    channel_ids: Vec<Uuid>,
+}
+
+#[derive(Deserialize)]
+#[serde(crate = "rocket::serde")]
+struct DeleteChannelData {
+    channel_id: Uuid,
+}
+
+#[derive(Deserialize)]
+#[serde(crate = "rocket::serde")]
+struct OverwriteData {
+    channel_id: Uuid,
+    role_id: Option<Uuid>,
+    user_id: Option<Uuid>,
+    #[serde(default)]
+    allow: Permissions,
+    #[serde(default)]
+    deny: Permissions,
+}
+
+#[derive(Deserialize)]
+#[serde(crate = "rocket::serde")]
+struct DeleteOverwriteData {
+    channel_id: Uuid,
+    /// The role or member the overwrite was set for
+    target_id: Uuid,
+}
+
+/// Who an overwrite is set for
+#[derive(Clone, Copy)]
+enum OverwriteTarget {
+    Role(Uuid),
+    Member(Uuid),
+}
+
+enum ChannelError {
+    SqlxError(sqlx::Error),
+    MissingPermissions,
+    NoEntry,
+    ChannelNoExist(Uuid),
+    InvalidName,
+    InvalidTopic,
+    InvalidOrder,
+    InvalidOverwrite(&'static str),
+    Outranked,
+}
+
+impl From<sqlx::Error> for ChannelError {
+    fn from(e: sqlx::Error) -> Self {
+        Self::SqlxError(e)
+    }
+}
+
+impl<'r, 'o: 'r> rocket::response::Responder<'r, 'o> for ChannelError {
+    fn respond_to(self, _: &'r rocket::Request<'_>) -> rocket::response::Result<'o> {
+        match self {
+            ChannelError::SqlxError(e) => Ok(quick_response(
+                Status::InternalServerError,
+                e.to_string().as_str(),
+            )),
+            ChannelError::MissingPermissions => Ok(quick_response(
+                Status::Forbidden,
+                "You are missing permissions required to perform this action",
+            )),
+            ChannelError::NoEntry => Ok(quick_response(
+                Status::Forbidden,
+                "You do not appear to be part of this server",
+            )),
+            ChannelError::ChannelNoExist(id) => Ok(quick_response(
+                Status::BadRequest,
+                format!("Channel with the id {id} does not exist"),
+            )),
+            ChannelError::InvalidName => Ok(quick_response(
+                Status::BadRequest,
+                format!("Channel names must be between 1 and {MAX_NAME_LENGTH} characters long"),
+            )),
+            ChannelError::InvalidTopic => Ok(quick_response(
+                Status::BadRequest,
+                format!("Channel topics can be at most {MAX_TOPIC_LENGTH} characters long"),
+            )),
+            ChannelError::InvalidOrder => Ok(quick_response(
+                Status::BadRequest,
+                "The new order has to contain every channel of the server exactly once",
+            )),
+            ChannelError::InvalidOverwrite(reason) => {
+                Ok(quick_response(Status::BadRequest, reason))
+            }
+            ChannelError::Outranked => Ok(quick_response(
+                Status::Forbidden,
+                "You can only set overwrites for roles and members ranked below you",
+            )),
+        }
+    }
+}
+
+fn validate_name(name: &str) -> Result<&str, ChannelError> {
+    let name = name.trim();
+    if name.is_empty() || name.chars().count() > MAX_NAME_LENGTH {
+        return Err(ChannelError::InvalidName);
+    }
+    Ok(name)
+}
+
+fn validate_topic(topic: Option<&str>) -> Result<Option<&str>, ChannelError> {
+    match topic.map(str::trim) {
+        Some(topic) if topic.chars().count() > MAX_TOPIC_LENGTH => Err(ChannelError::InvalidTopic),
+        Some("") | None => Ok(None),
+        topic => Ok(topic),
+    }
+}
+
+/// Fetches the server and makes sure the user is allowed to manage its channels
+async fn managed_server(
+    state: &MyState,
+    user: &User,
+    server_id: Uuid,
+) -> Result<(Server, Permissions), ChannelError> {
+    let server = Server::filter_by_id(&state.conn, server_id).await?;
+
+    let permissions = server
+        .get_permissions(&state.conn, user)
+        .await?
+        .ok_or(ChannelError::NoEntry)?;
+
+    if permissions.contains(Permissions::MANAGE_CHANNELS) {
+        Ok((server, permissions))
+    } else {
+        Err(ChannelError::MissingPermissions)
+    }
+}
+
+/// Fetches the channel and makes sure the user is allowed to manage it
+async fn managed_channel(
+    state: &MyState,
+    user: &User,
+    channel_id: Uuid,
+) -> Result<(Channel, Server, Permissions), ChannelError> {
+    let channel = Channel::filter_by_id(&state.conn, channel_id)
+        .await?
+        .ok_or(ChannelError::ChannelNoExist(channel_id))?;
+    let (server, permissions) = managed_server(state, user, channel.server_id).await?;
+
+    Ok((channel, server, permissions))
+}
+
+/// Makes sure the overwrite target is a role or member of the server ranked below the user
+async fn check_overwrite_target(
+    state: &MyState,
+    user: &User,
+    server: &Server,
+    target: OverwriteTarget,
+) -> Result<(), ChannelError> {
+    match target {
+        OverwriteTarget::Role(role_id) => {
+            let role = Role::filter_by_id(&state.conn, role_id)
+                .await?
+                .filter(|role| role.server_id == server.server_id)
+                .ok_or(ChannelError::InvalidOverwrite(
+                    "This role does not belong to the server",
+                ))?;
+            let rank = server
+                .rank(&state.conn, user.user_id)
+                .await?
+                .ok_or(ChannelError::NoEntry)?;
+            if role.position >= rank {
+                return Err(ChannelError::Outranked);
+            }
+        }
+        OverwriteTarget::Member(user_id) => {
+            if server.rank(&state.conn, user_id).await?.is_none() {
+                return Err(ChannelError::InvalidOverwrite(
+                    "This user is not a member of the server",
+                ));
+            }
+            if user_id != user.user_id && !server.outranks(&state.conn, user, user_id).await? {
+                return Err(ChannelError::Outranked);
+            }
+        }
+    }
+
+    Ok(())
+}
+
+#[get("/list?<server>")]
+async fn list_channels(
+    state: &State<MyState>,
+    login: LoginGuard,
+    server: Uuid,
+) -> Result<Json<Vec<Channel>>, ChannelError> {
+    if !login.user.has_access_to_server(&state.conn, server).await? {
+        return Err(ChannelError::NoEntry);
+    }
+
+    let channels = Channel::filter_by_server_id(&state.conn, server).await?;
+    let mut readable = Vec::with_capacity(channels.len());
+    for channel in channels {
+        if channel
+            .permissions_for(&state.conn, &login.user)
+            .await?
+            .is_some_and(|permissions| permissions.contains(Permissions::READ_MESSAGES))
+        {
+            readable.push(channel);
+        }
+    }
+
+    Ok(Json(readable))
+}
+
+#[post("/new", data = "<data>")]
+async fn create_channel(
+    state: &State<MyState>,
+    login: LoginGuard,
+    data: Json<NewChannelData<'_>>,
+) -> Result<Json<Channel>, ChannelError> {
+    let (server, _) = managed_server(state, &login.user, data.server_id).await?;
+
+    let mut channel = Channel::new(validate_name(data.name)?, &server);
+    channel.topic = validate_topic(data.topic)?.map(str::to_string);
+    server.add_channel(&state.conn, &mut channel).await?;
+    let entry = AuditEntry::new(
+        server.server_id,
+        login.user.user_id,
+        AuditAction::ChannelCreated,
+        Some(channel.channel_id),
+    )
+    .with_after(&channel);
+    state.audit(entry).await;
+
+    let (tx, _) = broadcast::channel::<String>(15);
+    state.channels.write().await.insert(channel.channel_id, tx);
+
+    events::emit(
+        state,
+        server.server_id,
+        ServerEvent::ChannelCreated { channel: &channel },
+    )
+    .await;
+
+    Ok(Json(channel))
+}
+
+#[post("/rename", data = "<data>")]
+async fn rename_channel(
+    state: &State<MyState>,
+    login: LoginGuard,
+    data: Json<RenameChannelData<'_>>,
+) -> Result<Json<Channel>, ChannelError> {
+    let (mut channel, _, _) = managed_channel(state, &login.user, data.channel_id).await?;
+    let entry = AuditEntry::new(
+        channel.server_id,
+        login.user.user_id,
+        AuditAction::ChannelUpdated,
+        Some(channel.channel_id),
+    )
+    .with_before(&channel);
+    channel
+        .rename(&state.conn, validate_name(data.name)?)
+        .await?;
+    state.audit(entry.with_after(&channel)).await;
+
+    events::emit(
+        state,
+        channel.server_id,
+        ServerEvent::ChannelUpdated { channel: &channel },
+    )
+    .await;
+
+    Ok(Json(channel))
+}
+
+#[post("/topic", data = "<data>")]
+async fn set_channel_topic(
+    state: &State<MyState>,
+    login: LoginGuard,
+    data: Json<ChannelTopicData<'_>>,
+) -> Result<Json<Channel>, ChannelError> {
+    let (mut channel, _, _) = managed_channel(state, &login.user, data.channel_id).await?;
+    let entry = AuditEntry::new(
+        channel.server_id,
+        login.user.user_id,
+        AuditAction::ChannelUpdated,
+        Some(channel.channel_id),
+    )
+    .with_before(&channel);
+    channel
+        .set_topic(&state.conn, validate_topic(data.topic)?)
+        .await?;
+    state.audit(entry.with_after(&channel)).await;
+
+    events::emit(
+        state,
+        channel.server_id,
+        ServerEvent::ChannelUpdated { channel: &channel },
+    )
+    .await;
+
+    Ok(Json(channel))
+}
+
+#[post("/reorder", data = "<data>")]
+async fn reorder_channels(
+    state: &State<MyState>,
+    login: LoginGuard,
+    data: Json<ReorderChannelsData>,
+) -> Result<Status, ChannelError> {
+    let (server, _) = managed_server(state, &login.user, data.server_id).await?;
+
+    match server
+        .reorder_channels(&state.conn, &data.channel_ids)
+        .await
+    {
+        Err(sqlx::Error::RowNotFound) => return Err(ChannelError::InvalidOrder),
+        result => result?,
+    }
+    let entry = AuditEntry::new(
+        server.server_id,
+        login.user.user_id,
+        AuditAction::ChannelsReordered,
+        None,
+    )
+    .with_after(&data.channel_ids);
+    state.audit(entry).await;
+
+    events::emit(
+        state,
+        server.server_id,
+        ServerEvent::ChannelsReordered {
+            channel_ids: &data.channel_ids,
+        },
+    )
+    .await;
+
+    Ok(Status::Ok)
+}
+
+#[post("/delete", data = "<data>")]
+async fn delete_channel(
+    state: &State<MyState>,
+    login: LoginGuard,
+    data: Json<DeleteChannelData>,
+) -> Result<Status, ChannelError> {
+    let (channel, _, _) = managed_channel(state, &login.user, data.channel_id).await?;
+    channel.delete(&state.conn).await?;
+    let entry = AuditEntry::new(
+        channel.server_id,
+        login.user.user_id,
+        AuditAction::ChannelDeleted,
+        Some(channel.channel_id),
+    )
+    .with_before(&channel);
+    state.audit(entry).await;
+
+    // Dropping the sender closes the streams of everyone still subscribed
+    state.channels.write().await.remove(&channel.channel_id);
+
+    events::emit(
+        state,
+        channel.server_id,
+        ServerEvent::ChannelDeleted {
+            channel_id: channel.channel_id,
+        },
+    )
+    .await;
+
+    Ok(Status::Ok)
+}
+
+#[get("/overwrites?<channel>")]
+async fn list_overwrites(
+    state: &State<MyState>,
+    login: LoginGuard,
+    channel: Uuid,
+) -> Result<Json<Vec<Overwrite>>, ChannelError> {
+    let (channel, _, _) = managed_channel(state, &login.user, channel).await?;
+
+    Ok(Json(
+        Overwrite::filter_by_channel_id(&state.conn, channel.channel_id).await?,
+    ))
+}
+
+#[post("/overwrite", data = "<data>")]
+async fn set_overwrite(
+    state: &State<MyState>,
+    login: LoginGuard,
+    data: Json<OverwriteData>,
+) -> Result<Json<Overwrite>, ChannelError> {
+    let (channel, server, permissions) =
+        managed_channel(state, &login.user, data.channel_id).await?;
+
+    // Nobody can allow or deny permissions they don't hold themselves
+    if !permissions.contains(data.allow | data.deny) {
+        return Err(ChannelError::MissingPermissions);
+    }
+
+    let (overwrite, target) = match (data.role_id, data.user_id) {
+        (Some(role_id), None) => (
+            Overwrite::for_role(channel.channel_id, role_id, data.allow, data.deny),
+            OverwriteTarget::Role(role_id),
+        ),
+        (None, Some(user_id)) => (
+            Overwrite::for_member(channel.channel_id, user_id, data.allow, data.deny),
+            OverwriteTarget::Member(user_id),
+        ),
+        _ => {
+            return Err(ChannelError::InvalidOverwrite(
+                "An overwrite needs either a role_id or a user_id",
+            ))
+        }
+    };
+    check_overwrite_target(state, &login.user, &server, target).await?;
+    overwrite.save(&state.conn).await?;
+    let entry = AuditEntry::new(
+        server.server_id,
+        login.user.user_id,
+        AuditAction::OverwriteUpdated,
+        Some(channel.channel_id),
+    )
+    .with_after(&overwrite);
+    state.audit(entry).await;
+
+    events::revoke(
+        state,
+        Revocation::Permissions {
+            server_id: server.server_id,
+        },
+    );
+    events::emit(
+        state,
+        server.server_id,
+        ServerEvent::ChannelPermissionsUpdated {
+            channel_id: channel.channel_id,
+        },
+    )
+    .await;
+
+    Ok(Json(overwrite))
+}
+
+#[post("/overwrite/delete", data = "<data>")]
+async fn delete_overwrite(
+    state: &State<MyState>,
+    login: LoginGuard,
+    data: Json<DeleteOverwriteData>,
+) -> Result<Status, ChannelError> {
+    let (channel, server, _) = managed_channel(state, &login.user, data.channel_id).await?;
+    let overwrite = Overwrite::filter_by_channel_id(&state.conn, channel.channel_id)
+        .await?
+        .into_iter()
+        .find(|overwrite| {
+            overwrite.role_id == Some(data.target_id) || overwrite.user_id == Some(data.target_id)
+        })
+        .ok_or(ChannelError::InvalidOverwrite(
+            "No overwrite is set for this role or member",
+        ))?;
+    let target = match overwrite.role_id {
+        Some(role_id) => OverwriteTarget::Role(role_id),
+        None => OverwriteTarget::Member(data.target_id),
+    };
+    // Overwrites of members that left have nobody to outrank, anyone managing the channel
+    // may clean them up
+    let left = match target {
+        OverwriteTarget::Member(user_id) => server.rank(&state.conn, user_id).await?.is_none(),
+        OverwriteTarget::Role(_) => false,
+    };
+    if !left {
+        check_overwrite_target(state, &login.user, &server, target).await?;
+    }
+    Overwrite::delete(&state.conn, channel.channel_id, data.target_id).await?;
+    let entry = AuditEntry::new(
+        server.server_id,
+        login.user.user_id,
+        AuditAction::OverwriteDeleted,
+        Some(channel.channel_id),
+    )
+    .with_before(&data.target_id);
+    state.audit(entry).await;
+
+    events::revoke(
+        state,
+        Revocation::Permissions {
+            server_id: server.server_id,
+        },
+    );
+    events::emit(
+        state,
+        server.server_id,
+        ServerEvent::ChannelPermissionsUpdated {
+            channel_id: channel.channel_id,
+        },
+    )
+    .await;
+
+    Ok(Status::Ok)
+}
+
+pub fn routes() -> Vec<rocket::Route> {
+    routes![
+        list_channels,
+        create_channel,
+        rename_channel,
+        set_channel_topic,
+        reorder_channels,
+        delete_channel,
+        list_overwrites,
+        set_overwrite,
+        delete_overwrite
+    ]
+}
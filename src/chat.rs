@@ -1,11 +1,18 @@
-use crate::{guards::LoginGuard, quick_response, MyState};
+use crate::{
+    config::Restricted,
+    events::{ChatMessage, Revocation},
+    guards::ApiGuard,
+    quick_response, MyState,
+};
 use rocket::{
     http::Status,
     response::stream::{Event, EventStream},
     serde::{json::Json, uuid::Uuid, Deserialize},
-    tokio::sync::broadcast::error,
+    tokio::{select, sync::broadcast::error},
     State,
 };
+use spook_chat_db::models::{Channel, Permissions, Scopes, Server, User};
+use sqlx::types::chrono::{DateTime, Utc};
 
 #[derive(Deserialize)]
 #[serde(crate = "rocket::serde")]
@@ -19,6 +26,9 @@ enum ChatError {
     SendError(error::SendError<String>),
     MissingPermission,
     NoChannelFound,
+    TimedOut(DateTime<Utc>),
+    Unverified,
+    MissingScope(Scopes),
 }
 
 impl From<sqlx::Error> for ChatError {
@@ -46,33 +56,103 @@ impl<'r, 'o: 'r> rocket::response::Responder<'r, 'o> for ChatError {
                 Status::BadRequest,
                 "This channel does not exist",
             )),
+            Self::TimedOut(until) => Ok(quick_response(
+                Status::Forbidden,
+                format!("You are timed out on this server until {until}"),
+            )),
+            Self::Unverified => Ok(quick_response(
+                Status::Forbidden,
+                "Verify your E-Mail address before sending messages",
+            )),
+            Self::MissingScope(scope) => Ok(quick_response(
+                Status::Forbidden,
+                format!("This token lacks the {} scope", scope.name()),
+            )),
         }
     }
 }
 
+/// Looks up the server of the channel and the user's permissions in it.
+/// Channels the user can't see are reported as missing permissions.
+async fn channel_permissions(
+    state: &MyState,
+    user: &User,
+    channel_id: Uuid,
+) -> Result<(Uuid, Permissions), ChatError> {
+    let channel = Channel::filter_by_id(&state.conn, channel_id)
+        .await?
+        .ok_or(ChatError::MissingPermission)?;
+    let permissions = channel
+        .permissions_for(&state.conn, user)
+        .await?
+        .ok_or(ChatError::MissingPermission)?;
+
+    Ok((channel.server_id, permissions))
+}
+
 #[get("/subscribe?<channel>")]
 async fn subscribe(
     state: &State<MyState>,
-    login: LoginGuard,
+    login: ApiGuard,
     channel: Uuid,
 ) -> Result<EventStream![], ChatError> {
-    if !login
-        .user
-        .has_access_to_channel(&state.conn, channel)
-        .await?
-    {
+    if !login.allows(Scopes::READ_MESSAGES) {
+        return Err(ChatError::MissingScope(Scopes::READ_MESSAGES));
+    }
+    let (server_id, permissions) = channel_permissions(state, &login.user, channel).await?;
+    if !permissions.contains(Permissions::READ_MESSAGES) {
         return Err(ChatError::MissingPermission);
-    };
+    }
+    let user = login.user;
+    let credential = login.credential;
+    let conn = state.conn.clone();
 
-    let tx = state
+    let mut rx = state
         .channels
+        .read()
+        .await
         .get(&channel)
-        .ok_or(ChatError::NoChannelFound)?;
+        .ok_or(ChatError::NoChannelFound)?
+        .subscribe();
+    let mut revocations = state.revocations.subscribe();
 
-    let mut rx = tx.subscribe();
     let event_stream = EventStream! { loop {
-        if let Ok(msg) = rx.recv().await {
-            yield Event::data(msg).event("message");
+        // Revocations go first so a deleted server's final event wins over the closed channel
+        select! {
+            biased;
+            revocation = revocations.recv() => match revocation {
+                Ok(Revocation::ServerDeleted { server_id: deleted }) if deleted == server_id => {
+                    yield Event::data(deleted.to_string()).event("server_deleted");
+                    break;
+                }
+                Ok(Revocation::Permissions { server_id: changed }) if changed == server_id => {
+                    match user.has_access_to_channel(&conn, channel).await {
+                        Ok(true) => continue,
+                        _ => break,
+                    }
+                }
+                Ok(revocation)
+                    if revocation.applies_to(user.user_id, server_id)
+                        || revocation.ends_credential(user.user_id, credential) => break,
+                // Missed revocations may have ended the credential or the access
+                Err(error::RecvError::Lagged(_)) => {
+                    let allowed = match credential.is_valid(&conn).await {
+                        Ok(true) => user.has_access_to_channel(&conn, channel).await,
+                        other => other,
+                    };
+                    match allowed {
+                        Ok(true) => continue,
+                        _ => break,
+                    }
+                }
+                Err(error::RecvError::Closed) => break,
+                _ => continue,
+            },
+            msg = rx.recv() => match msg {
+                Ok(msg) => yield Event::data(msg).event("message"),
+                Err(error::RecvError::Lagged(_)) => continue,
+                Err(error::RecvError::Closed) => break,
+            },
         }
     }};
 
@@ -82,23 +162,54 @@ async fn subscribe(
 #[post("/send", data = "<message>")]
 async fn send(
     state: &State<MyState>,
-    login: LoginGuard,
+    login: ApiGuard,
     message: Json<MessageData<'_>>,
 ) -> Result<String, ChatError> {
-    if !login
+    if !login.allows(Scopes::SEND_MESSAGES) {
+        return Err(ChatError::MissingScope(Scopes::SEND_MESSAGES));
+    }
+    if !state
+        .verification
+        .allows(&login.user, Restricted::SendMessages)
+    {
+        return Err(ChatError::Unverified);
+    }
+    let (server_id, permissions) = channel_permissions(state, &login.user, message.channel).await?;
+    if !permissions.contains(Permissions::SEND_MESSAGES) {
+        return Err(ChatError::MissingPermission);
+    }
+    if let Some(until) = login
         .user
-        .has_access_to_channel(&state.conn, message.channel)
+        .timed_out_in_channel(&state.conn, message.channel)
         .await?
     {
-        return Err(ChatError::MissingPermission);
-    };
+        return Err(ChatError::TimedOut(until));
+    }
 
     let tx = state
         .channels
+        .read()
+        .await
         .get(&message.channel)
+        .cloned()
         .ok_or(ChatError::NoChannelFound)?;
 
-    tx.send(message.message.to_string())?;
+    let profile = Server::filter_by_id(&state.conn, server_id)
+        .await?
+        .profile(&state.conn, &login.user)
+        .await?
+        .ok_or(ChatError::MissingPermission)?;
+    let payload = ChatMessage {
+        message_id: Uuid::new_v4(),
+        author_id: Some(login.user.user_id),
+        display_name: Some(&profile.display_name),
+        bot: login.user.bot,
+        avatar: profile.avatar.as_deref(),
+        content: message.message,
+        created_at: Utc::now(),
+    };
+
+    tx.send(payload.to_json())?;
     return Ok("Message has been sent".to_string());
 }
 
@@ -0,0 +1,195 @@
+use chrono::Duration;
+use spook_chat_db::models::{MessagePolicy, SessionLifetime, User};
+use std::env;
+
+/// Session lifetimes, configured through the environment
+pub struct SessionConfig {
+    pub default: SessionLifetime,
+    /// Used for logins that ask to be remembered
+    pub remember: SessionLifetime,
+}
+
+impl SessionConfig {
+    pub fn from_env() -> Self {
+        Self {
+            default: SessionLifetime {
+                absolute: Duration::hours(var_or("SESSION_TIMEOUT_HOURS", 24)),
+                idle: Duration::minutes(var_or("SESSION_IDLE_TIMEOUT_MINUTES", 120)),
+            },
+            remember: SessionLifetime {
+                absolute: Duration::days(var_or("REMEMBER_ME_TIMEOUT_DAYS", 30)),
+                idle: Duration::days(var_or("REMEMBER_ME_IDLE_TIMEOUT_DAYS", 7)),
+            },
+        }
+    }
+
+    pub fn lifetime(&self, remember: bool) -> SessionLifetime {
+        if remember {
+            self.remember
+        } else {
+            self.default
+        }
+    }
+}
+
+/// How long the tokens sent out by email stay valid
+pub struct TokenConfig {
+    pub password_reset: Duration,
+    pub email_verification: Duration,
+}
+
+impl TokenConfig {
+    pub fn from_env() -> Self {
+        Self {
+            password_reset: Duration::minutes(var_or("PASSWORD_RESET_TIMEOUT_MINUTES", 30)),
+            email_verification: Duration::hours(var_or("EMAIL_VERIFICATION_TIMEOUT_HOURS", 48)),
+        }
+    }
+}
+
+/// How failed logins are slowed down and when accounts get locked
+#[derive(Clone, Copy)]
+pub struct LoginThrottleConfig {
+    /// Failures per account before each further one adds a doubling delay
+    pub account_backoff_after: i32,
+    /// The same per IP address, higher because addresses can be shared
+    pub ip_backoff_after: i32,
+    pub max_backoff: Duration,
+    /// Failures per account that lock it and alert its owner
+    pub lockout_after: i32,
+    pub lockout: Duration,
+    /// Failures are forgotten once none happened for this long
+    pub window: Duration,
+    /// Password reset requests per E-Mail address within `window`
+    pub reset_requests_per_address: i32,
+    /// The same per IP address
+    pub reset_requests_per_ip: i32,
+}
+
+impl LoginThrottleConfig {
+    pub fn from_env() -> Self {
+        Self {
+            account_backoff_after: var_or("LOGIN_BACKOFF_AFTER", 3) as i32,
+            ip_backoff_after: var_or("LOGIN_IP_BACKOFF_AFTER", 20) as i32,
+            max_backoff: Duration::seconds(var_or("LOGIN_MAX_BACKOFF_SECONDS", 300)),
+            lockout_after: var_or("LOGIN_LOCKOUT_AFTER", 10) as i32,
+            lockout: Duration::minutes(var_or("LOGIN_LOCKOUT_MINUTES", 30)),
+            window: Duration::minutes(var_or("LOGIN_FAILURE_WINDOW_MINUTES", 60)),
+            reset_requests_per_address: var_or("PASSWORD_RESET_REQUESTS_PER_ADDRESS", 3) as i32,
+            reset_requests_per_ip: var_or("PASSWORD_RESET_REQUESTS_PER_IP", 10) as i32,
+        }
+    }
+
+    /// The delay after `failures` failures in a row, one second for the first past `after`
+    /// and doubling from there
+    pub fn backoff(&self, failures: i32, after: i32) -> Option<Duration> {
+        let excess = failures - after;
+        if excess <= 0 {
+            return None;
+        }
+        let seconds = 1_i64 << (excess - 1).min(30);
+        Some(Duration::seconds(seconds).min(self.max_backoff))
+    }
+}
+
+/// Actions that can be held back until an account's E-Mail address is verified
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Restricted {
+    JoinServers,
+    SendMessages,
+}
+
+/// What unverified accounts may not do, configured through `VERIFICATION_REQUIRED_TO`
+/// as a comma separated list of "join_servers" and "send_messages"
+pub struct VerificationPolicy {
+    required_to: Vec<Restricted>,
+}
+
+impl VerificationPolicy {
+    pub fn from_env() -> Self {
+        let required_to = env::var("VERIFICATION_REQUIRED_TO")
+            .unwrap_or_else(|_| "join_servers".to_string())
+            .split(',')
+            .map(str::trim)
+            .filter(|action| !action.is_empty())
+            .map(|action| match action {
+                "join_servers" => Restricted::JoinServers,
+                "send_messages" => Restricted::SendMessages,
+                _ => panic!("VERIFICATION_REQUIRED_TO contains unknown action {action}"),
+            })
+            .collect();
+        Self { required_to }
+    }
+
+    pub fn allows(&self, user: &User, action: Restricted) -> bool {
+        user.is_verified() || !self.required_to.contains(&action)
+    }
+}
+
+/// What happens to the messages of deleted accounts, configured through
+/// `ACCOUNT_DELETION_MESSAGES` as "anonymize" or "delete"
+pub struct DeletionPolicy {
+    pub messages: MessagePolicy,
+}
+
+impl DeletionPolicy {
+    pub fn from_env() -> Self {
+        let messages = match env::var("ACCOUNT_DELETION_MESSAGES").as_deref() {
+            Ok("delete") => MessagePolicy::Delete,
+            Ok("anonymize") | Err(_) => MessagePolicy::Anonymize,
+            Ok(other) => panic!("ACCOUNT_DELETION_MESSAGES can't be {other}"),
+        };
+        Self { messages }
+    }
+}
+
+fn var_or(name: &str, default: i64) -> i64 {
+    match env::var(name) {
+        Ok(value) => value
+            .parse()
+            .unwrap_or_else(|_| panic!("{name} has to be a whole number")),
+        Err(_) => default,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn throttle_config() -> LoginThrottleConfig {
+        LoginThrottleConfig {
+            account_backoff_after: 3,
+            ip_backoff_after: 20,
+            max_backoff: Duration::seconds(300),
+            lockout_after: 10,
+            lockout: Duration::minutes(30),
+            window: Duration::minutes(60),
+            reset_requests_per_address: 3,
+            reset_requests_per_ip: 10,
+        }
+    }
+
+    #[test]
+    fn no_backoff_up_to_the_threshold() {
+        let config = throttle_config();
+        for failures in 0..=3 {
+            assert_eq!(config.backoff(failures, 3), None);
+        }
+    }
+
+    #[test]
+    fn backoff_doubles_past_the_threshold() {
+        let config = throttle_config();
+        assert_eq!(config.backoff(4, 3), Some(Duration::seconds(1)));
+        assert_eq!(config.backoff(5, 3), Some(Duration::seconds(2)));
+        assert_eq!(config.backoff(6, 3), Some(Duration::seconds(4)));
+        assert_eq!(config.backoff(11, 3), Some(Duration::seconds(128)));
+    }
+
+    #[test]
+    fn backoff_is_capped() {
+        let config = throttle_config();
+        assert_eq!(config.backoff(13, 3), Some(config.max_backoff));
+        assert_eq!(config.backoff(i32::MAX, 3), Some(config.max_backoff));
+    }
+}
@@ -0,0 +1,126 @@
+use crate::{guards::Credential, MyState};
+use rocket::serde::{json, uuid::Uuid, Serialize};
+use spook_chat_db::models::{Channel, Permissions, Profile, Role};
+use sqlx::types::chrono::{DateTime, Utc};
+
+/// Events pushed to every member subscribed to a server
+#[derive(Serialize)]
+#[serde(crate = "rocket::serde", tag = "type", rename_all = "snake_case")]
+pub enum ServerEvent<'a> {
+    ChannelCreated { channel: &'a Channel },
+    ChannelUpdated { channel: &'a Channel },
+    ChannelDeleted { channel_id: Uuid },
+    ChannelsReordered { channel_ids: &'a [Uuid] },
+    ChannelPermissionsUpdated { channel_id: Uuid },
+    RoleCreated { role: &'a Role },
+    RoleUpdated { role: &'a Role },
+    RoleDeleted { role_id: Uuid },
+    RoleAssigned { role_id: Uuid, user_id: Uuid },
+    RoleUnassigned { role_id: Uuid, user_id: Uuid },
+    OwnershipTransferred { user_id: Uuid },
+    MemberJoined { user_id: Uuid, username: &'a str },
+    MemberUpdated { user_id: Uuid, profile: &'a Profile },
+}
+
+/// Broadcasts an event to everyone subscribed to the server.
+/// Having no subscribers at the moment is not an error.
+pub async fn emit(state: &MyState, server_id: Uuid, event: ServerEvent<'_>) {
+    if let Ok(payload) = json::to_string(&event) {
+        let _ = state.server_events(server_id).await.send(payload);
+    }
+}
+
+/// A message as it is sent to everyone subscribed to a channel
+#[derive(Serialize)]
+#[serde(crate = "rocket::serde")]
+pub struct ChatMessage<'a> {
+    pub message_id: Uuid,
+    /// `None` for notices posted by the server itself
+    pub author_id: Option<Uuid>,
+    /// The author's display name on the server the channel belongs to
+    pub display_name: Option<&'a str>,
+    /// Whether the author is a bot account
+    pub bot: bool,
+    pub avatar: Option<&'a str>,
+    pub content: &'a str,
+    pub created_at: DateTime<Utc>,
+}
+
+impl ChatMessage<'_> {
+    pub fn to_json(&self) -> String {
+        json::to_string(self).unwrap_or_default()
+    }
+}
+
+/// Events pushed to every session of a single user
+#[derive(Serialize)]
+#[serde(crate = "rocket::serde", tag = "type", rename_all = "snake_case")]
+pub enum UserEvent {
+    PermissionsChanged {
+        server_id: Uuid,
+        permissions: Permissions,
+    },
+}
+
+/// Broadcasts an event to every session of the user that is subscribed
+pub async fn emit_to_user(state: &MyState, user_id: Uuid, event: UserEvent) {
+    if let Ok(payload) = json::to_string(&event) {
+        let _ = state.user_events(user_id).await.send(payload);
+    }
+}
+
+/// Tells open event streams that access was taken away from their owner
+#[derive(Clone, Copy)]
+pub enum Revocation {
+    /// The user may no longer see anything on the server, e.g. because they were banned
+    Membership { user_id: Uuid, server_id: Uuid },
+    /// Roles or overwrites on the server changed, so channel streams have to check
+    /// whether their owner can still read the channel
+    Permissions { server_id: Uuid },
+    /// The server was deleted, streams send a final event before closing
+    ServerDeleted { server_id: Uuid },
+    /// The session was logged out or revoked, every stream opened with it is closed
+    Session { session_id: Uuid },
+    /// Every session of the user but `except` was logged out at once
+    UserSessions { user_id: Uuid, except: Option<Uuid> },
+    /// The API token was revoked, every stream opened with it is closed
+    ApiToken { token_id: Uuid },
+}
+
+impl Revocation {
+    /// Whether a stream `user_id` opened on `server_id` has to be closed right away
+    pub fn applies_to(&self, user_id: Uuid, server_id: Uuid) -> bool {
+        match *self {
+            Self::Membership {
+                user_id: revoked_user,
+                server_id: revoked_server,
+            } => revoked_user == user_id && revoked_server == server_id,
+            Self::Permissions { .. } => false,
+            Self::ServerDeleted {
+                server_id: deleted_server,
+            } => deleted_server == server_id,
+            Self::Session { .. } | Self::UserSessions { .. } | Self::ApiToken { .. } => false,
+        }
+    }
+
+    /// Whether a stream `user_id` opened with `credential` has to be closed right away
+    pub fn ends_credential(&self, user_id: Uuid, credential: Credential) -> bool {
+        match (*self, credential) {
+            (Self::Session { session_id }, Credential::Session(id)) => session_id == id,
+            (
+                Self::UserSessions {
+                    user_id: revoked_user,
+                    except,
+                },
+                Credential::Session(id),
+            ) => revoked_user == user_id && except != Some(id),
+            (Self::ApiToken { token_id }, Credential::ApiToken(id)) => token_id == id,
+            _ => false,
+        }
+    }
+}
+
+/// Closes every stream the revocation applies to, or makes them check their access
+pub fn revoke(state: &MyState, revocation: Revocation) {
+    let _ = state.revocations.send(revocation);
+}
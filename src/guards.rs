@@ -7,7 +7,8 @@ use rocket::{
     serde::uuid::Uuid,
     Request, State,
 };
-use spook_chat_db::models::{Session, User};
+use spook_chat_db::models::{ApiToken, Scopes, Session, User};
+use sqlx::PgPool;
 
 pub struct LoginGuard {
     pub user: User,
@@ -19,7 +20,8 @@ impl<'r> FromRequest<'r> for LoginGuard {
     type Error = sqlx::Error;
 
     async fn from_request(request: &'r Request<'_>) -> rocket::request::Outcome<Self, Self::Error> {
-        let conn = &request.guard::<&State<MyState>>().await.unwrap().conn;
+        let state = request.guard::<&State<MyState>>().await.unwrap();
+        let conn = &state.conn;
 
         if let Some(session_cookie) = request.cookies().get_private("session") {
             let session_id = Uuid::from_str(session_cookie.value()).unwrap();
@@ -35,7 +37,12 @@ impl<'r> FromRequest<'r> for LoginGuard {
             };
 
             if let Some(user) = user {
-                if let Some(session) = session {
+                if let Some(mut session) = session {
+                    // Every authenticated request keeps the session from idling out
+                    let idle = state.sessions.lifetime(session.remember).idle;
+                    if let Err(e) = session.renew(conn, idle).await {
+                        return Outcome::Failure((Status::InternalServerError, e));
+                    }
                     return Outcome::Success(LoginGuard { user, session });
                 }
             }
@@ -43,3 +50,94 @@ impl<'r> FromRequest<'r> for LoginGuard {
         Outcome::Failure((Status::Unauthorized, sqlx::Error::RowNotFound))
     }
 }
+
+/// The session or API token a request was authenticated with
+#[derive(Clone, Copy)]
+pub enum Credential {
+    Session(Uuid),
+    ApiToken(Uuid),
+}
+
+impl Credential {
+    /// Whether the credential still exists and hasn't expired. Streams that
+    /// fell behind on revocations use this to find out if they missed theirs.
+    pub async fn is_valid(self, pool: &PgPool) -> sqlx::Result<bool> {
+        match self {
+            Self::Session(session_id) => {
+                Ok(Session::filter_by_id(pool, session_id).await?.is_some())
+            }
+            Self::ApiToken(token_id) => ApiToken::is_active(pool, token_id).await,
+        }
+    }
+}
+
+/// Authenticates browsers through the session cookie like `LoginGuard`, and other
+/// clients through an API token in an `Authorization: Bearer` header.
+/// Sessions may do everything, tokens only what their scopes allow.
+pub struct ApiGuard {
+    pub user: User,
+    pub scopes: Scopes,
+    /// The session or token used, its streams are closed once it's revoked
+    pub credential: Credential,
+}
+
+impl ApiGuard {
+    pub fn allows(&self, scopes: Scopes) -> bool {
+        self.scopes.contains(scopes)
+    }
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for ApiGuard {
+    type Error = sqlx::Error;
+
+    async fn from_request(request: &'r Request<'_>) -> rocket::request::Outcome<Self, Self::Error> {
+        let secret = match request.headers().get_one("Authorization") {
+            Some(header) => match header.strip_prefix("Bearer ") {
+                Some(secret) => secret.trim(),
+                None => return Outcome::Failure((Status::Unauthorized, sqlx::Error::RowNotFound)),
+            },
+            None => {
+                return request.guard::<LoginGuard>().await.map(|login| ApiGuard {
+                    credential: Credential::Session(login.session.session_id),
+                    user: login.user,
+                    scopes: Scopes::all(),
+                })
+            }
+        };
+
+        let state = request.guard::<&State<MyState>>().await.unwrap();
+        let token = match ApiToken::authenticate(&state.conn, secret).await {
+            Ok(Some(token)) => token,
+            Ok(None) => return Outcome::Failure((Status::Unauthorized, sqlx::Error::RowNotFound)),
+            Err(e) => return Outcome::Failure((Status::InternalServerError, e)),
+        };
+        match User::filter_by_id(&state.conn, token.user_id).await {
+            Ok(Some(user)) => Outcome::Success(ApiGuard {
+                user,
+                scopes: token.scopes,
+                credential: Credential::ApiToken(token.token_id),
+            }),
+            Ok(None) => Outcome::Failure((Status::Unauthorized, sqlx::Error::RowNotFound)),
+            Err(e) => Outcome::Failure((Status::InternalServerError, e)),
+        }
+    }
+}
+
+/// Where a request came from, recorded when a session is created
+pub struct ClientInfo {
+    pub user_agent: Option<String>,
+    pub ip_address: Option<String>,
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for ClientInfo {
+    type Error = std::convert::Infallible;
+
+    async fn from_request(request: &'r Request<'_>) -> rocket::request::Outcome<Self, Self::Error> {
+        Outcome::Success(ClientInfo {
+            user_agent: request.headers().get_one("User-Agent").map(str::to_string),
+            ip_address: request.client_ip().map(|ip| ip.to_string()),
+        })
+    }
+}
@@ -0,0 +1,195 @@
+use lettre::{message::Mailbox, AsyncSmtpTransport, AsyncTransport, Message, Tokio1Executor};
+use rocket::tokio::{fs::OpenOptions, io::AsyncWriteExt};
+use std::{env, fmt, path::PathBuf};
+
+pub enum MailError {
+    Address(lettre::address::AddressError),
+    Message(lettre::error::Error),
+    Smtp(lettre::transport::smtp::Error),
+    Io(std::io::Error),
+}
+
+impl fmt::Display for MailError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Address(e) => write!(f, "invalid address: {e}"),
+            Self::Message(e) => write!(f, "invalid message: {e}"),
+            Self::Smtp(e) => write!(f, "smtp: {e}"),
+            Self::Io(e) => write!(f, "io: {e}"),
+        }
+    }
+}
+
+impl From<lettre::address::AddressError> for MailError {
+    fn from(e: lettre::address::AddressError) -> Self {
+        Self::Address(e)
+    }
+}
+
+impl From<lettre::error::Error> for MailError {
+    fn from(e: lettre::error::Error) -> Self {
+        Self::Message(e)
+    }
+}
+
+impl From<lettre::transport::smtp::Error> for MailError {
+    fn from(e: lettre::transport::smtp::Error) -> Self {
+        Self::Smtp(e)
+    }
+}
+
+impl From<std::io::Error> for MailError {
+    fn from(e: std::io::Error) -> Self {
+        Self::Io(e)
+    }
+}
+
+/// A plain text email to a single recipient
+pub struct Email {
+    pub to: String,
+    pub subject: String,
+    pub body: String,
+}
+
+impl Email {
+    pub fn password_reset(to: &str, username: &str, link: &str) -> Self {
+        Self {
+            to: to.to_string(),
+            subject: "Reset your password".to_string(),
+            body: format!(
+                "Hi {username},\n\n\
+                someone asked to reset the password of your account. \
+                If that was you, use this to pick a new one:\n\n{link}\n\n\
+                If it wasn't, you can ignore this email."
+            ),
+        }
+    }
+
+    pub fn verification(to: &str, username: &str, link: &str) -> Self {
+        Self {
+            to: to.to_string(),
+            subject: "Verify your E-Mail address".to_string(),
+            body: format!(
+                "Hi {username},\n\n\
+                please confirm that this is your E-Mail address:\n\n{link}\n\n\
+                If you didn't sign up or change your address, you can ignore this email."
+            ),
+        }
+    }
+
+    pub fn lockout(to: &str, username: &str, failures: i32, ip_address: Option<&str>) -> Self {
+        let origin = match ip_address {
+            Some(ip_address) => format!(", the last one from {ip_address}"),
+            None => String::new(),
+        };
+        Self {
+            to: to.to_string(),
+            subject: "Your account was locked".to_string(),
+            body: format!(
+                "Hi {username},\n\n\
+                there were {failures} failed attempts to log in to your account{origin}, \
+                so logging in is blocked for a while.\n\n\
+                If that wasn't you, someone may be guessing your password. \
+                Consider changing it and turning on two-factor authentication."
+            ),
+        }
+    }
+}
+
+/// Where emails end up
+#[rocket::async_trait]
+pub trait Transport: Send + Sync {
+    async fn send(&self, from: &Mailbox, email: &Email) -> Result<(), MailError>;
+}
+
+pub struct SmtpTransport(AsyncSmtpTransport<Tokio1Executor>);
+
+#[rocket::async_trait]
+impl Transport for SmtpTransport {
+    async fn send(&self, from: &Mailbox, email: &Email) -> Result<(), MailError> {
+        let message = Message::builder()
+            .from(from.clone())
+            .to(email.to.parse()?)
+            .subject(email.subject.as_str())
+            .body(email.body.clone())?;
+        self.0.send(message).await?;
+        Ok(())
+    }
+}
+
+/// Writes emails to a file, or to stdout without one, for local development
+pub struct FileTransport(Option<PathBuf>);
+
+#[rocket::async_trait]
+impl Transport for FileTransport {
+    async fn send(&self, from: &Mailbox, email: &Email) -> Result<(), MailError> {
+        let text = format!(
+            "From: {from}\nTo: {}\nSubject: {}\n\n{}\n\n",
+            email.to, email.subject, email.body
+        );
+        match &self.0 {
+            Some(path) => {
+                let mut file = OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(path)
+                    .await?;
+                file.write_all(text.as_bytes()).await?;
+            }
+            None => print!("{text}"),
+        }
+        Ok(())
+    }
+}
+
+pub struct Mailer {
+    transport: Box<dyn Transport>,
+    from: Mailbox,
+    /// Where the frontend is served, used to turn tokens into links
+    public_url: Option<String>,
+}
+
+impl Mailer {
+    /// Sets up the transport picked by `MAIL_TRANSPORT`: "smtp" sends through `SMTP_URL`,
+    /// "file" appends to `MAIL_FILE` and anything else prints to stdout
+    pub fn from_env() -> Self {
+        let transport: Box<dyn Transport> = match env::var("MAIL_TRANSPORT").as_deref() {
+            Ok("smtp") => {
+                let url = env::var("SMTP_URL").expect("SMTP_URL has to be set to send over SMTP");
+                let transport = AsyncSmtpTransport::<Tokio1Executor>::from_url(&url)
+                    .unwrap_or_else(|e| panic!("SMTP_URL is invalid: {e}"))
+                    .build();
+                Box::new(SmtpTransport(transport))
+            }
+            Ok("file") => Box::new(FileTransport(Some(
+                env::var("MAIL_FILE")
+                    .unwrap_or_else(|_| "mail.log".to_string())
+                    .into(),
+            ))),
+            _ => Box::new(FileTransport(None)),
+        };
+
+        let from =
+            env::var("MAIL_FROM").unwrap_or_else(|_| "Spook Chat <noreply@localhost>".to_string());
+        Self {
+            transport,
+            from: from
+                .parse()
+                .unwrap_or_else(|e| panic!("MAIL_FROM is invalid: {e}")),
+            public_url: env::var("PUBLIC_URL").ok(),
+        }
+    }
+
+    pub async fn send(&self, email: Email) -> Result<(), MailError> {
+        self.transport.send(&self.from, &email).await
+    }
+
+    /// A link to `path` on the frontend carrying the token, or just the token
+    /// if `PUBLIC_URL` isn't set
+    pub fn link(&self, path: &str, token: &str) -> String {
+        match &self.public_url {
+            Some(url) => format!("{}/{path}?token={token}", url.trim_end_matches('/')),
+            None => token.to_string(),
+        }
+    }
+}
@@ -1,16 +1,32 @@
-use rocket::tokio::sync::broadcast::{channel, Sender};
+use rocket::tokio::sync::{
+    broadcast::{channel, Sender},
+    RwLock,
+};
 use rocket_cors::CorsOptions;
-use spook_chat_db::models::Channel;
+use spook_chat_db::models::{AuditEntry, Channel};
 use sqlx::{
     postgres::{PgPool, PgPoolOptions},
     types::Uuid,
 };
-use std::collections::HashMap;
+use std::{collections::HashMap, sync::Arc};
 
+mod account;
+mod api_tokens;
 mod auth;
+mod bots;
+mod channels;
 mod chat;
+mod config;
+mod events;
 mod guards;
+mod mail;
+mod roles;
 mod servers;
+mod tasks;
+mod throttle;
+mod two_factor;
+mod users;
+mod validation;
 
 pub fn quick_response<'a, S: Into<String>>(
     status: rocket::http::Status,
@@ -28,7 +44,49 @@ extern crate rocket;
 
 struct MyState {
     conn: PgPool,
-    channels: HashMap<Uuid, Sender<String>>,
+    channels: RwLock<HashMap<Uuid, Sender<String>>>,
+    servers: RwLock<HashMap<Uuid, Sender<String>>>,
+    users: RwLock<HashMap<Uuid, Sender<String>>>,
+    revocations: Sender<events::Revocation>,
+    sessions: config::SessionConfig,
+    tokens: config::TokenConfig,
+    verification: config::VerificationPolicy,
+    login_throttle: config::LoginThrottleConfig,
+    deletion: config::DeletionPolicy,
+    mailer: Arc<mail::Mailer>,
+}
+
+/// Returns the broadcaster stored under `id`, creating it on first use
+async fn broadcaster(map: &RwLock<HashMap<Uuid, Sender<String>>>, id: Uuid) -> Sender<String> {
+    if let Some(tx) = map.read().await.get(&id) {
+        return tx.clone();
+    }
+
+    map.write()
+        .await
+        .entry(id)
+        .or_insert_with(|| channel::<String>(15).0)
+        .clone()
+}
+
+impl MyState {
+    /// Returns the broadcaster for server-level events
+    async fn server_events(&self, server_id: Uuid) -> Sender<String> {
+        broadcaster(&self.servers, server_id).await
+    }
+
+    /// Returns the broadcaster for events addressed to every session of a user
+    async fn user_events(&self, user_id: Uuid) -> Sender<String> {
+        broadcaster(&self.users, user_id).await
+    }
+
+    /// Writes an audit log entry. The action it describes already happened,
+    /// so a failure is only logged rather than failing the request.
+    async fn audit(&self, entry: AuditEntry) {
+        if let Err(e) = entry.save(&self.conn).await {
+            eprintln!("Failed to write audit log entry: {e}");
+        }
+    }
 }
 
 #[launch]
@@ -60,10 +118,38 @@ async fn rocket() -> _ {
         channels.insert(channel_id, tx);
     }
 
+    rocket::tokio::spawn(tasks::lift_expired_bans(conn.clone()));
+    rocket::tokio::spawn(tasks::purge_expired_sessions(conn.clone()));
+    rocket::tokio::spawn(tasks::purge_expired_tokens(conn.clone()));
+    let login_throttle = config::LoginThrottleConfig::from_env();
+    rocket::tokio::spawn(tasks::purge_stale_throttles(
+        conn.clone(),
+        login_throttle.window,
+    ));
+
     rocket::build()
+        .mount("/account", account::routes())
         .mount("/auth", auth::routes())
+        .mount("/auth/2fa", two_factor::routes())
+        .mount("/auth/tokens", api_tokens::routes())
+        .mount("/bot", bots::routes())
+        .mount("/channel", channels::routes())
         .mount("/chat", chat::routes())
+        .mount("/role", roles::routes())
         .mount("/server", servers::routes())
-        .manage(MyState { conn, channels })
+        .mount("/user", users::routes())
+        .manage(MyState {
+            conn,
+            channels: RwLock::new(channels),
+            servers: RwLock::new(HashMap::new()),
+            users: RwLock::new(HashMap::new()),
+            revocations: channel(64).0,
+            sessions: config::SessionConfig::from_env(),
+            tokens: config::TokenConfig::from_env(),
+            verification: config::VerificationPolicy::from_env(),
+            login_throttle,
+            deletion: config::DeletionPolicy::from_env(),
+            mailer: Arc::new(mail::Mailer::from_env()),
+        })
         .attach(cors.to_cors().unwrap())
 }
@@ -0,0 +1,470 @@
+use crate::{
+    events::{self, Revocation, ServerEvent},
+    guards::LoginGuard,
+    quick_response, MyState,
+};
+use rocket::{
+    http::Status,
+    serde::{json::Json, uuid::Uuid, Deserialize},
+    State,
+};
+use spook_chat_db::models::{AuditAction, AuditEntry, Permissions, Role, Server, User};
+
+const MAX_NAME_LENGTH: usize = 30;
+const MAX_COLOUR: i32 = 0xFFFFFF;
+
+#[derive(Deserialize)]
+#[serde(crate = "rocket::serde")]
+struct NewRoleData<'a> {
+    server_id: Uuid,
+    name: &'a str,
+    colour: Option<i32>,
+    permissions: Permissions,
+    position: Option<i32>,
+}
+
+#[derive(Deserialize)]
+#[serde(crate = "rocket::serde")]
+struct EditRoleData<'a> {
+    role_id: Uuid,
+    name: Option<&'a str>,
+    colour: Option<i32>,
+    permissions: Option<Permissions>,
+    position: Option<i32>,
+}
+
+#[derive(Deserialize)]
+#[serde(crate = "rocket::serde")]
+struct DeleteRoleData {
+    role_id: Uuid,
+}
+
+#[derive(Deserialize)]
+#[serde(crate = "rocket::serde")]
+struct AssignRoleData {
+    role_id: Uuid,
+    user_id: Uuid,
+}
+
+enum RoleError {
+    SqlxError(sqlx::Error),
+    MissingPermissions,
+    NoEntry,
+    RoleNoExist(Uuid),
+    UserNoExist(Uuid),
+    Outranked,
+    InvalidRequest(&'static str),
+    InvalidName,
+}
+
+impl From<sqlx::Error> for RoleError {
+    fn from(e: sqlx::Error) -> Self {
+        Self::SqlxError(e)
+    }
+}
+
+impl<'r, 'o: 'r> rocket::response::Responder<'r, 'o> for RoleError {
+    fn respond_to(self, _: &'r rocket::Request<'_>) -> rocket::response::Result<'o> {
+        match self {
+            RoleError::SqlxError(e) => Ok(quick_response(
+                Status::InternalServerError,
+                e.to_string().as_str(),
+            )),
+            RoleError::MissingPermissions => Ok(quick_response(
+                Status::Forbidden,
+                "You are missing permissions required to perform this action",
+            )),
+            RoleError::NoEntry => Ok(quick_response(
+                Status::Forbidden,
+                "You do not appear to be part of this server",
+            )),
+            RoleError::RoleNoExist(id) => Ok(quick_response(
+                Status::BadRequest,
+                format!("Role with the id {id} does not exist"),
+            )),
+            RoleError::UserNoExist(id) => Ok(quick_response(
+                Status::BadRequest,
+                format!("User with the id {id} is not part of this server"),
+            )),
+            RoleError::Outranked => Ok(quick_response(
+                Status::Forbidden,
+                "You can only manage roles and members ranked below you",
+            )),
+            RoleError::InvalidRequest(reason) => Ok(quick_response(Status::BadRequest, reason)),
+            RoleError::InvalidName => Ok(quick_response(
+                Status::BadRequest,
+                format!("Role names must be between 1 and {MAX_NAME_LENGTH} characters long"),
+            )),
+        }
+    }
+}
+
+fn validate_name(name: &str) -> Result<&str, RoleError> {
+    let name = name.trim();
+    if name.is_empty() || name.chars().count() > MAX_NAME_LENGTH {
+        return Err(RoleError::InvalidName);
+    }
+    Ok(name)
+}
+
+fn validate_colour(colour: Option<i32>) -> Result<Option<i32>, RoleError> {
+    match colour {
+        Some(colour) if !(0..=MAX_COLOUR).contains(&colour) => Err(RoleError::InvalidRequest(
+            "Colours have to be given as a 24 bit RGB value",
+        )),
+        colour => Ok(colour),
+    }
+}
+
+/// A member allowed to manage the roles of a server, with their permissions and rank
+struct RoleManager {
+    server: Server,
+    permissions: Permissions,
+    rank: i32,
+}
+
+impl RoleManager {
+    async fn new(state: &MyState, user: &User, server_id: Uuid) -> Result<Self, RoleError> {
+        let server = Server::filter_by_id(&state.conn, server_id).await?;
+
+        let permissions = server
+            .get_permissions(&state.conn, user)
+            .await?
+            .ok_or(RoleError::NoEntry)?;
+        if !permissions.contains(Permissions::MANAGE_ROLES) {
+            return Err(RoleError::MissingPermissions);
+        }
+        let rank = server
+            .rank(&state.conn, user.user_id)
+            .await?
+            .ok_or(RoleError::NoEntry)?;
+
+        Ok(Self {
+            server,
+            permissions,
+            rank,
+        })
+    }
+
+    /// Roles can only be managed by members ranked above them
+    fn check_position(&self, position: i32) -> Result<(), RoleError> {
+        if position < self.rank {
+            Ok(())
+        } else {
+            Err(RoleError::Outranked)
+        }
+    }
+
+    /// Nobody can hand out permissions they don't hold themselves
+    fn check_permissions(&self, permissions: Permissions) -> Result<(), RoleError> {
+        if self.permissions.contains(permissions) {
+            Ok(())
+        } else {
+            Err(RoleError::MissingPermissions)
+        }
+    }
+}
+
+/// Fetches the role and makes sure the user is allowed to manage it
+async fn managed_role(
+    state: &MyState,
+    user: &User,
+    role_id: Uuid,
+) -> Result<(RoleManager, Role), RoleError> {
+    let role = Role::filter_by_id(&state.conn, role_id)
+        .await?
+        .ok_or(RoleError::RoleNoExist(role_id))?;
+    let manager = RoleManager::new(state, user, role.server_id).await?;
+    manager.check_position(role.position)?;
+
+    Ok((manager, role))
+}
+
+#[get("/list?<server>")]
+async fn list_roles(
+    state: &State<MyState>,
+    login: LoginGuard,
+    server: Uuid,
+) -> Result<Json<Vec<Role>>, RoleError> {
+    if !login.user.has_access_to_server(&state.conn, server).await? {
+        return Err(RoleError::NoEntry);
+    }
+
+    Ok(Json(Role::filter_by_server_id(&state.conn, server).await?))
+}
+
+#[post("/new", data = "<data>")]
+async fn create_role(
+    state: &State<MyState>,
+    login: LoginGuard,
+    data: Json<NewRoleData<'_>>,
+) -> Result<Json<Role>, RoleError> {
+    let manager = RoleManager::new(state, &login.user, data.server_id).await?;
+    manager.check_permissions(data.permissions)?;
+
+    let mut role = Role::new(
+        manager.server.server_id,
+        validate_name(data.name)?,
+        validate_colour(data.colour)?,
+        data.permissions,
+    );
+    if let Some(position) = data.position {
+        let roles = Role::filter_by_server_id(&state.conn, role.server_id).await?;
+        if position < 1 || position > roles.len() as i32 {
+            return Err(RoleError::InvalidRequest(
+                "Roles have to be placed above the everyone role and next to an existing one",
+            ));
+        }
+        role.position = position;
+    }
+    manager.check_position(role.position)?;
+    role.save(&state.conn).await?;
+    let entry = AuditEntry::new(
+        role.server_id,
+        login.user.user_id,
+        AuditAction::RoleCreated,
+        Some(role.role_id),
+    )
+    .with_after(&role);
+    state.audit(entry).await;
+
+    events::emit(
+        state,
+        role.server_id,
+        ServerEvent::RoleCreated { role: &role },
+    )
+    .await;
+
+    Ok(Json(role))
+}
+
+#[post("/edit", data = "<data>")]
+async fn edit_role(
+    state: &State<MyState>,
+    login: LoginGuard,
+    data: Json<EditRoleData<'_>>,
+) -> Result<Json<Role>, RoleError> {
+    let (manager, mut role) = managed_role(state, &login.user, data.role_id).await?;
+    let entry = AuditEntry::new(
+        role.server_id,
+        login.user.user_id,
+        AuditAction::RoleUpdated,
+        Some(role.role_id),
+    )
+    .with_before(&role);
+
+    if role.is_everyone() && (data.name.is_some() || data.position.is_some()) {
+        return Err(RoleError::InvalidRequest(
+            "The everyone role can't be renamed or moved",
+        ));
+    }
+    if let Some(name) = data.name {
+        role.name = validate_name(name)?.to_string();
+    }
+    if data.colour.is_some() {
+        role.colour = validate_colour(data.colour)?;
+    }
+    if let Some(permissions) = data.permissions {
+        // Permissions the manager lacks may stay on the role, but not be added or removed
+        let changed = role
+            .permissions
+            .union(permissions)
+            .difference(role.permissions.intersection(permissions));
+        manager.check_permissions(changed)?;
+        role.permissions = permissions;
+    }
+    if let Some(position) = data.position {
+        let roles = Role::filter_by_server_id(&state.conn, role.server_id).await?;
+        if position < 1 || position >= roles.len() as i32 {
+            return Err(RoleError::InvalidRequest(
+                "Roles have to be placed above the everyone role and next to an existing one",
+            ));
+        }
+        manager.check_position(position)?;
+    }
+
+    role.update(&state.conn).await?;
+    if let Some(position) = data.position {
+        role.move_to(&state.conn, position).await?;
+    }
+    state.audit(entry.with_after(&role)).await;
+
+    events::revoke(
+        state,
+        Revocation::Permissions {
+            server_id: role.server_id,
+        },
+    );
+    events::emit(
+        state,
+        role.server_id,
+        ServerEvent::RoleUpdated { role: &role },
+    )
+    .await;
+
+    Ok(Json(role))
+}
+
+#[post("/delete", data = "<data>")]
+async fn delete_role(
+    state: &State<MyState>,
+    login: LoginGuard,
+    data: Json<DeleteRoleData>,
+) -> Result<Status, RoleError> {
+    let (_, role) = managed_role(state, &login.user, data.role_id).await?;
+    if role.is_everyone() {
+        return Err(RoleError::InvalidRequest(
+            "The everyone role can't be deleted",
+        ));
+    }
+    role.delete(&state.conn).await?;
+    let entry = AuditEntry::new(
+        role.server_id,
+        login.user.user_id,
+        AuditAction::RoleDeleted,
+        Some(role.role_id),
+    )
+    .with_before(&role);
+    state.audit(entry).await;
+
+    events::revoke(
+        state,
+        Revocation::Permissions {
+            server_id: role.server_id,
+        },
+    );
+    events::emit(
+        state,
+        role.server_id,
+        ServerEvent::RoleDeleted {
+            role_id: role.role_id,
+        },
+    )
+    .await;
+
+    Ok(Status::Ok)
+}
+
+/// Makes sure the role can be given to or taken from the user
+async fn check_assignable(
+    state: &MyState,
+    login: &LoginGuard,
+    manager: &RoleManager,
+    role: &Role,
+    user_id: Uuid,
+) -> Result<(), RoleError> {
+    if role.is_everyone() {
+        return Err(RoleError::InvalidRequest(
+            "Every member holds the everyone role",
+        ));
+    }
+    // Otherwise managers could give themselves a lower role carrying powers they lack
+    manager.check_permissions(role.permissions)?;
+
+    let target = User::filter_by_id(&state.conn, user_id)
+        .await?
+        .ok_or(RoleError::UserNoExist(user_id))?;
+    if manager
+        .server
+        .get_permissions(&state.conn, &target)
+        .await?
+        .is_none()
+    {
+        return Err(RoleError::UserNoExist(user_id));
+    }
+    if user_id != login.user.user_id
+        && !manager
+            .server
+            .outranks(&state.conn, &login.user, user_id)
+            .await?
+    {
+        return Err(RoleError::Outranked);
+    }
+
+    Ok(())
+}
+
+#[post("/assign", data = "<data>")]
+async fn assign_role(
+    state: &State<MyState>,
+    login: LoginGuard,
+    data: Json<AssignRoleData>,
+) -> Result<Status, RoleError> {
+    let (manager, role) = managed_role(state, &login.user, data.role_id).await?;
+    check_assignable(state, &login, &manager, &role, data.user_id).await?;
+    role.assign(&state.conn, data.user_id).await?;
+    let entry = AuditEntry::new(
+        role.server_id,
+        login.user.user_id,
+        AuditAction::RoleAssigned,
+        Some(data.user_id),
+    )
+    .with_after(&role.role_id);
+    state.audit(entry).await;
+
+    events::revoke(
+        state,
+        Revocation::Permissions {
+            server_id: role.server_id,
+        },
+    );
+    events::emit(
+        state,
+        role.server_id,
+        ServerEvent::RoleAssigned {
+            role_id: role.role_id,
+            user_id: data.user_id,
+        },
+    )
+    .await;
+
+    Ok(Status::Ok)
+}
+
+#[post("/unassign", data = "<data>")]
+async fn unassign_role(
+    state: &State<MyState>,
+    login: LoginGuard,
+    data: Json<AssignRoleData>,
+) -> Result<Status, RoleError> {
+    let (manager, role) = managed_role(state, &login.user, data.role_id).await?;
+    check_assignable(state, &login, &manager, &role, data.user_id).await?;
+    role.unassign(&state.conn, data.user_id).await?;
+    let entry = AuditEntry::new(
+        role.server_id,
+        login.user.user_id,
+        AuditAction::RoleUnassigned,
+        Some(data.user_id),
+    )
+    .with_before(&role.role_id);
+    state.audit(entry).await;
+
+    events::revoke(
+        state,
+        Revocation::Permissions {
+            server_id: role.server_id,
+        },
+    );
+    events::emit(
+        state,
+        role.server_id,
+        ServerEvent::RoleUnassigned {
+            role_id: role.role_id,
+            user_id: data.user_id,
+        },
+    )
+    .await;
+
+    Ok(Status::Ok)
+}
+
+pub fn routes() -> Vec<rocket::Route> {
+    routes![
+        list_roles,
+        create_role,
+        edit_role,
+        delete_role,
+        assign_role,
+        unassign_role
+    ]
+}
@@ -1,24 +1,170 @@
-use crate::{guards::LoginGuard, quick_response, MyState};
+use crate::{
+    config::Restricted,
+    events::{self, ChatMessage, Revocation, ServerEvent, UserEvent},
+    guards::{ApiGuard, LoginGuard},
+    quick_response, MyState,
+};
 use rocket::{
     http::Status,
-    serde::{json::Json, uuid::Uuid, Deserialize},
+    response::stream::{Event, EventStream},
+    serde::{json::Json, uuid::Uuid, Deserialize, Serialize},
+    tokio::{select, sync::broadcast::error},
     State,
 };
-use spook_chat_db::models::{Invite, Permissions, Server, User};
+use spook_chat_db::models::{
+    AuditAction, AuditEntry, AuditFilter, Ban, ChangePermissions, Channel, Invite, Member,
+    Membership, Message, Permissions, Profile, Redemption, Role, Scopes, Server, User,
+};
 use sqlx::types::chrono::{DateTime, Utc};
 
+const DEFAULT_PAGE_SIZE: i64 = 50;
+const MAX_PAGE_SIZE: i64 = 100;
+const MAX_REASON_LENGTH: usize = 512;
+const MAX_IMAGE_URL_LENGTH: usize = 2048;
+const MAX_NICKNAME_LENGTH: usize = 32;
+const MIN_VANITY_CODE_LENGTH: usize = 3;
+const MAX_VANITY_CODE_LENGTH: usize = 32;
+
 #[derive(Deserialize)]
 #[serde(crate = "rocket::serde")]
 struct NewInviteConfig {
     server_id: Uuid,
     expires: Option<DateTime<Utc>>,
+    max_uses: Option<i32>,
+}
+
+#[derive(FromForm)]
+struct AuditLogQuery<'a> {
+    action: Option<&'a str>,
+    actor: Option<Uuid>,
+    target: Option<Uuid>,
+    page: Option<i64>,
+    per_page: Option<i64>,
+}
+
+/// What a user gets to see of a server before accepting an invite to it
+#[derive(Serialize)]
+#[serde(crate = "rocket::serde")]
+struct InvitePreview {
+    server_id: Uuid,
+    name: String,
+    icon: Option<String>,
+    member_count: usize,
+    online_count: usize,
+    expires_at: Option<DateTime<Utc>>,
+    remaining_uses: Option<i32>,
+}
+
+#[derive(Deserialize)]
+#[serde(crate = "rocket::serde")]
+struct ServerIconData<'a> {
+    server_id: Uuid,
+    icon: Option<&'a str>,
+}
+
+#[derive(Deserialize)]
+#[serde(crate = "rocket::serde")]
+struct VanityCodeData<'a> {
+    server_id: Uuid,
+    code: Option<&'a str>,
+}
+
+#[derive(Deserialize)]
+#[serde(crate = "rocket::serde")]
+struct AddBotData {
+    server_id: Uuid,
+    bot_id: Uuid,
+    #[serde(default)]
+    permissions: Permissions,
+}
+
+#[derive(Deserialize)]
+#[serde(crate = "rocket::serde")]
+struct NicknameData<'a> {
+    server_id: Uuid,
+    /// Someone else's nickname can only be reset, defaults to the caller
+    user_id: Option<Uuid>,
+    nickname: Option<&'a str>,
+}
+
+#[derive(Deserialize)]
+#[serde(crate = "rocket::serde")]
+struct AvatarData<'a> {
+    server_id: Uuid,
+    avatar: Option<&'a str>,
+}
+
+#[derive(Deserialize)]
+#[serde(crate = "rocket::serde")]
+struct SystemChannelData {
+    server_id: Uuid,
+    channel_id: Option<Uuid>,
+}
+
+#[derive(Deserialize)]
+#[serde(crate = "rocket::serde")]
+struct RevokeInviteData {
+    invite_id: Uuid,
+}
+
+#[derive(Deserialize)]
+#[serde(crate = "rocket::serde")]
+struct BanUserData<'a> {
+    server_id: Uuid,
+    user_id: Uuid,
+    reason: Option<&'a str>,
+    expires: Option<DateTime<Utc>>,
+}
+
+#[derive(Deserialize)]
+#[serde(crate = "rocket::serde")]
+struct UnbanUserData<'a> {
+    server_id: Uuid,
+    user_id: Uuid,
+    reason: Option<&'a str>,
+}
+
+#[derive(Deserialize)]
+#[serde(crate = "rocket::serde")]
+struct KickUserData<'a> {
+    server_id: Uuid,
+    user_id: Uuid,
+    reason: Option<&'a str>,
+}
+
+#[derive(Deserialize)]
+#[serde(crate = "rocket::serde")]
+struct TimeoutUserData<'a> {
+    server_id: Uuid,
+    user_id: Uuid,
+    until: Option<DateTime<Utc>>,
+    reason: Option<&'a str>,
+}
+
+#[derive(Deserialize)]
+#[serde(crate = "rocket::serde")]
+struct ChangePermissionsData {
+    server_id: Uuid,
+    user_id: Uuid,
+    #[serde(default)]
+    grant: Permissions,
+    #[serde(default)]
+    revoke: Permissions,
 }
 
 #[derive(Deserialize)]
 #[serde(crate = "rocket::serde")]
-struct BanUserData {
+struct TransferOwnershipData<'a> {
     server_id: Uuid,
     user_id: Uuid,
+    password: &'a str,
+}
+
+#[derive(Deserialize)]
+#[serde(crate = "rocket::serde")]
+struct DeleteServerData<'a> {
+    server_id: Uuid,
+    password: &'a str,
 }
 
 enum PermissionError {
@@ -26,6 +172,13 @@ enum PermissionError {
     MissingPermissions,
     NoEntry,
     UserNoExist(Uuid),
+    InvalidRequest(&'static str),
+    ReasonTooLong,
+    Outranked,
+    NotOwner,
+    WrongPassword,
+    NoFreeInviteCode,
+    MissingScope(Scopes),
 }
 
 impl From<sqlx::Error> for PermissionError {
@@ -53,6 +206,32 @@ impl<'r, 'o: 'r> rocket::response::Responder<'r, 'o> for PermissionError {
                 Status::Forbidden,
                 format!("User with the {id} does not exist"),
             )),
+            PermissionError::InvalidRequest(reason) => {
+                Ok(quick_response(Status::BadRequest, reason))
+            }
+            PermissionError::ReasonTooLong => Ok(quick_response(
+                Status::BadRequest,
+                format!("Reasons can be at most {MAX_REASON_LENGTH} characters long"),
+            )),
+            PermissionError::Outranked => Ok(quick_response(
+                Status::Forbidden,
+                "You can only act on members ranked below you",
+            )),
+            PermissionError::NotOwner => Ok(quick_response(
+                Status::Forbidden,
+                "Only the owner of this server can do this",
+            )),
+            PermissionError::WrongPassword => {
+                Ok(quick_response(Status::Forbidden, "Wrong password"))
+            }
+            PermissionError::NoFreeInviteCode => Ok(quick_response(
+                Status::ServiceUnavailable,
+                "No free invite code could be found, please try again",
+            )),
+            PermissionError::MissingScope(scope) => Ok(quick_response(
+                Status::Forbidden,
+                format!("This token lacks the {} scope", scope.name()),
+            )),
         }
     }
 }
@@ -61,6 +240,8 @@ enum JoinError {
     SqlxError(sqlx::Error),
     InviteNotExist,
     InviteExpired,
+    Banned,
+    Unverified,
 }
 
 impl From<sqlx::Error> for JoinError {
@@ -81,52 +262,282 @@ impl<'r, 'o: 'r> rocket::response::Responder<'r, 'o> for JoinError {
             }
             JoinError::InviteExpired => Ok(quick_response(
                 Status::BadRequest,
-                "This Invite has expired",
+                "This Invite has expired, been used up or revoked",
+            )),
+            JoinError::Banned => Ok(quick_response(
+                Status::Forbidden,
+                "You are banned from this server",
+            )),
+            JoinError::Unverified => Ok(quick_response(
+                Status::Forbidden,
+                "Verify your E-Mail address before joining servers",
             )),
         }
     }
 }
 
-#[get("/invite/<id>")]
-async fn join_server(
+#[get("/list")]
+async fn list_servers(
     state: &State<MyState>,
-    login: LoginGuard,
-    id: Uuid,
-) -> Result<Status, JoinError> {
-    let invite = Invite::filter_by_id(&state.conn, id)
+    login: ApiGuard,
+) -> Result<Json<Vec<Membership>>, PermissionError> {
+    if !login.allows(Scopes::READ_MESSAGES) {
+        return Err(PermissionError::MissingScope(Scopes::READ_MESSAGES));
+    }
+    Ok(Json(login.user.memberships(&state.conn).await?))
+}
+
+#[get("/members?<server>&<page>&<per_page>")]
+async fn list_members(
+    state: &State<MyState>,
+    login: ApiGuard,
+    server: Uuid,
+    page: Option<i64>,
+    per_page: Option<i64>,
+) -> Result<Json<Vec<Member>>, PermissionError> {
+    if !login.allows(Scopes::READ_MESSAGES) {
+        return Err(PermissionError::MissingScope(Scopes::READ_MESSAGES));
+    }
+    let server = Server::filter_by_id(&state.conn, server).await?;
+
+    server
+        .get_permissions(&state.conn, &login.user)
         .await?
-        .ok_or(JoinError::InviteNotExist)?;
-    if invite.is_valid() {
-        login
-            .user
-            .add_to_server(&state.conn, invite.server_id)
-            .await?;
-        Ok(Status::Ok)
-    } else {
-        Err(JoinError::InviteExpired)
+        .ok_or(PermissionError::NoEntry)?;
+
+    let per_page = per_page
+        .unwrap_or(DEFAULT_PAGE_SIZE)
+        .clamp(1, MAX_PAGE_SIZE);
+    let page = page.unwrap_or(0).max(0);
+    let members = server
+        .members(&state.conn, per_page, page.saturating_mul(per_page))
+        .await?;
+
+    Ok(Json(members))
+}
+
+#[get("/subscribe?<server>")]
+async fn subscribe(
+    state: &State<MyState>,
+    login: ApiGuard,
+    server: Uuid,
+) -> Result<EventStream![], PermissionError> {
+    if !login.allows(Scopes::READ_MESSAGES) {
+        return Err(PermissionError::MissingScope(Scopes::READ_MESSAGES));
+    }
+    if !login.user.has_access_to_server(&state.conn, server).await? {
+        return Err(PermissionError::NoEntry);
+    }
+
+    let user = login.user;
+    let user_id = user.user_id;
+    let credential = login.credential;
+    let conn = state.conn.clone();
+    let mut rx = state.server_events(server).await.subscribe();
+    let mut revocations = state.revocations.subscribe();
+
+    let event_stream = EventStream! { loop {
+        select! {
+            biased;
+            revocation = revocations.recv() => match revocation {
+                Ok(Revocation::ServerDeleted { server_id }) if server_id == server => {
+                    yield Event::data(server_id.to_string()).event("server_deleted");
+                    break;
+                }
+                Ok(revocation)
+                    if revocation.applies_to(user_id, server)
+                        || revocation.ends_credential(user_id, credential) => break,
+                // Missed revocations may have ended the credential or the membership
+                Err(error::RecvError::Lagged(_)) => {
+                    let allowed = match credential.is_valid(&conn).await {
+                        Ok(true) => user.has_access_to_server(&conn, server).await,
+                        other => other,
+                    };
+                    match allowed {
+                        Ok(true) => continue,
+                        _ => break,
+                    }
+                }
+                Err(error::RecvError::Closed) => break,
+                _ => continue,
+            },
+            event = rx.recv() => match event {
+                Ok(event) => yield Event::data(event).event("server"),
+                Err(error::RecvError::Lagged(_)) => continue,
+                Err(error::RecvError::Closed) => break,
+            },
+        }
+    }};
+
+    Ok(event_stream)
+}
+
+/// Where an invite leads: a regular invite or the vanity code of a server
+enum InviteTarget {
+    Invite(Invite),
+    Vanity(Server),
+}
+
+/// Looks up an invite by its id or code, or a server by its vanity code
+async fn find_invite(state: &MyState, code: &str) -> Result<InviteTarget, JoinError> {
+    let invite = match Uuid::parse_str(code) {
+        Ok(id) => Invite::filter_by_id(&state.conn, id).await?,
+        Err(_) => Invite::filter_by_code(&state.conn, code).await?,
+    };
+    if let Some(invite) = invite {
+        return Ok(InviteTarget::Invite(invite));
+    }
+
+    Server::filter_by_vanity_code(&state.conn, code)
+        .await?
+        .map(InviteTarget::Vanity)
+        .ok_or(JoinError::InviteNotExist)
+}
+
+/// The server an invite leads to, together with the invite unless it is a vanity code
+async fn invited_server(
+    state: &MyState,
+    target: InviteTarget,
+) -> Result<(Server, Option<Invite>), JoinError> {
+    match target {
+        InviteTarget::Invite(invite) => Ok((
+            Server::filter_by_id(&state.conn, invite.server_id).await?,
+            Some(invite),
+        )),
+        InviteTarget::Vanity(server) => Ok((server, None)),
     }
 }
 
-#[post("/invite", data = "<id>")]
+/// Posts a notice about the new member in the server's system channel, if it has one,
+/// and tells everyone subscribed to the server. The member has already joined by then,
+/// so a notice that couldn't be saved is only logged.
+async fn announce_join(state: &MyState, server: &Server, user: &User) {
+    if let Some(channel_id) = server.system_channel_id {
+        let message = Message::new(&format!("{} joined the server", user.username));
+        if let Err(e) = message.save_system(&state.conn, channel_id).await {
+            eprintln!("Failed to save join message: {e}");
+        } else if let Some(tx) = state.channels.read().await.get(&channel_id) {
+            let payload = ChatMessage {
+                message_id: message.message_id,
+                author_id: None,
+                display_name: None,
+                bot: false,
+                avatar: None,
+                content: &message.content,
+                created_at: message.created_at,
+            };
+            let _ = tx.send(payload.to_json());
+        }
+    }
+
+    events::emit(
+        state,
+        server.server_id,
+        ServerEvent::MemberJoined {
+            user_id: user.user_id,
+            username: &user.username,
+        },
+    )
+    .await;
+}
+
+/// Joins the server the invite leads to. Members that already belong to it
+/// just get its details back.
+async fn join(
+    state: &MyState,
+    user: &User,
+    target: InviteTarget,
+) -> Result<Json<Server>, JoinError> {
+    let (server, invite) = invited_server(state, target).await?;
+    if server.get_permissions(&state.conn, user).await?.is_some() {
+        return Ok(Json(server));
+    }
+
+    if !state.verification.allows(user, Restricted::JoinServers) {
+        return Err(JoinError::Unverified);
+    }
+    if user.is_banned_from(&state.conn, server.server_id).await? {
+        return Err(JoinError::Banned);
+    }
+    // Concurrent joins of the same user must only be announced once
+    let joined = match invite {
+        Some(invite) => {
+            if !invite.is_valid() {
+                return Err(JoinError::InviteExpired);
+            }
+            match invite.redeem(&state.conn, user.user_id).await? {
+                Redemption::Joined => true,
+                Redemption::AlreadyMember => false,
+                Redemption::Unusable => return Err(JoinError::InviteExpired),
+            }
+        }
+        None => {
+            user.add_to_server(&state.conn, server.server_id)
+                .await?
+                .rows_affected()
+                == 1
+        }
+    };
+
+    if joined {
+        announce_join(state, &server, user).await;
+    }
+    Ok(Json(server))
+}
+
+#[get("/invite/<code>")]
+async fn preview_invite(
+    state: &State<MyState>,
+    code: &str,
+) -> Result<Json<InvitePreview>, JoinError> {
+    let target = find_invite(state, code).await?;
+    let (server, invite) = invited_server(state, target).await?;
+    if invite.as_ref().is_some_and(|invite| !invite.is_valid()) {
+        return Err(JoinError::InviteExpired);
+    }
+
+    // Members count as online while they have their user event stream open
+    let member_ids = server.member_ids(&state.conn).await?;
+    let users = state.users.read().await;
+    let online_count = member_ids
+        .iter()
+        .filter(|user_id| users.get(user_id).is_some_and(|tx| tx.receiver_count() > 0))
+        .count();
+    drop(users);
+
+    Ok(Json(InvitePreview {
+        server_id: server.server_id,
+        name: server.name,
+        icon: server.icon,
+        member_count: member_ids.len(),
+        online_count,
+        expires_at: invite.as_ref().and_then(|invite| invite.expires_at),
+        remaining_uses: invite
+            .and_then(|invite| invite.max_uses.map(|max_uses| max_uses - invite.uses)),
+    }))
+}
+
+#[post("/invite/<code>")]
+async fn join_server(
+    state: &State<MyState>,
+    login: LoginGuard,
+    code: &str,
+) -> Result<Json<Server>, JoinError> {
+    let target = find_invite(state, code).await?;
+    join(state, &login.user, target).await
+}
+
+#[post("/invite", data = "<code>")]
 async fn join_server_post(
     state: &State<MyState>,
     login: LoginGuard,
-    id: Json<Uuid>,
-) -> Result<Status, JoinError> {
-    let invite = Invite::filter_by_id(&state.conn, id.0)
-        .await?
-        .ok_or(JoinError::InviteNotExist)?;
-    if invite.is_valid() {
-        login
-            .user
-            .add_to_server(&state.conn, invite.server_id)
-            .await?;
-        Ok(Status::Ok)
-    } else {
-        Err(JoinError::InviteExpired)
-    }
+    code: Json<String>,
+) -> Result<Json<Server>, JoinError> {
+    let target = find_invite(state, &code).await?;
+    join(state, &login.user, target).await
 }
 
+/// Creates an invite and responds with its code
 #[post("/new/invite", data = "<config>")]
 async fn create_invite(
     state: &State<MyState>,
@@ -140,46 +551,435 @@ async fn create_invite(
         .await?
         .ok_or(PermissionError::NoEntry)?;
 
-    if permissions.manage_invites {
-        let invite = server.create_invite(&state.conn, config.expires).await?;
+    if config.max_uses.is_some_and(|max_uses| max_uses < 1) {
+        return Err(PermissionError::InvalidRequest(
+            "An invite has to allow at least one use",
+        ));
+    }
+
+    if permissions.contains(Permissions::MANAGE_INVITES) {
+        let invite = server
+            .create_invite(
+                &state.conn,
+                login.user.user_id,
+                config.expires,
+                config.max_uses,
+            )
+            .await?
+            .ok_or(PermissionError::NoFreeInviteCode)?;
+        // Saved invites always carry the code they were saved with
+        let code = invite
+            .code
+            .clone()
+            .ok_or(PermissionError::NoFreeInviteCode)?;
+        let entry = AuditEntry::new(
+            server.server_id,
+            login.user.user_id,
+            AuditAction::InviteCreated,
+            Some(invite.invite_id),
+        )
+        .with_after(&invite);
+        state.audit(entry).await;
 
-        Ok((Status::Ok, invite.invite_id.to_string()))
+        Ok((Status::Ok, code))
     } else {
         Err(PermissionError::MissingPermissions)
     }
 }
 
-#[post("/user/ban", data = "<data>")]
-async fn ban_user(
+/// Icons and avatars are linked, not uploaded
+fn validate_image_url(url: Option<&str>) -> Result<(), PermissionError> {
+    if url.is_some_and(|url| {
+        url.len() > MAX_IMAGE_URL_LENGTH
+            || !(url.starts_with("https://") || url.starts_with("http://"))
+    }) {
+        return Err(PermissionError::InvalidRequest(
+            "Images have to be given as a http(s) URL of at most 2048 characters",
+        ));
+    }
+    Ok(())
+}
+
+#[post("/icon", data = "<data>")]
+async fn set_icon(
     state: &State<MyState>,
     login: LoginGuard,
-    data: Json<BanUserData>,
+    data: Json<ServerIconData<'_>>,
+) -> Result<Json<Server>, PermissionError> {
+    let mut server = Server::filter_by_id(&state.conn, data.server_id).await?;
+
+    let permissions = server
+        .get_permissions(&state.conn, &login.user)
+        .await?
+        .ok_or(PermissionError::NoEntry)?;
+
+    if !permissions.contains(Permissions::ADMINISTRATOR) {
+        return Err(PermissionError::MissingPermissions);
+    }
+    validate_image_url(data.icon)?;
+
+    let previous = server.icon.clone();
+    server.set_icon(&state.conn, data.icon).await?;
+    let entry = AuditEntry::new(
+        server.server_id,
+        login.user.user_id,
+        AuditAction::ServerUpdated,
+        None,
+    )
+    .with_before(&previous)
+    .with_after(&server.icon);
+    state.audit(entry).await;
+
+    Ok(Json(server))
+}
+
+/// Vanity codes are lowercase letters, digits and inner hyphens,
+/// so they can't be confused with invite ids
+fn validate_vanity_code(code: &str) -> Result<String, PermissionError> {
+    let code = code.trim().to_lowercase();
+    if code.len() < MIN_VANITY_CODE_LENGTH
+        || code.len() > MAX_VANITY_CODE_LENGTH
+        || code.starts_with('-')
+        || code.ends_with('-')
+        || !code
+            .chars()
+            .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-')
+        || Uuid::parse_str(&code).is_ok()
+    {
+        return Err(PermissionError::InvalidRequest(
+            "Vanity codes must be 3 to 32 letters, digits or inner hyphens",
+        ));
+    }
+    Ok(code)
+}
+
+#[post("/system-channel", data = "<data>")]
+async fn set_system_channel(
+    state: &State<MyState>,
+    login: LoginGuard,
+    data: Json<SystemChannelData>,
+) -> Result<Json<Server>, PermissionError> {
+    let mut server = Server::filter_by_id(&state.conn, data.server_id).await?;
+
+    let permissions = server
+        .get_permissions(&state.conn, &login.user)
+        .await?
+        .ok_or(PermissionError::NoEntry)?;
+
+    if !permissions.contains(Permissions::ADMINISTRATOR) {
+        return Err(PermissionError::MissingPermissions);
+    }
+    if let Some(channel_id) = data.channel_id {
+        match Channel::filter_by_id(&state.conn, channel_id).await? {
+            Some(channel) if channel.server_id == server.server_id => {}
+            _ => {
+                return Err(PermissionError::InvalidRequest(
+                    "The system channel has to be one of the server's channels",
+                ))
+            }
+        }
+    }
+
+    let previous = server.system_channel_id;
+    server
+        .set_system_channel(&state.conn, data.channel_id)
+        .await?;
+    let entry = AuditEntry::new(
+        server.server_id,
+        login.user.user_id,
+        AuditAction::ServerUpdated,
+        None,
+    )
+    .with_before(&previous)
+    .with_after(&server.system_channel_id);
+    state.audit(entry).await;
+
+    Ok(Json(server))
+}
+
+#[post("/vanity", data = "<data>")]
+async fn set_vanity_code(
+    state: &State<MyState>,
+    login: LoginGuard,
+    data: Json<VanityCodeData<'_>>,
+) -> Result<Json<Server>, PermissionError> {
+    let mut server = Server::filter_by_id(&state.conn, data.server_id).await?;
+
+    if !server.is_owner(&state.conn, login.user.user_id).await? {
+        return Err(PermissionError::NotOwner);
+    }
+
+    let code = data.code.map(validate_vanity_code).transpose()?;
+    let previous = server.vanity_code.clone();
+    if !server.set_vanity_code(&state.conn, code.as_deref()).await? {
+        return Err(PermissionError::InvalidRequest(
+            "This code is already in use",
+        ));
+    }
+    let entry = AuditEntry::new(
+        server.server_id,
+        login.user.user_id,
+        AuditAction::ServerUpdated,
+        None,
+    )
+    .with_before(&previous)
+    .with_after(&server.vanity_code);
+    state.audit(entry).await;
+
+    Ok(Json(server))
+}
+
+/// Adds one of the user's bots to a server they own. The bot gets a role of its own
+/// with the given permissions, so it ranks above members without roles.
+#[post("/bot/add", data = "<data>")]
+async fn add_bot(
+    state: &State<MyState>,
+    login: LoginGuard,
+    data: Json<AddBotData>,
 ) -> Result<String, PermissionError> {
     let server = Server::filter_by_id(&state.conn, data.server_id).await?;
 
+    if !server.is_owner(&state.conn, login.user.user_id).await? {
+        return Err(PermissionError::NotOwner);
+    }
+    let bot = User::filter_by_id(&state.conn, data.bot_id)
+        .await?
+        .filter(|bot| bot.bot && bot.bot_owner_id == Some(login.user.user_id))
+        .ok_or(PermissionError::UserNoExist(data.bot_id))?;
+    if server.get_permissions(&state.conn, &bot).await?.is_some() {
+        return Err(PermissionError::InvalidRequest(
+            "This bot is already a member of the server",
+        ));
+    }
+
+    bot.add_to_server(&state.conn, server.server_id).await?;
+    let role = Role::new(server.server_id, &bot.username, None, data.permissions);
+    role.save(&state.conn).await?;
+    role.assign(&state.conn, bot.user_id).await?;
+    let entry = AuditEntry::new(
+        server.server_id,
+        login.user.user_id,
+        AuditAction::BotAdded,
+        Some(bot.user_id),
+    )
+    .with_after(&role);
+    state.audit(entry).await;
+
+    events::emit(
+        state,
+        server.server_id,
+        ServerEvent::RoleCreated { role: &role },
+    )
+    .await;
+    announce_join(state, &server, &bot).await;
+
+    Ok(format!("Added {} to the server", bot.username))
+}
+
+#[get("/invites?<server>")]
+async fn list_invites(
+    state: &State<MyState>,
+    login: LoginGuard,
+    server: Uuid,
+) -> Result<Json<Vec<Invite>>, PermissionError> {
+    let server = Server::filter_by_id(&state.conn, server).await?;
+
     let permissions = server
         .get_permissions(&state.conn, &login.user)
         .await?
         .ok_or(PermissionError::NoEntry)?;
 
-    if permissions.manage_users {
-        let user_to_ban = User::filter_by_id(&state.conn, data.user_id)
-            .await?
-            .ok_or(PermissionError::UserNoExist(data.user_id))?;
-        server.ban_user(&state.conn, &user_to_ban).await?;
+    if !permissions.contains(Permissions::MANAGE_INVITES) {
+        return Err(PermissionError::MissingPermissions);
+    }
 
-        Ok(format!("User {} banned", data.user_id))
+    Ok(Json(
+        Invite::filter_active_by_server_id(&state.conn, server.server_id).await?,
+    ))
+}
+
+#[post("/invite/revoke", data = "<data>")]
+async fn revoke_invite(
+    state: &State<MyState>,
+    login: LoginGuard,
+    data: Json<RevokeInviteData>,
+) -> Result<String, PermissionError> {
+    let mut invite = Invite::filter_by_id(&state.conn, data.invite_id)
+        .await?
+        .ok_or(PermissionError::InvalidRequest("This invite is invalid"))?;
+    let server = Server::filter_by_id(&state.conn, invite.server_id).await?;
+
+    let permissions = server
+        .get_permissions(&state.conn, &login.user)
+        .await?
+        .ok_or(PermissionError::NoEntry)?;
+
+    if !permissions.contains(Permissions::MANAGE_INVITES) {
+        return Err(PermissionError::MissingPermissions);
+    }
+
+    invite.revoke(&state.conn).await?;
+    let entry = AuditEntry::new(
+        server.server_id,
+        login.user.user_id,
+        AuditAction::InviteRevoked,
+        Some(invite.invite_id),
+    );
+    state.audit(entry).await;
+
+    Ok(format!("Invite {} revoked", invite.invite_id))
+}
+
+#[get("/audit-log?<server>&<query..>")]
+async fn audit_log(
+    state: &State<MyState>,
+    login: LoginGuard,
+    server: Uuid,
+    query: AuditLogQuery<'_>,
+) -> Result<Json<Vec<AuditEntry>>, PermissionError> {
+    let server = Server::filter_by_id(&state.conn, server).await?;
+
+    let permissions = server
+        .get_permissions(&state.conn, &login.user)
+        .await?
+        .ok_or(PermissionError::NoEntry)?;
+
+    if !permissions.contains(Permissions::VIEW_AUDIT_LOG) {
+        return Err(PermissionError::MissingPermissions);
+    }
+
+    let filter = AuditFilter {
+        action: query
+            .action
+            .map(str::parse)
+            .transpose()
+            .map_err(|_| PermissionError::InvalidRequest("Unknown audit log action"))?,
+        actor_id: query.actor,
+        target_id: query.target,
+    };
+    let per_page = query
+        .per_page
+        .unwrap_or(DEFAULT_PAGE_SIZE)
+        .clamp(1, MAX_PAGE_SIZE);
+    let page = query.page.unwrap_or(0).max(0);
+    let entries = AuditEntry::filter_by_server_id(
+        &state.conn,
+        server.server_id,
+        &filter,
+        per_page,
+        page.saturating_mul(per_page),
+    )
+    .await?;
+
+    Ok(Json(entries))
+}
+
+#[get("/bans?<server>")]
+async fn list_bans(
+    state: &State<MyState>,
+    login: LoginGuard,
+    server: Uuid,
+) -> Result<Json<Vec<Ban>>, PermissionError> {
+    let server = Server::filter_by_id(&state.conn, server).await?;
+
+    let permissions = server
+        .get_permissions(&state.conn, &login.user)
+        .await?
+        .ok_or(PermissionError::NoEntry)?;
+
+    if permissions.contains(Permissions::MANAGE_USERS) {
+        Ok(Json(
+            Ban::filter_by_server_id(&state.conn, server.server_id).await?,
+        ))
     } else {
         Err(PermissionError::MissingPermissions)
     }
 }
 
+fn validate_reason(reason: Option<&str>) -> Result<(), PermissionError> {
+    if reason.is_some_and(|reason| reason.chars().count() > MAX_REASON_LENGTH) {
+        return Err(PermissionError::ReasonTooLong);
+    }
+    Ok(())
+}
+
+#[post("/user/ban", data = "<data>")]
+async fn ban_user(
+    state: &State<MyState>,
+    login: ApiGuard,
+    data: Json<BanUserData<'_>>,
+) -> Result<String, PermissionError> {
+    if !login.allows(Scopes::MODERATE) {
+        return Err(PermissionError::MissingScope(Scopes::MODERATE));
+    }
+    let server = Server::filter_by_id(&state.conn, data.server_id).await?;
+
+    let permissions = server
+        .get_permissions(&state.conn, &login.user)
+        .await?
+        .ok_or(PermissionError::NoEntry)?;
+
+    if !permissions.contains(Permissions::MANAGE_USERS) {
+        return Err(PermissionError::MissingPermissions);
+    }
+    if data.user_id == login.user.user_id {
+        return Err(PermissionError::InvalidRequest("You can not ban yourself"));
+    }
+    validate_reason(data.reason)?;
+    if data.expires.is_some_and(|expires| expires <= Utc::now()) {
+        return Err(PermissionError::InvalidRequest(
+            "The ban has to expire in the future",
+        ));
+    }
+
+    // Users who have not joined yet can be banned pre-emptively
+    let user_to_ban = User::filter_by_id(&state.conn, data.user_id)
+        .await?
+        .ok_or(PermissionError::UserNoExist(data.user_id))?;
+    if !server
+        .outranks(&state.conn, &login.user, user_to_ban.user_id)
+        .await?
+    {
+        return Err(PermissionError::Outranked);
+    }
+
+    let ban = Ban::new(
+        server.server_id,
+        user_to_ban.user_id,
+        Some(login.user.user_id),
+        data.reason,
+        data.expires,
+    );
+    server.ban_user(&state.conn, &ban).await?;
+    let entry = AuditEntry::new(
+        server.server_id,
+        login.user.user_id,
+        AuditAction::MemberBanned,
+        Some(ban.user_id),
+    )
+    .with_after(&ban)
+    .with_reason(data.reason);
+    state.audit(entry).await;
+    events::revoke(
+        state,
+        Revocation::Membership {
+            user_id: user_to_ban.user_id,
+            server_id: server.server_id,
+        },
+    );
+
+    Ok(format!("User {} banned", data.user_id))
+}
+
 #[post("/user/unban", data = "<data>")]
 async fn unban_user(
     state: &State<MyState>,
-    login: LoginGuard,
-    data: Json<BanUserData>,
+    login: ApiGuard,
+    data: Json<UnbanUserData<'_>>,
 ) -> Result<String, PermissionError> {
+    if !login.allows(Scopes::MODERATE) {
+        return Err(PermissionError::MissingScope(Scopes::MODERATE));
+    }
     let server = Server::filter_by_id(&state.conn, data.server_id).await?;
 
     let permissions = server
@@ -187,11 +987,20 @@ async fn unban_user(
         .await?
         .ok_or(PermissionError::NoEntry)?;
 
-    if permissions.manage_users {
+    if permissions.contains(Permissions::MANAGE_USERS) {
+        validate_reason(data.reason)?;
         let user_to_unban = User::filter_by_id(&state.conn, data.user_id)
             .await?
             .ok_or(PermissionError::UserNoExist(data.user_id))?;
         server.unban_user(&state.conn, &user_to_unban).await?;
+        let entry = AuditEntry::new(
+            server.server_id,
+            login.user.user_id,
+            AuditAction::MemberUnbanned,
+            Some(user_to_unban.user_id),
+        )
+        .with_reason(data.reason);
+        state.audit(entry).await;
 
         Ok(format!("User {} unbanned", data.user_id))
     } else {
@@ -199,12 +1008,496 @@ async fn unban_user(
     }
 }
 
+#[post("/user/kick", data = "<data>")]
+async fn kick_user(
+    state: &State<MyState>,
+    login: ApiGuard,
+    data: Json<KickUserData<'_>>,
+) -> Result<String, PermissionError> {
+    if !login.allows(Scopes::MODERATE) {
+        return Err(PermissionError::MissingScope(Scopes::MODERATE));
+    }
+    let server = Server::filter_by_id(&state.conn, data.server_id).await?;
+
+    let permissions = server
+        .get_permissions(&state.conn, &login.user)
+        .await?
+        .ok_or(PermissionError::NoEntry)?;
+
+    if !permissions.contains(Permissions::MANAGE_USERS) {
+        return Err(PermissionError::MissingPermissions);
+    }
+    if data.user_id == login.user.user_id {
+        return Err(PermissionError::InvalidRequest(
+            "Use the leave endpoint to leave a server",
+        ));
+    }
+    validate_reason(data.reason)?;
+
+    let user_to_kick = User::filter_by_id(&state.conn, data.user_id)
+        .await?
+        .ok_or(PermissionError::UserNoExist(data.user_id))?;
+    if server
+        .get_permissions(&state.conn, &user_to_kick)
+        .await?
+        .is_none()
+    {
+        return Err(PermissionError::InvalidRequest(
+            "This user is not part of the server",
+        ));
+    }
+    if !server
+        .outranks(&state.conn, &login.user, user_to_kick.user_id)
+        .await?
+    {
+        return Err(PermissionError::Outranked);
+    }
+
+    server
+        .remove_user(&state.conn, user_to_kick.user_id)
+        .await?;
+    let entry = AuditEntry::new(
+        server.server_id,
+        login.user.user_id,
+        AuditAction::MemberKicked,
+        Some(user_to_kick.user_id),
+    )
+    .with_reason(data.reason);
+    state.audit(entry).await;
+    events::revoke(
+        state,
+        Revocation::Membership {
+            user_id: user_to_kick.user_id,
+            server_id: server.server_id,
+        },
+    );
+
+    Ok(format!("User {} kicked", data.user_id))
+}
+
+#[post("/user/timeout", data = "<data>")]
+async fn timeout_user(
+    state: &State<MyState>,
+    login: ApiGuard,
+    data: Json<TimeoutUserData<'_>>,
+) -> Result<String, PermissionError> {
+    if !login.allows(Scopes::MODERATE) {
+        return Err(PermissionError::MissingScope(Scopes::MODERATE));
+    }
+    let server = Server::filter_by_id(&state.conn, data.server_id).await?;
+
+    let permissions = server
+        .get_permissions(&state.conn, &login.user)
+        .await?
+        .ok_or(PermissionError::NoEntry)?;
+
+    if !permissions.contains(Permissions::MANAGE_USERS) {
+        return Err(PermissionError::MissingPermissions);
+    }
+    if data.user_id == login.user.user_id {
+        return Err(PermissionError::InvalidRequest(
+            "You can not time yourself out",
+        ));
+    }
+    if data.until.is_some_and(|until| until <= Utc::now()) {
+        return Err(PermissionError::InvalidRequest(
+            "The timeout has to end in the future",
+        ));
+    }
+    validate_reason(data.reason)?;
+
+    let user_to_timeout = User::filter_by_id(&state.conn, data.user_id)
+        .await?
+        .ok_or(PermissionError::UserNoExist(data.user_id))?;
+    if server
+        .get_permissions(&state.conn, &user_to_timeout)
+        .await?
+        .is_none()
+    {
+        return Err(PermissionError::InvalidRequest(
+            "This user is not part of the server",
+        ));
+    }
+    if !server
+        .outranks(&state.conn, &login.user, user_to_timeout.user_id)
+        .await?
+    {
+        return Err(PermissionError::Outranked);
+    }
+
+    server
+        .timeout_user(&state.conn, user_to_timeout.user_id, data.until)
+        .await?;
+    let entry = AuditEntry::new(
+        server.server_id,
+        login.user.user_id,
+        AuditAction::MemberTimedOut,
+        Some(user_to_timeout.user_id),
+    )
+    .with_after(&data.until)
+    .with_reason(data.reason);
+    state.audit(entry).await;
+
+    match data.until {
+        Some(until) => Ok(format!("User {} timed out until {until}", data.user_id)),
+        None => Ok(format!("Timeout of user {} lifted", data.user_id)),
+    }
+}
+
+#[post("/user/permissions", data = "<data>")]
+async fn change_permissions(
+    state: &State<MyState>,
+    login: LoginGuard,
+    data: Json<ChangePermissionsData>,
+) -> Result<Json<Permissions>, PermissionError> {
+    let server = Server::filter_by_id(&state.conn, data.server_id).await?;
+
+    let permissions = server
+        .get_permissions(&state.conn, &login.user)
+        .await?
+        .ok_or(PermissionError::NoEntry)?;
+
+    if !permissions.contains(Permissions::MANAGE_ROLES) {
+        return Err(PermissionError::MissingPermissions);
+    }
+    // Nobody can hand out or take away permissions they don't hold themselves
+    if !permissions.contains(data.grant | data.revoke) {
+        return Err(PermissionError::MissingPermissions);
+    }
+    if data.user_id == login.user.user_id {
+        return Err(PermissionError::InvalidRequest(
+            "You can not change your own permissions",
+        ));
+    }
+
+    let target = User::filter_by_id(&state.conn, data.user_id)
+        .await?
+        .ok_or(PermissionError::UserNoExist(data.user_id))?;
+    let previous = server.get_permissions(&state.conn, &target).await?.ok_or(
+        PermissionError::InvalidRequest("This user is not part of the server"),
+    )?;
+    if !server
+        .outranks(&state.conn, &login.user, target.user_id)
+        .await?
+    {
+        return Err(PermissionError::Outranked);
+    }
+
+    let direct = server
+        .change_permissions(
+            &state.conn,
+            &target,
+            ChangePermissions {
+                grant: data.grant,
+                revoke: data.revoke,
+            },
+        )
+        .await?;
+
+    events::revoke(
+        state,
+        Revocation::Permissions {
+            server_id: server.server_id,
+        },
+    );
+    let effective = server.get_permissions(&state.conn, &target).await?;
+    let entry = AuditEntry::new(
+        server.server_id,
+        login.user.user_id,
+        AuditAction::PermissionsChanged,
+        Some(target.user_id),
+    )
+    .with_before(&previous)
+    .with_after(&effective);
+    state.audit(entry).await;
+    if let Some(effective) = effective {
+        events::emit_to_user(
+            state,
+            target.user_id,
+            UserEvent::PermissionsChanged {
+                server_id: server.server_id,
+                permissions: effective,
+            },
+        )
+        .await;
+    }
+
+    Ok(Json(direct))
+}
+
+/// Tells the server's subscribers how the member appears now
+async fn announce_profile(
+    state: &MyState,
+    server: &Server,
+    user: &User,
+) -> Result<Profile, PermissionError> {
+    let profile = server
+        .profile(&state.conn, user)
+        .await?
+        .ok_or(PermissionError::NoEntry)?;
+
+    events::emit(
+        state,
+        server.server_id,
+        ServerEvent::MemberUpdated {
+            user_id: user.user_id,
+            profile: &profile,
+        },
+    )
+    .await;
+
+    Ok(profile)
+}
+
+#[post("/nickname", data = "<data>")]
+async fn set_nickname(
+    state: &State<MyState>,
+    login: LoginGuard,
+    data: Json<NicknameData<'_>>,
+) -> Result<Json<Profile>, PermissionError> {
+    let server = Server::filter_by_id(&state.conn, data.server_id).await?;
+
+    let permissions = server
+        .get_permissions(&state.conn, &login.user)
+        .await?
+        .ok_or(PermissionError::NoEntry)?;
+
+    let nickname = data.nickname.map(str::trim);
+    if nickname.is_some_and(|nickname| {
+        nickname.is_empty() || nickname.chars().count() > MAX_NICKNAME_LENGTH
+    }) {
+        return Err(PermissionError::InvalidRequest(
+            "Nicknames must be between 1 and 32 characters long",
+        ));
+    }
+
+    let user_id = data.user_id.unwrap_or(login.user.user_id);
+    if user_id == login.user.user_id {
+        server.set_nickname(&state.conn, user_id, nickname).await?;
+        return Ok(Json(announce_profile(state, &server, &login.user).await?));
+    }
+
+    // Moderators may take a nickname away, but not pick one for someone else
+    if !permissions.contains(Permissions::MANAGE_USERS) {
+        return Err(PermissionError::MissingPermissions);
+    }
+    if nickname.is_some() {
+        return Err(PermissionError::InvalidRequest(
+            "You can only reset the nicknames of other members",
+        ));
+    }
+    let member = User::filter_by_id(&state.conn, user_id)
+        .await?
+        .ok_or(PermissionError::UserNoExist(user_id))?;
+    let previous =
+        server
+            .profile(&state.conn, &member)
+            .await?
+            .ok_or(PermissionError::InvalidRequest(
+                "This user is not part of the server",
+            ))?;
+    if !server
+        .outranks(&state.conn, &login.user, member.user_id)
+        .await?
+    {
+        return Err(PermissionError::Outranked);
+    }
+
+    server
+        .set_nickname(&state.conn, member.user_id, None)
+        .await?;
+    let entry = AuditEntry::new(
+        server.server_id,
+        login.user.user_id,
+        AuditAction::NicknameReset,
+        Some(member.user_id),
+    )
+    .with_before(&previous.display_name);
+    state.audit(entry).await;
+
+    Ok(Json(announce_profile(state, &server, &member).await?))
+}
+
+#[post("/avatar", data = "<data>")]
+async fn set_avatar(
+    state: &State<MyState>,
+    login: LoginGuard,
+    data: Json<AvatarData<'_>>,
+) -> Result<Json<Profile>, PermissionError> {
+    let server = Server::filter_by_id(&state.conn, data.server_id).await?;
+
+    server
+        .get_permissions(&state.conn, &login.user)
+        .await?
+        .ok_or(PermissionError::NoEntry)?;
+    validate_image_url(data.avatar)?;
+
+    server
+        .set_avatar(&state.conn, login.user.user_id, data.avatar)
+        .await?;
+
+    Ok(Json(announce_profile(state, &server, &login.user).await?))
+}
+
+#[post("/leave", data = "<id>")]
+async fn leave_server(
+    state: &State<MyState>,
+    login: LoginGuard,
+    id: Json<Uuid>,
+) -> Result<String, PermissionError> {
+    let server = Server::filter_by_id(&state.conn, id.0).await?;
+
+    server
+        .get_permissions(&state.conn, &login.user)
+        .await?
+        .ok_or(PermissionError::NoEntry)?;
+
+    if server.is_owner(&state.conn, login.user.user_id).await? {
+        return Err(PermissionError::InvalidRequest(
+            "Transfer the ownership of this server before leaving it",
+        ));
+    }
+
+    server.remove_user(&state.conn, login.user.user_id).await?;
+    events::revoke(
+        state,
+        Revocation::Membership {
+            user_id: login.user.user_id,
+            server_id: server.server_id,
+        },
+    );
+
+    Ok(format!("You left {}", server.name))
+}
+
+#[post("/transfer", data = "<data>")]
+async fn transfer_ownership(
+    state: &State<MyState>,
+    login: LoginGuard,
+    data: Json<TransferOwnershipData<'_>>,
+) -> Result<String, PermissionError> {
+    let server = Server::filter_by_id(&state.conn, data.server_id).await?;
+
+    if !server.is_owner(&state.conn, login.user.user_id).await? {
+        return Err(PermissionError::NotOwner);
+    }
+    if !login.user.verify_password(data.password) {
+        return Err(PermissionError::WrongPassword);
+    }
+    if data.user_id == login.user.user_id {
+        return Err(PermissionError::InvalidRequest(
+            "You already own this server",
+        ));
+    }
+
+    let new_owner = User::filter_by_id(&state.conn, data.user_id)
+        .await?
+        .ok_or(PermissionError::UserNoExist(data.user_id))?;
+    // Deleting the owner's account takes their bots along, which would leave the server without one
+    if new_owner.bot {
+        return Err(PermissionError::InvalidRequest("Bots can't own servers"));
+    }
+    if server
+        .get_permissions(&state.conn, &new_owner)
+        .await?
+        .is_none()
+    {
+        return Err(PermissionError::InvalidRequest(
+            "This user is not part of the server",
+        ));
+    }
+
+    server
+        .transfer_ownership(&state.conn, login.user.user_id, new_owner.user_id)
+        .await?;
+    let entry = AuditEntry::new(
+        server.server_id,
+        login.user.user_id,
+        AuditAction::OwnershipTransferred,
+        Some(new_owner.user_id),
+    )
+    .with_before(&login.user.user_id)
+    .with_after(&new_owner.user_id);
+    state.audit(entry).await;
+
+    events::revoke(
+        state,
+        Revocation::Permissions {
+            server_id: server.server_id,
+        },
+    );
+    events::emit(
+        state,
+        server.server_id,
+        ServerEvent::OwnershipTransferred {
+            user_id: new_owner.user_id,
+        },
+    )
+    .await;
+
+    Ok(format!("{} now owns {}", new_owner.username, server.name))
+}
+
+#[post("/delete", data = "<data>")]
+async fn delete_server(
+    state: &State<MyState>,
+    login: LoginGuard,
+    data: Json<DeleteServerData<'_>>,
+) -> Result<String, PermissionError> {
+    let server = Server::filter_by_id(&state.conn, data.server_id).await?;
+
+    if !server.is_owner(&state.conn, login.user.user_id).await? {
+        return Err(PermissionError::NotOwner);
+    }
+    if !login.user.verify_password(data.password) {
+        return Err(PermissionError::WrongPassword);
+    }
+
+    let channel_ids = server.delete(&state.conn).await?;
+
+    // Streams send their final event on the revocation before noticing the closed senders
+    events::revoke(
+        state,
+        Revocation::ServerDeleted {
+            server_id: server.server_id,
+        },
+    );
+    let mut channels = state.channels.write().await;
+    for channel_id in channel_ids {
+        channels.remove(&channel_id);
+    }
+    drop(channels);
+    state.servers.write().await.remove(&server.server_id);
+
+    Ok(format!("Deleted {}", server.name))
+}
+
 pub fn routes() -> Vec<rocket::Route> {
     routes![
+        list_servers,
+        list_members,
+        subscribe,
+        preview_invite,
         join_server,
         join_server_post,
         create_invite,
+        set_icon,
+        set_vanity_code,
+        add_bot,
+        set_system_channel,
+        list_invites,
+        revoke_invite,
+        list_bans,
+        audit_log,
         ban_user,
-        unban_user
+        unban_user,
+        kick_user,
+        timeout_user,
+        change_permissions,
+        set_nickname,
+        set_avatar,
+        leave_server,
+        transfer_ownership,
+        delete_server
     ]
 }
@@ -0,0 +1,56 @@
+use chrono::Utc;
+use rocket::tokio::time::{interval, Duration};
+use spook_chat_db::models::{Ban, EmailVerification, LoginThrottle, PasswordReset, Session};
+use sqlx::PgPool;
+
+const BAN_CHECK_INTERVAL: Duration = Duration::from_secs(60);
+const SESSION_PURGE_INTERVAL: Duration = Duration::from_secs(15 * 60);
+const TOKEN_PURGE_INTERVAL: Duration = Duration::from_secs(60 * 60);
+const THROTTLE_PURGE_INTERVAL: Duration = Duration::from_secs(15 * 60);
+
+/// Periodically deletes bans whose expiry has passed
+pub async fn lift_expired_bans(conn: PgPool) {
+    let mut ticker = interval(BAN_CHECK_INTERVAL);
+    loop {
+        ticker.tick().await;
+        if let Err(e) = Ban::lift_expired(&conn).await {
+            eprintln!("Failed to lift expired bans: {e}");
+        }
+    }
+}
+
+/// Periodically deletes sessions that have expired or sat idle for too long
+pub async fn purge_expired_sessions(conn: PgPool) {
+    let mut ticker = interval(SESSION_PURGE_INTERVAL);
+    loop {
+        ticker.tick().await;
+        if let Err(e) = Session::purge_expired(&conn).await {
+            eprintln!("Failed to purge expired sessions: {e}");
+        }
+    }
+}
+
+/// Periodically deletes emailed tokens that can no longer be used
+pub async fn purge_expired_tokens(conn: PgPool) {
+    let mut ticker = interval(TOKEN_PURGE_INTERVAL);
+    loop {
+        ticker.tick().await;
+        if let Err(e) = PasswordReset::purge_expired(&conn).await {
+            eprintln!("Failed to purge expired password resets: {e}");
+        }
+        if let Err(e) = EmailVerification::purge_expired(&conn).await {
+            eprintln!("Failed to purge expired email verifications: {e}");
+        }
+    }
+}
+
+/// Periodically forgets login failures older than `window` that block nothing anymore
+pub async fn purge_stale_throttles(conn: PgPool, window: chrono::Duration) {
+    let mut ticker = interval(THROTTLE_PURGE_INTERVAL);
+    loop {
+        ticker.tick().await;
+        if let Err(e) = LoginThrottle::purge_stale(&conn, Utc::now() - window).await {
+            eprintln!("Failed to purge stale login throttles: {e}");
+        }
+    }
+}
@@ -0,0 +1,148 @@
+use crate::{mail::Email, MyState};
+use spook_chat_db::models::{LoginAttempt, LoginThrottle, ThrottleKind, User};
+use sqlx::types::chrono::{DateTime, Utc};
+
+/// Counts a login attempt for `email` and from the IP address before the password or
+/// code gets checked, so parallel attempts can't all get past the backoff. Attempts
+/// count against the address rather than the account, so unknown addresses are
+/// throttled exactly like known ones.
+/// Returns until when attempts are refused instead, if they are.
+pub async fn begin_attempt(
+    state: &MyState,
+    email: &str,
+    ip_address: Option<&str>,
+) -> sqlx::Result<LoginAttempt> {
+    let config = &state.login_throttle;
+    let now = Utc::now();
+    let since = now - config.window;
+
+    if let Some(ip_address) = ip_address {
+        let attempt = LoginThrottle::attempt(
+            &state.conn,
+            ThrottleKind::IpAddress,
+            ip_address,
+            since,
+            |failures| {
+                config
+                    .backoff(failures, config.ip_backoff_after)
+                    .map(|delay| now + delay)
+            },
+        )
+        .await?;
+        if let LoginAttempt::Blocked(_) = attempt {
+            return Ok(attempt);
+        }
+    }
+
+    LoginThrottle::attempt(
+        &state.conn,
+        ThrottleKind::Account,
+        &LoginThrottle::address_subject(email),
+        since,
+        |failures| {
+            if failures >= config.lockout_after {
+                Some(now + config.lockout)
+            } else {
+                config
+                    .backoff(failures, config.account_backoff_after)
+                    .map(|delay| now + delay)
+            }
+        },
+    )
+    .await
+}
+
+/// Called once an attempt turned out wrong. It was already counted, but once it
+/// locked the account its owner is told by email.
+pub async fn record_failure(
+    state: &MyState,
+    user: Option<&User>,
+    failures: i32,
+    ip_address: Option<&str>,
+) {
+    if let Some(user) = user {
+        if failures >= state.login_throttle.lockout_after {
+            let email = Email::lockout(&user.email_address, &user.username, failures, ip_address);
+            if let Err(e) = state.mailer.send(email).await {
+                eprintln!("Failed to send lockout email: {e}");
+            }
+        }
+    }
+}
+
+/// Forgets the account's failures once a login went through completely.
+/// Only this attempt is taken back for the address, so one valid account
+/// can't be used to keep guessing others.
+pub async fn record_success(
+    state: &MyState,
+    user: &User,
+    ip_address: Option<&str>,
+) -> sqlx::Result<()> {
+    LoginThrottle::clear(
+        &state.conn,
+        ThrottleKind::Account,
+        &LoginThrottle::address_subject(&user.email_address),
+    )
+    .await?;
+    if let Some(ip_address) = ip_address {
+        LoginThrottle::uncount(&state.conn, ThrottleKind::IpAddress, ip_address).await?;
+    }
+    Ok(())
+}
+
+/// Takes back the attempt of a right password while the second factor is still
+/// missing, the code is counted on its own
+pub async fn record_password_accepted(
+    state: &MyState,
+    user: &User,
+    ip_address: Option<&str>,
+) -> sqlx::Result<()> {
+    LoginThrottle::uncount(
+        &state.conn,
+        ThrottleKind::Account,
+        &LoginThrottle::address_subject(&user.email_address),
+    )
+    .await?;
+    if let Some(ip_address) = ip_address {
+        LoginThrottle::uncount(&state.conn, ThrottleKind::IpAddress, ip_address).await?;
+    }
+    Ok(())
+}
+
+/// Counts a password reset request for the E-Mail address and from the IP address,
+/// whether or not the address belongs to an account. Returns until when requests
+/// are refused if they are. Addresses are only stored hashed.
+pub async fn record_reset_request(
+    state: &MyState,
+    email: &str,
+    ip_address: Option<&str>,
+) -> sqlx::Result<Option<DateTime<Utc>>> {
+    let config = &state.login_throttle;
+    let now = Utc::now();
+    let since = now - config.window;
+
+    let address = LoginThrottle::address_subject(email);
+    let mut limits = vec![(
+        ThrottleKind::ResetAddress,
+        address.as_str(),
+        config.reset_requests_per_address,
+    )];
+    if let Some(ip_address) = ip_address {
+        limits.push((
+            ThrottleKind::ResetIpAddress,
+            ip_address,
+            config.reset_requests_per_ip,
+        ));
+    }
+
+    for (kind, subject, limit) in limits {
+        let attempt = LoginThrottle::attempt(&state.conn, kind, subject, since, |requests| {
+            (requests >= limit).then_some(now + config.window)
+        })
+        .await?;
+        if let LoginAttempt::Blocked(until) = attempt {
+            return Ok(Some(until));
+        }
+    }
+    Ok(None)
+}
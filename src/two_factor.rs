@@ -0,0 +1,176 @@
+use crate::{guards::LoginGuard, quick_response, MyState};
+use rocket::{
+    http::{RawStr, Status},
+    response::Responder,
+    serde::{json::Json, Deserialize, Serialize},
+    State,
+};
+use spook_chat_db::models::TwoFactor;
+
+/// Shown as the account's issuer in authenticator apps
+const ISSUER: &str = "Spook Chat";
+
+#[derive(Deserialize)]
+#[serde(crate = "rocket::serde")]
+struct CodeData<'a> {
+    code: &'a str,
+}
+
+#[derive(Deserialize)]
+#[serde(crate = "rocket::serde")]
+struct DisableData<'a> {
+    password: &'a str,
+    /// A code from the authenticator app or one of the recovery codes
+    code: &'a str,
+}
+
+#[derive(Serialize)]
+#[serde(crate = "rocket::serde")]
+struct TwoFactorStatus {
+    enabled: bool,
+    recovery_codes_left: i64,
+}
+
+#[derive(Serialize)]
+#[serde(crate = "rocket::serde")]
+struct Provisioning {
+    secret: String,
+    /// `otpauth://` URI to show as a QR code
+    uri: String,
+}
+
+#[derive(Serialize)]
+#[serde(crate = "rocket::serde")]
+struct RecoveryCodes {
+    recovery_codes: Vec<String>,
+}
+
+enum TwoFactorError {
+    SqlxError(sqlx::Error),
+    AlreadyEnabled,
+    NotEnabled,
+    NotSetUp,
+    InvalidCode,
+    WrongPassword,
+}
+
+impl<'r, 'o: 'r> Responder<'r, 'o> for TwoFactorError {
+    fn respond_to(self, _request: &'r rocket::Request<'_>) -> rocket::response::Result<'o> {
+        match self {
+            Self::SqlxError(e) => Ok(quick_response(Status::InternalServerError, e.to_string())),
+            Self::AlreadyEnabled => Ok(quick_response(
+                Status::BadRequest,
+                "Two-factor authentication is already enabled",
+            )),
+            Self::NotEnabled => Ok(quick_response(
+                Status::BadRequest,
+                "Two-factor authentication is not enabled",
+            )),
+            Self::NotSetUp => Ok(quick_response(
+                Status::BadRequest,
+                "Set up two-factor authentication first",
+            )),
+            Self::InvalidCode => Ok(quick_response(Status::BadRequest, "Invalid code")),
+            Self::WrongPassword => Ok(quick_response(Status::Forbidden, "Wrong password")),
+        }
+    }
+}
+
+impl From<sqlx::Error> for TwoFactorError {
+    fn from(e: sqlx::Error) -> Self {
+        Self::SqlxError(e)
+    }
+}
+
+#[get("/")]
+async fn status(
+    state: &State<MyState>,
+    login: LoginGuard,
+) -> Result<Json<TwoFactorStatus>, TwoFactorError> {
+    let two_factor = TwoFactor::filter_by_user_id(&state.conn, login.user.user_id)
+        .await?
+        .filter(TwoFactor::is_enabled);
+    let recovery_codes_left = match &two_factor {
+        Some(two_factor) => two_factor.remaining_recovery_codes(&state.conn).await?,
+        None => 0,
+    };
+
+    Ok(Json(TwoFactorStatus {
+        enabled: two_factor.is_some(),
+        recovery_codes_left,
+    }))
+}
+
+/// Draws a new secret for the user to add to their authenticator app.
+/// It only takes effect once confirmed through `enable`.
+#[post("/setup")]
+async fn setup(
+    state: &State<MyState>,
+    login: LoginGuard,
+) -> Result<Json<Provisioning>, TwoFactorError> {
+    let existing = TwoFactor::filter_by_user_id(&state.conn, login.user.user_id).await?;
+    if existing.is_some_and(|two_factor| two_factor.is_enabled()) {
+        return Err(TwoFactorError::AlreadyEnabled);
+    }
+
+    let two_factor = TwoFactor::new(&login.user);
+    two_factor.save(&state.conn).await?;
+
+    let uri = format!(
+        "otpauth://totp/{issuer}:{account}?secret={secret}&issuer={issuer}&algorithm=SHA1&digits=6&period=30",
+        issuer = RawStr::new(ISSUER).percent_encode(),
+        account = RawStr::new(&login.user.email_address).percent_encode(),
+        secret = two_factor.secret,
+    );
+    Ok(Json(Provisioning {
+        secret: two_factor.secret,
+        uri,
+    }))
+}
+
+/// Turns on two-factor authentication once the user entered a code from their app,
+/// and hands out the recovery codes
+#[post("/enable", data = "<data>")]
+async fn enable(
+    state: &State<MyState>,
+    login: LoginGuard,
+    data: Json<CodeData<'_>>,
+) -> Result<Json<RecoveryCodes>, TwoFactorError> {
+    let mut two_factor = TwoFactor::filter_by_user_id(&state.conn, login.user.user_id)
+        .await?
+        .ok_or(TwoFactorError::NotSetUp)?;
+    if two_factor.is_enabled() {
+        return Err(TwoFactorError::AlreadyEnabled);
+    }
+    if !two_factor.verify(&state.conn, data.code).await? {
+        return Err(TwoFactorError::InvalidCode);
+    }
+
+    let recovery_codes = two_factor.enable(&state.conn).await?;
+    Ok(Json(RecoveryCodes { recovery_codes }))
+}
+
+#[post("/disable", data = "<data>")]
+async fn disable(
+    state: &State<MyState>,
+    login: LoginGuard,
+    data: Json<DisableData<'_>>,
+) -> Result<String, TwoFactorError> {
+    if !login.user.verify_password(data.password) {
+        return Err(TwoFactorError::WrongPassword);
+    }
+    let mut two_factor = TwoFactor::filter_by_user_id(&state.conn, login.user.user_id)
+        .await?
+        .filter(TwoFactor::is_enabled)
+        .ok_or(TwoFactorError::NotEnabled)?;
+    if !two_factor.verify_any(&state.conn, data.code).await? {
+        return Err(TwoFactorError::InvalidCode);
+    }
+
+    two_factor.delete(&state.conn).await?;
+    Ok("Two-factor authentication disabled".to_string())
+}
+
+pub fn routes() -> Vec<rocket::Route> {
+    routes![status, setup, enable, disable]
+}
@@ -0,0 +1,63 @@
+use crate::{guards::ApiGuard, quick_response, MyState};
+use rocket::{
+    http::Status,
+    response::{
+        stream::{Event, EventStream},
+        Responder,
+    },
+    tokio::{select, sync::broadcast::error},
+    State,
+};
+use spook_chat_db::models::Scopes;
+
+enum UserError {
+    MissingScope(Scopes),
+}
+
+impl<'r, 'o: 'r> Responder<'r, 'o> for UserError {
+    fn respond_to(self, _request: &'r rocket::Request<'_>) -> rocket::response::Result<'o> {
+        match self {
+            Self::MissingScope(scope) => Ok(quick_response(
+                Status::Forbidden,
+                format!("This token lacks the {} scope", scope.name()),
+            )),
+        }
+    }
+}
+
+#[get("/subscribe")]
+async fn subscribe(state: &State<MyState>, login: ApiGuard) -> Result<EventStream![], UserError> {
+    if !login.allows(Scopes::READ_MESSAGES) {
+        return Err(UserError::MissingScope(Scopes::READ_MESSAGES));
+    }
+    let user_id = login.user.user_id;
+    let credential = login.credential;
+    let conn = state.conn.clone();
+    let mut rx = state.user_events(user_id).await.subscribe();
+    let mut revocations = state.revocations.subscribe();
+
+    Ok(EventStream! { loop {
+        select! {
+            biased;
+            revocation = revocations.recv() => match revocation {
+                Ok(revocation) if revocation.ends_credential(user_id, credential) => break,
+                // Missed revocations may have included this credential's
+                Err(error::RecvError::Lagged(_)) => match credential.is_valid(&conn).await {
+                    Ok(true) => continue,
+                    _ => break,
+                },
+                Err(error::RecvError::Closed) => break,
+                _ => continue,
+            },
+            event = rx.recv() => match event {
+                Ok(event) => yield Event::data(event).event("user"),
+                Err(error::RecvError::Lagged(_)) => continue,
+                Err(error::RecvError::Closed) => break,
+            },
+        }
+    }})
+}
+
+pub fn routes() -> Vec<rocket::Route> {
+    routes![subscribe]
+}
@@ -0,0 +1,171 @@
+use rocket::serde::Serialize;
+
+/// The columns are VARCHAR(255) and VARCHAR(30)
+const MAX_EMAIL_LENGTH: usize = 255;
+/// The limit RFC 5321 puts on the part before the @
+const MAX_LOCAL_PART_LENGTH: usize = 64;
+const MIN_USERNAME_LENGTH: usize = 2;
+const MAX_USERNAME_LENGTH: usize = 30;
+const MIN_PASSWORD_LENGTH: usize = 8;
+/// Hashing gets slow beyond this, and nobody types more
+const MAX_PASSWORD_LENGTH: usize = 128;
+
+/// A problem with one field of a submitted form
+#[derive(Serialize)]
+#[serde(crate = "rocket::serde")]
+pub struct FieldError {
+    pub field: &'static str,
+    pub message: String,
+}
+
+/// Collects the problems of every field, so clients can show them all at once
+#[derive(Default, Serialize)]
+#[serde(crate = "rocket::serde")]
+pub struct FieldErrors {
+    pub errors: Vec<FieldError>,
+}
+
+impl FieldErrors {
+    pub fn check(&mut self, field: &'static str, result: Result<(), String>) {
+        if let Err(message) = result {
+            self.errors.push(FieldError { field, message });
+        }
+    }
+
+    /// `Ok` if every field was fine
+    pub fn into_result(self) -> Result<(), Self> {
+        if self.errors.is_empty() {
+            Ok(())
+        } else {
+            Err(self)
+        }
+    }
+}
+
+/// A pragmatic check rather than the full RFC 5322 grammar: something before the `@`,
+/// a domain with at least one dot after it, and no whitespace anywhere
+pub fn email(address: &str) -> Result<(), String> {
+    if address.len() > MAX_EMAIL_LENGTH {
+        return Err(format!(
+            "E-Mail addresses can be at most {MAX_EMAIL_LENGTH} characters long"
+        ));
+    }
+    if address.chars().any(char::is_whitespace) {
+        return Err("E-Mail addresses can't contain whitespace".to_string());
+    }
+
+    let (local, domain) = address
+        .rsplit_once('@')
+        .ok_or("E-Mail addresses need an @")?;
+    if local.is_empty() || local.len() > MAX_LOCAL_PART_LENGTH {
+        return Err(format!(
+            "The part before the @ has to be between 1 and {MAX_LOCAL_PART_LENGTH} characters long"
+        ));
+    }
+    let labels_valid = domain.split('.').all(|label| {
+        !label.is_empty()
+            && !label.starts_with('-')
+            && !label.ends_with('-')
+            && label.chars().all(|c| c.is_alphanumeric() || c == '-')
+    });
+    if !domain.contains('.') || !labels_valid {
+        return Err("The domain of this E-Mail address is invalid".to_string());
+    }
+    Ok(())
+}
+
+pub fn username(name: &str) -> Result<(), String> {
+    let length = name.chars().count();
+    if !(MIN_USERNAME_LENGTH..=MAX_USERNAME_LENGTH).contains(&length) {
+        return Err(format!(
+            "Usernames have to be between {MIN_USERNAME_LENGTH} and {MAX_USERNAME_LENGTH} characters long"
+        ));
+    }
+    if !name
+        .chars()
+        .all(|c| c.is_ascii_alphanumeric() || matches!(c, '_' | '.' | '-'))
+    {
+        return Err("Usernames can only contain letters, digits, _, . and -".to_string());
+    }
+    Ok(())
+}
+
+/// Long enough, not just letters or just digits, and none of `personal`,
+/// which holds things like the username and address of the account
+pub fn password(password: &str, personal: &[&str]) -> Result<(), String> {
+    let length = password.chars().count();
+    if length < MIN_PASSWORD_LENGTH {
+        return Err(format!(
+            "Passwords have to be at least {MIN_PASSWORD_LENGTH} characters long"
+        ));
+    }
+    if length > MAX_PASSWORD_LENGTH {
+        return Err(format!(
+            "Passwords can be at most {MAX_PASSWORD_LENGTH} characters long"
+        ));
+    }
+    if password.chars().all(char::is_alphabetic) || password.chars().all(|c| c.is_ascii_digit()) {
+        return Err("Passwords need more than just letters or just digits".to_string());
+    }
+    if personal
+        .iter()
+        .any(|value| password.eq_ignore_ascii_case(value))
+    {
+        return Err("Passwords can't be the same as the username or E-Mail address".to_string());
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn valid_emails_pass() {
+        assert!(email("someone@example.com").is_ok());
+        assert!(email("first.last+tag@mail.example.co.uk").is_ok());
+    }
+
+    #[test]
+    fn invalid_emails_fail() {
+        assert!(email("no-at-sign.example.com").is_err());
+        assert!(email("@example.com").is_err());
+        assert!(email("someone@localhost").is_err());
+        assert!(email("someone@-example.com").is_err());
+        assert!(email("someone@exa mple.com").is_err());
+        assert!(email("someone@example..com").is_err());
+        let local = "a".repeat(MAX_LOCAL_PART_LENGTH + 1);
+        assert!(email(&format!("{local}@example.com")).is_err());
+        let domain = "a".repeat(MAX_EMAIL_LENGTH);
+        assert!(email(&format!("someone@{domain}.com")).is_err());
+    }
+
+    #[test]
+    fn usernames_are_checked_for_length_and_characters() {
+        assert!(username("spooky_user-1.0").is_ok());
+        assert!(username("a").is_err());
+        assert!(username(&"a".repeat(MAX_USERNAME_LENGTH + 1)).is_err());
+        assert!(username(&"a".repeat(MAX_USERNAME_LENGTH)).is_ok());
+        assert!(username("no spaces").is_err());
+        assert!(username("ünïcode").is_err());
+    }
+
+    #[test]
+    fn passwords_are_checked() {
+        assert!(password("correct horse 1", &[]).is_ok());
+        assert!(password("short1", &[]).is_err());
+        assert!(password(&"a1".repeat(MAX_PASSWORD_LENGTH), &[]).is_err());
+        assert!(password("onlyletters", &[]).is_err());
+        assert!(password("1234567890", &[]).is_err());
+        assert!(password("Spooky_User1", &["spooky_user1"]).is_err());
+    }
+
+    #[test]
+    fn messages_mention_the_limits() {
+        let message = username("a").unwrap_err();
+        assert!(message.contains(&MIN_USERNAME_LENGTH.to_string()));
+        assert!(message.contains(&MAX_USERNAME_LENGTH.to_string()));
+        let message = password("short1", &[]).unwrap_err();
+        assert!(message.contains(&MIN_PASSWORD_LENGTH.to_string()));
+    }
+}